@@ -0,0 +1,204 @@
+//! Dependency-free timing of common removal patterns, run with `cargo bench`.
+
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+fn report(name: &str, calls: u32, elapsed: Duration) {
+    println!(
+        "{:<32} {:>10.0} ns/call ({} calls)",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(calls),
+        calls
+    );
+}
+
+fn bench_missing_targets(root: &Path) {
+    let target = root.join("missing");
+    let calls = 200_000;
+    let start = Instant::now();
+    for _ in 0..calls {
+        assert!(rm_rf::remove(&target).is_err());
+    }
+    report("remove (missing target)", calls, start.elapsed());
+}
+
+fn bench_small_files(root: &Path) {
+    let calls = 20_000;
+    let paths: Vec<_> = (0..calls).map(|i| root.join(i.to_string())).collect();
+    for path in &paths {
+        fs::write(path, b"").unwrap();
+    }
+    let start = Instant::now();
+    for path in &paths {
+        rm_rf::remove(path).unwrap();
+    }
+    report("remove (single empty file)", calls, start.elapsed());
+}
+
+fn create_tree(tree: &Path) {
+    let (dirs, files_per_dir) = (200, 50);
+    for d in 0..dirs {
+        let dir = tree.join(d.to_string()).join("nested");
+        fs::create_dir_all(&dir).unwrap();
+        for f in 0..files_per_dir {
+            fs::write(dir.join(f.to_string()), b"x").unwrap();
+        }
+    }
+}
+
+/// Removes a wide and deep tree, asserting the traversal makes no redundant calls.
+fn bench_tree(root: &Path) {
+    let tree = root.join("tree");
+    create_tree(&tree);
+    let opts = rm_rf::RemoveOptions::new().count_syscalls(true);
+    let start = Instant::now();
+    let stats = opts.execute(&tree).unwrap();
+    let elapsed = start.elapsed();
+    let entries = stats.entries_removed();
+    report("remove (tree, per entry)", entries as u32, elapsed);
+    println!("{:<32} {:?}", "syscalls", stats.syscalls);
+    assert_eq!(stats.syscalls.stat, entries, "one stat per entry");
+    assert_eq!(stats.syscalls.unlink, stats.files_removed);
+}
+
+#[cfg(unix)]
+fn bench_fast_tree(root: &Path) {
+    let tree = root.join("fast_tree");
+    create_tree(&tree);
+    let start = Instant::now();
+    let stats = rm_rf::remove_fast(&tree).unwrap();
+    let elapsed = start.elapsed();
+    report(
+        "remove_fast (tree, per entry)",
+        stats.entries_removed() as u32,
+        elapsed,
+    );
+}
+
+/// A single directory of many empty files, `RM_RF_BENCH_WIDE_FILES` of them (100000 by default),
+/// to compare directory reading with and without the `linux-getdents` feature.
+#[cfg(unix)]
+fn bench_fast_wide_dir(root: &Path) {
+    let files = std::env::var("RM_RF_BENCH_WIDE_FILES")
+        .ok()
+        .and_then(|files| files.parse().ok())
+        .unwrap_or(100_000);
+    let dir = root.join("fast_wide_dir");
+    fs::create_dir_all(&dir).unwrap();
+    for f in 0..files {
+        fs::File::create(dir.join(f.to_string())).unwrap();
+    }
+    let start = Instant::now();
+    let stats = rm_rf::remove_fast(&dir).unwrap();
+    let elapsed = start.elapsed();
+    report(
+        "remove_fast (wide dir, per entry)",
+        stats.entries_removed() as u32,
+        elapsed,
+    );
+}
+
+/// A chain of nested directories holding a couple of files each.
+fn create_deep_tree(tree: &Path) {
+    let mut dir = tree.to_path_buf();
+    for level in 0..300 {
+        dir.push(level.to_string());
+        fs::create_dir_all(&dir).unwrap();
+        for f in 0..2 {
+            fs::write(dir.join(format!("f{}", f)), b"x").unwrap();
+        }
+    }
+}
+
+/// Compares the path-based traversal with the descriptor-based one `remove` uses on Linux,
+/// whose calls don't resolve the whole path from the target down again.
+fn bench_deep_tree(root: &Path) {
+    let tree = root.join("deep_tree");
+    let runs = 20;
+    let entries = 901 * runs;
+    let mut path_based = Duration::default();
+    let mut default = Duration::default();
+    for _ in 0..runs {
+        create_deep_tree(&tree);
+        let start = Instant::now();
+        rm_rf::remove_tree(&tree, &rm_rf::RemoveOptions::new()).unwrap();
+        path_based += start.elapsed();
+        create_deep_tree(&tree);
+        let start = Instant::now();
+        rm_rf::remove(&tree).unwrap();
+        default += start.elapsed();
+    }
+    report("remove_tree (deep, per entry)", entries, path_based);
+    report("remove (deep, per entry)", entries, default);
+}
+
+/// Compares removing each directory once emptied with `RemoveOptions::two_phase`.
+fn bench_two_phase(root: &Path) {
+    let tree = root.join("two_phase");
+    let runs = 20;
+    let mut single = Duration::default();
+    let mut two_phase = Duration::default();
+    let mut entries = 0;
+    for _ in 0..runs {
+        for (enabled, elapsed) in [(false, &mut single), (true, &mut two_phase)] {
+            create_tree(&tree);
+            let opts = rm_rf::RemoveOptions::new().two_phase(enabled);
+            let start = Instant::now();
+            entries += opts.execute(&tree).unwrap().entries_removed();
+            *elapsed += start.elapsed();
+        }
+    }
+    let entries = (entries / 2) as u32;
+    report("single pass (tree, per entry)", entries, single);
+    report("two phases (tree, per entry)", entries, two_phase);
+}
+
+/// Compares sequential, adaptive and naive ("everything parallel") removals,
+/// on a wide tree and on a deep and narrow one.
+fn bench_parallel(root: &Path) {
+    let create_wide: fn(&Path) = create_tree;
+    for (shape, create) in [("wide", create_wide), ("deep", create_deep_tree)] {
+        let tree = root.join(format!("parallel_{}", shape));
+        let runs = [
+            ("sequential", None),
+            ("adaptive", Some(rm_rf::DEFAULT_FANOUT_THRESHOLD)),
+            ("naive parallel", Some(0)),
+        ];
+        for (name, threshold) in runs {
+            create(&tree);
+            let start = Instant::now();
+            let stats = match threshold {
+                None => rm_rf::remove_tree(&tree, &rm_rf::RemoveOptions::new()),
+                Some(threshold) => {
+                    let opts = rm_rf::RemoveOptions::new().parallel_fanout_threshold(threshold);
+                    rm_rf::remove_parallel(&tree, &opts)
+                }
+            }
+            .unwrap();
+            let elapsed = start.elapsed();
+            report(
+                &format!("{} ({}, per entry)", name, shape),
+                stats.entries_removed() as u32,
+                elapsed,
+            );
+        }
+    }
+}
+
+fn main() {
+    let root = Path::new("target").join("bench_remove");
+    let _ = rm_rf::ensure_removed(&root);
+    fs::create_dir_all(&root).unwrap();
+    bench_missing_targets(&root);
+    bench_small_files(&root);
+    bench_tree(&root);
+    #[cfg(unix)]
+    bench_fast_tree(&root);
+    #[cfg(unix)]
+    bench_fast_wide_dir(&root);
+    bench_deep_tree(&root);
+    bench_two_phase(&root);
+    bench_parallel(&root);
+    rm_rf::remove(&root).unwrap();
+}
@@ -0,0 +1,134 @@
+//! The read-only survey of a tree behind `analyze`.
+
+use crate::remover::{device, is_special_file};
+use crate::sys::RealSys;
+use crate::walk::{TreeWalker, Visit, WalkEntry};
+use crate::{Error, Operation, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// What stands in the way of removing a tree, as counted by `analyze`.
+///
+/// Plain data, like `RemoveStats`. Every count includes the target itself.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RemovalAnalysis {
+    /// Every entry found, the contents of unreadable directories excluded.
+    pub entries: u64,
+    /// Entries with read-only permissions. On Windows `remove` clears the attribute of
+    /// each of them; on Unix only directories matter, as their entries can't be unlinked,
+    /// see `RemoveOptions::fix_permissions`.
+    pub read_only: u64,
+    /// Files and directories with the immutable or append-only attribute, which nothing
+    /// removes until it's cleared, e.g. with `chattr -i`. Only detected on Linux.
+    pub immutable: u64,
+    /// Directories that couldn't be listed, lacking read permission for instance.
+    pub unreadable_dirs: u64,
+    /// Symlinks, never followed here, see `RemoveOptions::symlinks`.
+    pub symlinks: u64,
+    /// Directories on another filesystem than their parent,
+    /// see `RemoveOptions::refuse_mount_points`. Not detected on Windows.
+    pub mount_points: u64,
+    /// Device nodes, FIFOs and sockets, see `RemoveOptions::refuse_device_nodes`.
+    pub special_files: u64,
+    /// How deep the deepest entry is: 0 when the target has no children, 1 when only children.
+    pub max_depth: usize,
+    /// The largest number of entries found in a single directory.
+    pub max_fanout: usize,
+}
+
+pub(crate) fn analyze(path: &Path) -> Result<RemovalAnalysis> {
+    let path = crate::validate_target(path, false)?;
+    if let Err(err) = path.symlink_metadata() {
+        return match err.kind() {
+            ErrorKind::NotFound => Err(Error::NotFound),
+            _ => Err(err.into()),
+        };
+    }
+    let mut analysis = RemovalAnalysis::default();
+    // for each directory from the target down to the current one: its device and child count
+    let mut dirs: Vec<(u64, usize)> = Vec::new();
+    let mut walker = TreeWalker::new(&path);
+    while let Some(visit) = walker.next(&RealSys) {
+        let entry = match visit {
+            Ok(Visit::Dir(entry)) | Ok(Visit::Leaf(entry)) => entry,
+            Ok(Visit::Leave(_)) => continue,
+            Err(Error::IoFailed {
+                operation: Operation::ReadDir,
+                source,
+                ..
+            }) if source.kind() == ErrorKind::PermissionDenied => {
+                analysis.unreadable_dirs += 1;
+                continue;
+            }
+            Err(err) => return Err(err),
+        };
+        // the entries at a depth are the children of the last directory entered above it
+        for (_, children) in dirs.drain(entry.depth..) {
+            analysis.max_fanout = analysis.max_fanout.max(children);
+        }
+        if let Some((parent_device, children)) = dirs.last_mut() {
+            *children += 1;
+            if entry.metadata.is_dir() && device(&entry.metadata) != *parent_device {
+                analysis.mount_points += 1;
+            }
+        }
+        count(&mut analysis, &entry);
+        if entry.metadata.is_dir() {
+            dirs.push((device(&entry.metadata), 0));
+        }
+    }
+    for (_, children) in dirs {
+        analysis.max_fanout = analysis.max_fanout.max(children);
+    }
+    Ok(analysis)
+}
+
+fn count(analysis: &mut RemovalAnalysis, entry: &WalkEntry) {
+    let metadata = &entry.metadata;
+    analysis.entries += 1;
+    analysis.max_depth = analysis.max_depth.max(entry.depth);
+    if metadata.file_type().is_symlink() {
+        analysis.symlinks += 1;
+        // the permissions of a link are meaningless, and it can't be made immutable
+        return;
+    }
+    if metadata.permissions().readonly() {
+        analysis.read_only += 1;
+    }
+    if is_special_file(metadata) {
+        analysis.special_files += 1;
+    } else if is_immutable(&entry.path, metadata) {
+        analysis.immutable += 1;
+    }
+}
+
+/// Whether the file or directory at `path` has `FS_IMMUTABLE_FL` or `FS_APPEND_FL`,
+/// `false` if that can't be told, e.g. for a file without read permission.
+#[cfg(target_os = "linux")]
+fn is_immutable(path: &Path, metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+    const FS_IMMUTABLE_FL: libc::c_int = 0x10;
+    const FS_APPEND_FL: libc::c_int = 0x20;
+    if !metadata.is_file() && !metadata.is_dir() {
+        return false;
+    }
+    let file = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut flags: libc::c_int = 0;
+    // SAFETY: `file` is open, and the kernel writes an `int` to `flags`
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) };
+    result == 0 && flags & (FS_IMMUTABLE_FL | FS_APPEND_FL) != 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_immutable(_: &Path, _: &fs::Metadata) -> bool {
+    false
+}
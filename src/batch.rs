@@ -0,0 +1,200 @@
+use crate::options::Outcome;
+use crate::{Error, RemoveErrors, RemoveOptions, RemoveStats, Result};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) fn execute_all<I, P>(opts: &RemoveOptions, paths: I) -> Result<RemoveStats>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let (paths, subsumed) = dedup(opts, paths);
+    let mut stats = RemoveStats {
+        targets_subsumed: subsumed,
+        ..RemoveStats::default()
+    };
+    for path in paths {
+        stats += execute_one(opts, &path)?;
+    }
+    check_match(opts, stats)
+}
+
+/// Enforces `RemoveOptions::require_match` for the total outcome of an operation.
+pub(crate) fn check_match(opts: &RemoveOptions, stats: RemoveStats) -> Result<RemoveStats> {
+    if opts.require_match && stats.entries_removed() == 0 {
+        Err(Error::NoMatch)
+    } else {
+        Ok(stats)
+    }
+}
+
+pub(crate) fn execute_all_best_effort<I, P>(
+    opts: &RemoveOptions,
+    paths: I,
+) -> (RemoveStats, RemoveErrors)
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let (paths, subsumed) = dedup(opts, paths);
+    let mut stats = RemoveStats {
+        targets_subsumed: subsumed,
+        ..RemoveStats::default()
+    };
+    let mut errors = Vec::new();
+    for path in paths {
+        match execute_one(opts, &path) {
+            Ok(removed) => stats += removed,
+            Err(err @ Error::Cancelled) | Err(err @ Error::DeadlineExceeded) => {
+                errors.push((path, err));
+                break;
+            }
+            Err(err) => errors.push((path, err)),
+        }
+    }
+    (stats, errors.into())
+}
+
+/// Removes the relative `entries` under `root`, refusing any entry that would escape it.
+///
+/// All entries are checked before anything gets removed.
+pub(crate) fn remove_manifest(
+    opts: &RemoveOptions,
+    root: &Path,
+    entries: &[&Path],
+) -> Result<RemoveStats> {
+    let canonical_root = root.canonicalize().map_err(|err| match err.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound,
+        _ => Error::IoError(err),
+    })?;
+    let mut targets = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let target = root.join(confined_entry(entry)?);
+        let parent = target.parent().unwrap_or(root);
+        match parent.canonicalize() {
+            Ok(parent) if !parent.starts_with(&canonical_root) => {
+                return Err(Error::InvalidTarget(format!(
+                    "Invalid manifest entry {}, it resolves outside of the root",
+                    entry.display()
+                )))
+            }
+            // a missing parent means a missing target, which is skipped anyway
+            _ => targets.push(target),
+        }
+    }
+    execute_all(opts, targets)
+}
+
+/// Checks that `entry` is a plain relative path naming something below the root.
+pub(crate) fn confined_entry(entry: &Path) -> Result<&Path> {
+    let mut named = false;
+    for component in entry.components() {
+        match component {
+            Component::Normal(_) => named = true,
+            Component::CurDir => {}
+            _ => {
+                return Err(Error::InvalidTarget(format!(
+                    "Invalid manifest entry {}, it must be relative and cannot contain \"..\"",
+                    entry.display()
+                )))
+            }
+        }
+    }
+    if named {
+        Ok(entry)
+    } else {
+        Err(Error::InvalidTarget(format!(
+            "Invalid manifest entry {:?}, it names the root itself",
+            entry
+        )))
+    }
+}
+
+/// Missing targets are skipped in batches, like `rm -rf a b c` does.
+fn execute_one(opts: &RemoveOptions, path: &Path) -> Result<RemoveStats> {
+    opts.check_cancelled()?;
+    match opts.execute_if_exists(path) {
+        Ok(Outcome::Removed(stats)) => Ok(stats),
+        Ok(Outcome::Missing(_)) => Ok(RemoveStats::default()),
+        Err(partial) => Err(partial.error),
+    }
+}
+
+/// Drops targets that refer to a path already seen earlier in the batch,
+/// and targets inside another target of the batch, which removing that one covers.
+///
+/// Only targets that pass `validate_target` are compared: the others are kept as they
+/// are, to fail on their own, since e.g. `x/..` would otherwise cover all of `x`'s siblings.
+/// Returns the remaining targets in their original order, and how many were inside others.
+fn dedup<I, P>(opts: &RemoveOptions, paths: I) -> (Vec<PathBuf>, u64)
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    let mut seen = HashSet::new();
+    let targets: Vec<(PathBuf, Option<PathBuf>)> = paths
+        .into_iter()
+        .map(|path| path.as_ref().to_path_buf())
+        .filter_map(|path| {
+            let identity = match crate::validate_target(&path, opts.allow_dot_dot) {
+                Ok(valid) => identity(&valid),
+                Err(_) => return Some((path, None)),
+            };
+            if seen.insert(identity.clone()) {
+                Some((path, Some(identity)))
+            } else {
+                None
+            }
+        })
+        .collect();
+    let mut subsumed = 0;
+    let topmost = targets
+        .into_iter()
+        .filter(|(_, identity)| {
+            let inside_another = identity
+                .as_ref()
+                .is_some_and(|identity| identity.ancestors().skip(1).any(|a| seen.contains(a)));
+            subsumed += u64::from(inside_another);
+            !inside_another
+        })
+        .map(|(path, _)| path)
+        .collect();
+    (topmost, subsumed)
+}
+
+/// A normalized form of `path`, equal for aliases of the same directory entry.
+///
+/// Only the parent is canonicalized: the last segment itself is removed without
+/// being followed, so a symlink and its target are distinct entries.
+/// Paths whose parent can't be canonicalized fall back to lexical normalization.
+pub(crate) fn identity(path: &Path) -> PathBuf {
+    if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        if let Ok(parent) = parent.canonicalize() {
+            return parent.join(name);
+        }
+    }
+    normalize_lexically(path)
+}
+
+fn normalize_lexically(path: &Path) -> PathBuf {
+    let absolute = match std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    };
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
@@ -1,12 +1,90 @@
+use crate::RemoveStats;
 use core::fmt;
+use std::path::{Path, PathBuf};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum Error {
     NotFound,
     InvalidTarget(String),
     IoError(std::io::Error),
+    /// An io error during the traversal, with the operation and the path it failed on.
+    IoFailed {
+        operation: Operation,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// `remove_empty` was given a directory that still has entries.
+    NotEmpty,
+    /// A batch or pattern-based removal with `RemoveOptions::require_match` removed nothing.
+    NoMatch,
+    /// The entry has the BSD system-immutable or system-append-only flag (`schg`/`sappnd`).
+    /// Unlike the user flags, these can only be cleared at a low securelevel.
+    SystemImmutable(PathBuf),
+    /// The removal was stopped after freeing more than the configured limit,
+    /// in bytes for `RemoveOptions::max_bytes` or in entries for `RemoveOptions::max_entries`.
+    BudgetExceeded {
+        removed: u64,
+        limit: u64,
+    },
+    /// The removal was stopped through `RemoveOptions::cancel_token`.
+    Cancelled,
+    /// The removal was stopped once `RemoveOptions::deadline` had passed.
+    DeadlineExceeded,
+    /// A filesystem call on this path timed out, e.g. on an unreachable network mount,
+    /// or took longer than `RemoveOptions::per_op_timeout`,
+    /// even after the retries of `RemoveOptions::retry`.
+    /// Carries the same context as `IoFailed`.
+    Timeout {
+        operation: Operation,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    /// No free staging name could be found next to this target, see `remove_deferred`.
+    StagingCollision(PathBuf),
+    /// A symlink was found with `SymlinkPolicy::Error`.
+    UnexpectedSymlink(PathBuf),
+    /// A cycle of symlinks, either in the path leading to this entry (`ELOOP`),
+    /// or back into the tree being removed with `SymlinkPolicy::FollowAndRemoveTarget`.
+    /// Also a chain of links longer than `RemoveOptions::max_symlink_hops`.
+    SymlinkLoop(PathBuf),
+    /// A mount point was found with `RemoveOptions::refuse_mount_points`.
+    MountPoint(PathBuf),
+    /// A device node, FIFO or socket was found with `RemoveOptions::refuse_device_nodes`.
+    RefusedSpecialFile(PathBuf),
+    /// A user callback, such as `RemoveOptions::on_enter` or the predicate of `prune`, panicked.
+    /// Carries the panic message, if it was a string.
+    ///
+    /// The panic is caught and the removal stops cleanly, the callback staying usable.
+    /// With `panic = "abort"`, nothing can be caught and the process aborts as usual.
+    CallbackPanicked(Option<String>),
+}
+
+impl Error {
+    /// The io error behind this one, for `IoError`, `IoFailed` and `Timeout`.
+    ///
+    /// `None` for the other variants, `NotFound` and `InvalidTarget` included: they carry
+    /// no io error of their own, even where one was their cause.
+    pub fn as_io(&self) -> Option<&std::io::Error> {
+        match self {
+            Error::IoError(err)
+            | Error::IoFailed { source: err, .. }
+            | Error::Timeout { source: err, .. } => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Same as `as_io`, taking the io error out, or giving the error back if it has none.
+    pub fn into_io(self) -> std::result::Result<std::io::Error, Error> {
+        match self {
+            Error::IoError(err)
+            | Error::IoFailed { source: err, .. }
+            | Error::Timeout { source: err, .. } => Ok(err),
+            other => Err(other),
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -15,6 +93,43 @@ impl fmt::Display for Error {
             Error::NotFound => write!(f, "path not found"),
             Error::InvalidTarget(reason) => write!(f, "{}", reason),
             Error::IoError(e) => e.fmt(f),
+            Error::IoFailed {
+                operation,
+                path,
+                source,
+            } => write!(f, "{} failed on {}: {}", operation, path.display(), source),
+            Error::NotEmpty => write!(f, "directory not empty"),
+            Error::NoMatch => write!(f, "nothing matched the removal targets"),
+            Error::SystemImmutable(path) => write!(
+                f,
+                "{} is system-immutable (schg/sappnd) and cannot be removed at the current securelevel",
+                path.display()
+            ),
+            Error::BudgetExceeded { removed, limit } => write!(
+                f,
+                "removal budget exceeded: removed {} with a limit of {}",
+                removed, limit
+            ),
+            Error::Cancelled => write!(f, "removal cancelled"),
+            Error::DeadlineExceeded => write!(f, "removal deadline exceeded"),
+            Error::Timeout {
+                operation,
+                path,
+                source,
+            } => write!(f, "{} timed out on {}: {}", operation, path.display(), source),
+            Error::StagingCollision(path) => {
+                write!(f, "no free staging name next to {}", path.display())
+            }
+            Error::UnexpectedSymlink(path) => write!(f, "unexpected symlink {}", path.display()),
+            Error::SymlinkLoop(path) => write!(f, "symlink loop at {}", path.display()),
+            Error::MountPoint(path) => {
+                write!(f, "refusing to cross into the mount point {}", path.display())
+            }
+            Error::RefusedSpecialFile(path) => {
+                write!(f, "refusing to remove special file {}", path.display())
+            }
+            Error::CallbackPanicked(Some(message)) => write!(f, "a callback panicked: {}", message),
+            Error::CallbackPanicked(None) => write!(f, "a callback panicked"),
         }
     }
 }
@@ -25,12 +140,228 @@ impl std::error::Error for Error {
             Error::NotFound => None,
             Error::InvalidTarget(_) => None,
             Error::IoError(ref e) => Some(e),
+            Error::IoFailed { ref source, .. } => Some(source),
+            Error::NotEmpty => None,
+            Error::NoMatch => None,
+            Error::SystemImmutable(_) => None,
+            Error::BudgetExceeded { .. } => None,
+            Error::Cancelled => None,
+            Error::DeadlineExceeded => None,
+            Error::Timeout { ref source, .. } => Some(source),
+            Error::StagingCollision(_) => None,
+            Error::UnexpectedSymlink(_) => None,
+            Error::SymlinkLoop(_) => None,
+            Error::MountPoint(_) => None,
+            Error::RefusedSpecialFile(_) => None,
+            Error::CallbackPanicked(_) => None,
         }
     }
 }
 
+/// Calls the user callback `callback`, turning a panic in it into `Error::CallbackPanicked`.
+///
+/// The callback's captures aren't looked at after a panic, other than to be dropped
+/// with it eventually, so nothing is observed in a broken state.
+pub(crate) fn catch_callback<T>(callback: impl FnOnce() -> T) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(callback)).map_err(|payload| {
+        let message = match payload.downcast::<String>() {
+            Ok(message) => Some(*message),
+            Err(payload) => payload
+                .downcast_ref::<&str>()
+                .map(|message| message.to_string()),
+        };
+        Error::CallbackPanicked(message)
+    })
+}
+
 impl From<std::io::Error> for Error {
     fn from(err: std::io::Error) -> Error {
         Error::IoError(err)
     }
 }
+
+/// The filesystem operations the traversal performs.
+///
+/// `Copy`, comparable and hashable, e.g. to count failures per operation in a map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Operation {
+    Stat,
+    ReadDir,
+    RemoveFile,
+    RemoveDir,
+    SetPermissions,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Stat => "stat",
+            Operation::ReadDir => "read_dir",
+            Operation::RemoveFile => "remove_file",
+            Operation::RemoveDir => "remove_dir",
+            Operation::SetPermissions => "set_permissions",
+        })
+    }
+}
+
+/// Wraps an io error of `operation` on `path`, for use with `map_err`.
+pub(crate) fn failed(
+    operation: Operation,
+    path: &Path,
+) -> impl FnOnce(std::io::Error) -> Error + '_ {
+    move |source| match source.kind() {
+        std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => Error::Timeout {
+            operation,
+            path: path.to_path_buf(),
+            source,
+        },
+        _ if is_symlink_loop(&source) => Error::SymlinkLoop(path.to_path_buf()),
+        _ => Error::IoFailed {
+            operation,
+            path: path.to_path_buf(),
+            source,
+        },
+    }
+}
+
+/// Whether resolving a path failed on too many levels of symlinks.
+pub(crate) fn is_symlink_loop(err: &std::io::Error) -> bool {
+    #[cfg(unix)]
+    let code = libc::ELOOP;
+    // ERROR_CANT_RESOLVE_FILENAME
+    #[cfg(windows)]
+    let code = 1921;
+    #[cfg(not(any(unix, windows)))]
+    return false;
+    #[cfg(any(unix, windows))]
+    return err.raw_os_error() == Some(code);
+}
+
+/// The errors of a best-effort removal, each with the target it happened on.
+///
+/// Displayed one `path: error` per line, the first `RemoveErrors::DISPLAYED` only.
+/// As an error, its `source` is the first underlying error.
+#[derive(Debug, Default)]
+pub struct RemoveErrors {
+    errors: Vec<(PathBuf, Error)>,
+}
+
+impl RemoveErrors {
+    /// How many errors `Display` lists before summing up the rest.
+    pub const DISPLAYED: usize = 10;
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<'_, (PathBuf, Error)> {
+        self.errors.iter()
+    }
+
+    /// `Ok` if there were no errors, so that the errors can be propagated with `?`.
+    pub fn into_result(self) -> std::result::Result<(), RemoveErrors> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(self)
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<(PathBuf, Error)> {
+        self.errors
+    }
+}
+
+impl From<Vec<(PathBuf, Error)>> for RemoveErrors {
+    fn from(errors: Vec<(PathBuf, Error)>) -> RemoveErrors {
+        RemoveErrors { errors }
+    }
+}
+
+impl IntoIterator for RemoveErrors {
+    type Item = (PathBuf, Error);
+    type IntoIter = std::vec::IntoIter<(PathBuf, Error)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.into_iter()
+    }
+}
+
+impl<'e> IntoIterator for &'e RemoveErrors {
+    type Item = &'e (PathBuf, Error);
+    type IntoIter = std::slice::Iter<'e, (PathBuf, Error)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.errors.iter()
+    }
+}
+
+impl fmt::Display for RemoveErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.errors.is_empty() {
+            return write!(f, "no errors");
+        }
+        for (i, (path, error)) in self.errors.iter().take(Self::DISPLAYED).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}: {}", path.display(), error)?;
+        }
+        if self.errors.len() > Self::DISPLAYED {
+            write!(f, "\n... and {} more", self.errors.len() - Self::DISPLAYED)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RemoveErrors {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.errors
+            .first()
+            .map(|(_, error)| error as &(dyn std::error::Error + 'static))
+    }
+}
+
+/// A failed removal, together with what had been removed before the failure.
+#[derive(Debug)]
+pub struct PartialRemoval {
+    /// Boxed to keep `Result`s with this error small.
+    pub stats: Box<RemoveStats>,
+    pub error: Error,
+}
+
+impl fmt::Display for PartialRemoval {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (after removing {} entries)",
+            self.error,
+            self.stats.entries_removed()
+        )
+    }
+}
+
+impl std::error::Error for PartialRemoval {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<Error> for PartialRemoval {
+    fn from(error: Error) -> PartialRemoval {
+        PartialRemoval {
+            stats: Box::default(),
+            error,
+        }
+    }
+}
+
+impl From<PartialRemoval> for Error {
+    fn from(partial: PartialRemoval) -> Error {
+        partial.error
+    }
+}
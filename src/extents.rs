@@ -0,0 +1,80 @@
+//! The unshared extents of a file, for `RemoveOptions::measure_exclusive_bytes`.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// `_IOWR('f', 11, struct fiemap)`, from `linux/fs.h`.
+const FS_IOC_FIEMAP: libc::Ioctl = 0xC020_660B_u32 as libc::Ioctl;
+const FIEMAP_EXTENT_LAST: u32 = 0x1;
+const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+/// How many extents each call asks for.
+const BATCH: usize = 128;
+
+/// `struct fiemap_extent`.
+#[repr(C)]
+#[allow(dead_code)] // the fields the kernel fills in besides those used
+#[derive(Clone, Copy, Default)]
+struct Extent {
+    logical: u64,
+    physical: u64,
+    length: u64,
+    reserved64: [u64; 2],
+    flags: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct fiemap`, followed by room for `BATCH` extents.
+#[repr(C)]
+#[allow(dead_code)]
+struct Fiemap {
+    start: u64,
+    length: u64,
+    flags: u32,
+    mapped_extents: u32,
+    extent_count: u32,
+    reserved: u32,
+    extents: [Extent; BATCH],
+}
+
+/// The bytes of the file at `path` stored in extents no other file shares,
+/// as `FS_IOC_FIEMAP` reports them: a reflinked or snapshotted extent is shared,
+/// and holes count for nothing.
+///
+/// Extents are whole blocks, so the total can exceed the size of the file a bit.
+/// Fails with the `ioctl` error on filesystems that can't map extents, such as tmpfs.
+pub(crate) fn exclusive_bytes(path: &Path) -> io::Result<u64> {
+    let file = File::open(path)?;
+    let mut map = Box::new(Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: 0,
+        mapped_extents: 0,
+        extent_count: BATCH as u32,
+        reserved: 0,
+        extents: [Extent::default(); BATCH],
+    });
+    let mut exclusive = 0;
+    loop {
+        // SAFETY: `map` has room for the `extent_count` extents the kernel may write
+        if unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut *map as *mut Fiemap) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let extents = &map.extents[..map.mapped_extents as usize];
+        let last = match extents.last() {
+            Some(last) => *last,
+            None => return Ok(exclusive),
+        };
+        exclusive += extents
+            .iter()
+            .filter(|extent| extent.flags & FIEMAP_EXTENT_SHARED == 0)
+            .map(|extent| extent.length)
+            .sum::<u64>();
+        if last.flags & FIEMAP_EXTENT_LAST != 0 {
+            return Ok(exclusive);
+        }
+        map.start = last.logical + last.length;
+        map.length = u64::MAX - map.start;
+    }
+}
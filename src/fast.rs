@@ -0,0 +1,544 @@
+//! Removal through directory file descriptors and C strings, without any `Path` handling for children.
+
+use crate::error::failed;
+use crate::{Error, Operation, RemoveOptions, RemoveStats, Result, SymlinkPolicy};
+use std::cell::Cell;
+use std::ffi::{CStr, CString, OsStr};
+use std::fs::{self, File};
+use std::io;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Component, Path, PathBuf};
+use std::time::Instant;
+
+pub(crate) fn remove_fast(path: &Path) -> Result<RemoveStats> {
+    let path = crate::validate_target(path, false)?;
+    let started = Instant::now();
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(failed(Operation::Stat, &path)(err)),
+    };
+    let mut stats = RemoveStats::default();
+    if metadata.is_dir() {
+        remove_dir_tree(&path, &RemoveOptions::default(), &mut stats)?;
+    } else {
+        fs::remove_file(&path).map_err(failed(Operation::RemoveFile, &path))?;
+        if metadata.file_type().is_symlink() {
+            stats.symlinks_removed += 1;
+        } else {
+            stats.files_removed += 1;
+        }
+        stats.bytes_freed += metadata.len();
+        if std::os::unix::fs::MetadataExt::nlink(&metadata) <= 1 {
+            stats.inodes_freed += 1;
+            stats.exclusive_bytes_freed += metadata.len();
+        }
+    }
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Removes the directory `path` and everything inside it, adding to `stats`.
+pub(crate) fn remove_dir_tree(
+    path: &Path,
+    opts: &RemoveOptions,
+    stats: &mut RemoveStats,
+) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::InvalidTarget("Invalid path, contains a nul byte".to_string()))?;
+    remove_at(
+        &Parent::around(libc::AT_FDCWD),
+        &c_path,
+        &Location::Root(path),
+        opts,
+        stats,
+    )
+}
+
+/// The directory holding the entries being removed.
+struct Parent<'a> {
+    fd: RawFd,
+    /// The snapshot and location of a directory this removal empties, `None` for the
+    /// one around the target, whose permissions are never changed.
+    emptied: Option<(&'a libc::stat, &'a Location<'a>)>,
+    /// Whether its permissions were fixed already.
+    fixed: Cell<bool>,
+}
+
+impl<'a> Parent<'a> {
+    fn around(fd: RawFd) -> Parent<'a> {
+        Parent {
+            fd,
+            emptied: None,
+            fixed: Cell::new(false),
+        }
+    }
+
+    /// Unlinks its entry `name`, fixing its permissions once if that's denied,
+    /// like `RemoveOptions::fix_permissions` does.
+    fn unlink(&self, name: &CStr, flags: libc::c_int, opts: &RemoveOptions) -> io::Result<()> {
+        match unlink_at(self.fd, name, flags) {
+            Err(err) if err.kind() == ErrorKind::PermissionDenied && self.fix(opts) => {
+                unlink_at(self.fd, name, flags)
+            }
+            result => result,
+        }
+    }
+
+    fn fix(&self, opts: &RemoveOptions) -> bool {
+        match self.emptied {
+            Some((stat, location)) if !self.fixed.replace(true) => {
+                // SAFETY: `fd` is an open directory
+                fix_permissions(stat, location, opts, |mode| unsafe {
+                    libc::fchmod(self.fd, mode)
+                })
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The first option set in `opts` that handle-based removal can't honor.
+fn unsupported_option(opts: &RemoveOptions) -> Option<&'static str> {
+    let options = [
+        (opts.dry_run, "dry_run"),
+        (opts.journal.is_some(), "journal"),
+        (opts.symlinks != SymlinkPolicy::default(), "symlinks"),
+        (opts.refuse_device_nodes, "refuse_device_nodes"),
+        (opts.refuse_mount_points, "refuse_mount_points"),
+        (opts.only_own_files, "only_own_files"),
+        (
+            opts.protect_system_paths || opts.safety.is_some(),
+            "protect_system_paths",
+        ),
+        (opts.restore_permissions, "restore_permissions"),
+        (opts.preserve_parent_times, "preserve_parent_times"),
+        (opts.on_enter.is_some(), "on_enter"),
+        (opts.size_fn.is_some(), "size_fn"),
+        (opts.size_histogram.is_some(), "size_histogram"),
+        (opts.count_syscalls, "count_syscalls"),
+        (opts.retry.is_some(), "retry"),
+        (opts.op_timeout.is_some(), "per_op_timeout"),
+        #[cfg(feature = "ignore")]
+        (opts.ignore_file.is_some(), "honor_ignore_file"),
+        #[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+        (opts.measure_exclusive_bytes, "measure_exclusive_bytes"),
+    ];
+    options
+        .iter()
+        .find(|(enabled, _)| *enabled)
+        .map(|(_, option)| *option)
+}
+
+/// Gives the directory described by `stat` `u+rwx` with `chmod` if the caller owns it
+/// and permissions aren't to be kept, as `Remover::make_writable` does. Returns whether it did.
+fn fix_permissions(
+    stat: &libc::stat,
+    location: &Location,
+    opts: &RemoveOptions,
+    chmod: impl FnOnce(libc::mode_t) -> libc::c_int,
+) -> bool {
+    // SAFETY: `geteuid` has no preconditions and never fails
+    let user = unsafe { libc::geteuid() };
+    let mode = stat.st_mode;
+    if opts.keep_permissions
+        || mode & libc::S_IFMT != libc::S_IFDIR
+        || mode & libc::S_IRWXU == libc::S_IRWXU
+        || user == 0
+        || stat.st_uid != user
+    {
+        return false;
+    }
+    if chmod(mode & 0o7777 | libc::S_IRWXU) != 0 {
+        return false;
+    }
+    if let Some(log) = &opts.permission_log {
+        log.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(location.path());
+    }
+    true
+}
+
+/// Where an entry is, tracked without allocating, and only made into a path for errors.
+enum Location<'a> {
+    /// The target, at this path.
+    Root(&'a Path),
+    /// An entry of the directory at the first location, with this name.
+    Child(&'a Location<'a>, &'a CStr),
+}
+
+impl Location<'_> {
+    fn path(&self) -> PathBuf {
+        match self {
+            Location::Root(path) => path.to_path_buf(),
+            Location::Child(parent, name) => parent.path().join(OsStr::from_bytes(name.to_bytes())),
+        }
+    }
+
+    /// Wraps an io error of `operation` here, like `failed`.
+    fn failed(&self, operation: Operation) -> impl FnOnce(io::Error) -> Error + '_ {
+        move |err| failed(operation, &self.path())(err)
+    }
+}
+
+/// Removes the entry `name` of `parent`, honoring the cancel token and the budgets of `opts`.
+pub(crate) fn remove_relative(
+    parent: &File,
+    name: &OsStr,
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    if let Some(option) = unsupported_option(opts) {
+        return Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!("{} is not supported for handle-based removal", option),
+        )
+        .into());
+    }
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) if !name.as_bytes().contains(&b'/') => {}
+        _ => {
+            return Err(Error::InvalidTarget(
+                "Invalid name, it must be a single path segment other than \".\" or \"..\""
+                    .to_string(),
+            ))
+        }
+    }
+    let name = CString::new(name.as_bytes())
+        .map_err(|_| Error::InvalidTarget("Invalid name, contains a nul byte".to_string()))?;
+    let started = Instant::now();
+    let mut stats = RemoveStats::default();
+    // errors name the entry relative to `parent`, whose path is unknown
+    let location = Location::Root(Path::new(OsStr::from_bytes(name.to_bytes())));
+    let parent = Parent::around(parent.as_raw_fd());
+    match remove_at(&parent, &name, &location, opts, &mut stats) {
+        Err(Error::IoFailed {
+            operation: Operation::Stat,
+            source,
+            ..
+        }) if source.kind() == ErrorKind::NotFound && stats.entries_removed() == 0 => {
+            return Err(Error::NotFound)
+        }
+        result => result?,
+    }
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Removes everything inside `dir`, addressing each child only by its name relative to `dir`.
+fn clear(
+    dir: &mut Dir,
+    stat: &libc::stat,
+    location: &Location,
+    opts: &RemoveOptions,
+    stats: &mut RemoveStats,
+) -> Result<()> {
+    let parent = Parent {
+        fd: dir.fd(),
+        emptied: Some((stat, location)),
+        fixed: Cell::new(false),
+    };
+    let mut fanout = 0;
+    while let Some(name) = dir
+        .next_name()
+        .map_err(location.failed(Operation::ReadDir))?
+    {
+        if name.to_bytes() == b"." || name.to_bytes() == b".." {
+            continue;
+        }
+        fanout += 1;
+        stats.max_dir_fanout = stats.max_dir_fanout.max(fanout);
+        remove_at(&parent, name, &Location::Child(location, name), opts, stats)?;
+    }
+    Ok(())
+}
+
+fn remove_at(
+    parent: &Parent,
+    name: &CStr,
+    location: &Location,
+    opts: &RemoveOptions,
+    stats: &mut RemoveStats,
+) -> Result<()> {
+    let stat = stat_at(parent.fd, name).map_err(location.failed(Operation::Stat))?;
+    if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+        opts.check_cancelled()?;
+        let opened = match Dir::open(parent.fd, name) {
+            Err(err)
+                if err.kind() == ErrorKind::PermissionDenied
+                    // SAFETY: `name` is a valid nul-terminated string
+                    && fix_permissions(&stat, location, opts, |mode| unsafe {
+                        libc::fchmodat(parent.fd, name.as_ptr(), mode, 0)
+                    }) =>
+            {
+                Dir::open(parent.fd, name)
+            }
+            result => result,
+        };
+        let mut dir = match opened {
+            Ok(dir) => dir,
+            // an empty directory can be removed without being listed
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                parent
+                    .unlink(name, libc::AT_REMOVEDIR, opts)
+                    .map_err(|_| location.failed(Operation::ReadDir)(err))?;
+                stats.dirs_removed += 1;
+                stats.inodes_freed += 1;
+                return opts.check_budget(stats);
+            }
+            Err(err) => return Err(location.failed(Operation::ReadDir)(err)),
+        };
+        // don't die with stack overflow for deeply nested directories
+        let (red_zone, stack_size) = opts.stack();
+        stacker::maybe_grow(red_zone, stack_size, || {
+            clear(&mut dir, &stat, location, opts, stats)
+        })?;
+        drop(dir);
+        parent
+            .unlink(name, libc::AT_REMOVEDIR, opts)
+            .map_err(location.failed(Operation::RemoveDir))?;
+        stats.dirs_removed += 1;
+        stats.inodes_freed += 1;
+        opts.check_budget(stats)
+    } else {
+        parent
+            .unlink(name, 0, opts)
+            .map_err(location.failed(Operation::RemoveFile))?;
+        if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            stats.symlinks_removed += 1;
+        } else {
+            stats.files_removed += 1;
+        }
+        stats.bytes_freed += stat.st_size as u64;
+        // stat-ed right before the unlink, so a single link means it was the last one
+        if stat.st_nlink <= 1 {
+            stats.inodes_freed += 1;
+            stats.exclusive_bytes_freed += stat.st_size as u64;
+        }
+        opts.check_budget(stats)
+    }
+}
+
+/// An open directory stream, closed on drop.
+#[cfg(not(all(feature = "linux-getdents", target_os = "linux")))]
+pub(crate) struct Dir {
+    stream: *mut libc::DIR,
+}
+
+#[cfg(not(all(feature = "linux-getdents", target_os = "linux")))]
+impl Dir {
+    /// Opens the directory `name` relative to `parent`, never following a symlink in its place.
+    pub(crate) fn open(parent: RawFd, name: &CStr) -> io::Result<Dir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // SAFETY: `name` is a valid nul-terminated string
+        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: `fd` is an open directory descriptor, owned by the stream from now on
+        let stream = unsafe { libc::fdopendir(fd) };
+        if stream.is_null() {
+            let err = io::Error::last_os_error();
+            // SAFETY: `fd` wasn't taken over by a stream
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+        Ok(Dir { stream })
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        // SAFETY: `stream` is open for as long as `self` lives
+        unsafe { libc::dirfd(self.stream) }
+    }
+
+    /// The name of the next entry, valid until the stream is advanced again.
+    fn next_name(&mut self) -> io::Result<Option<&CStr>> {
+        set_errno(0);
+        // SAFETY: `stream` is open, and the entry is only borrowed until the next call
+        unsafe {
+            let entry = libc::readdir(self.stream);
+            if entry.is_null() {
+                return match io::Error::last_os_error() {
+                    err if err.raw_os_error() == Some(0) || !CAN_SET_ERRNO => Ok(None),
+                    err => Err(err),
+                };
+            }
+            Ok(Some(CStr::from_ptr((*entry).d_name.as_ptr())))
+        }
+    }
+}
+
+#[cfg(not(all(feature = "linux-getdents", target_os = "linux")))]
+impl Drop for Dir {
+    fn drop(&mut self) {
+        // SAFETY: `stream` is open and never used again
+        unsafe { libc::closedir(self.stream) };
+    }
+}
+
+/// How much `getdents64` is asked for at once: about 4000 entries with short names,
+/// where `readdir` reads 32KiB at a time.
+#[cfg(all(feature = "linux-getdents", target_os = "linux"))]
+const DENTS_BUFFER_SIZE: usize = 256 * 1024;
+
+/// An open directory read with `getdents64` in large batches, closed on drop.
+///
+/// Entries are parsed in place from a buffer allocated once per directory,
+/// so reading a directory of millions of entries takes a few hundred syscalls
+/// and no allocation per entry.
+#[cfg(all(feature = "linux-getdents", target_os = "linux"))]
+pub(crate) struct Dir {
+    fd: RawFd,
+    buffer: Vec<u8>,
+    /// The unread part of the buffer.
+    start: usize,
+    end: usize,
+}
+
+#[cfg(all(feature = "linux-getdents", target_os = "linux"))]
+impl Dir {
+    /// Opens the directory `name` relative to `parent`, never following a symlink in its place.
+    pub(crate) fn open(parent: RawFd, name: &CStr) -> io::Result<Dir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // SAFETY: `name` is a valid nul-terminated string
+        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Dir {
+            fd,
+            buffer: vec![0; DENTS_BUFFER_SIZE],
+            start: 0,
+            end: 0,
+        })
+    }
+
+    pub(crate) fn fd(&self) -> RawFd {
+        self.fd
+    }
+
+    /// The name of the next entry, valid until the stream is advanced again.
+    fn next_name(&mut self) -> io::Result<Option<&CStr>> {
+        if self.start == self.end {
+            // SAFETY: `fd` is open, and the kernel writes at most `buffer.len()` bytes
+            let read = unsafe {
+                libc::syscall(
+                    libc::SYS_getdents64,
+                    self.fd,
+                    self.buffer.as_mut_ptr(),
+                    self.buffer.len(),
+                )
+            };
+            if read < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if read == 0 {
+                return Ok(None);
+            }
+            self.start = 0;
+            self.end = read as usize;
+        }
+        // a `linux_dirent64`: inode (8 bytes), offset (8), record length (2), type (1), name
+        let record = &self.buffer[self.start..self.end];
+        let length = usize::from(u16::from_ne_bytes([record[16], record[17]]));
+        self.start += length;
+        CStr::from_bytes_until_nul(&record[19..length])
+            .map(Some)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "unterminated directory entry"))
+    }
+}
+
+#[cfg(all(feature = "linux-getdents", target_os = "linux"))]
+impl Drop for Dir {
+    fn drop(&mut self) {
+        // SAFETY: `fd` is open and never used again
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+fn stat_at(dir: RawFd, name: &CStr) -> io::Result<libc::stat> {
+    let mut stat = MaybeUninit::uninit();
+    // SAFETY: `name` is nul-terminated and `stat` is written to before being read
+    unsafe {
+        if libc::fstatat(
+            dir,
+            name.as_ptr(),
+            stat.as_mut_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat.assume_init())
+    }
+}
+
+fn unlink_at(dir: RawFd, name: &CStr, flags: libc::c_int) -> io::Result<()> {
+    // SAFETY: `name` is a valid nul-terminated string
+    if unsafe { libc::unlinkat(dir, name.as_ptr(), flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+#[cfg_attr(all(feature = "linux-getdents", target_os = "linux"), allow(dead_code))]
+const CAN_SET_ERRNO: bool = true;
+
+/// `readdir` only tells the end of a stream from a failure through `errno`.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+))]
+#[cfg_attr(all(feature = "linux-getdents", target_os = "linux"), allow(dead_code))]
+fn set_errno(value: libc::c_int) {
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    use libc::__errno as errno_location;
+    #[cfg(target_os = "linux")]
+    use libc::__errno_location as errno_location;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd"))]
+    use libc::__error as errno_location;
+    // SAFETY: the errno location is thread-local and always valid
+    unsafe { *errno_location() = value };
+}
+
+/// Elsewhere, a stream failing midway is taken as its end: the directory then
+/// isn't empty, and removing it fails instead.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+const CAN_SET_ERRNO: bool = false;
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd"
+)))]
+fn set_errno(_: libc::c_int) {}
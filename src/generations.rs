@@ -0,0 +1,110 @@
+//! Rotation of numbered or dated directories, for `remove_older_generations`.
+
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use std::cmp::Ordering;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// How `remove_older_generations` tells newer generations from older ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum GenerationOrder {
+    /// The latest modification time is the newest.
+    Modified,
+    /// The greatest name is the newest, with runs of digits compared as numbers,
+    /// so that `backup-10` is newer than `backup-9`.
+    Name,
+}
+
+struct Generation {
+    path: PathBuf,
+    name: OsString,
+    modified: SystemTime,
+}
+
+pub(crate) fn remove_older_generations(
+    parent: &Path,
+    keep: usize,
+    order: GenerationOrder,
+) -> Result<RemoveStats> {
+    let parent = crate::validate_target(parent, false)?;
+    match parent.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let mut generations = Vec::new();
+    for child in fs::read_dir(&parent)? {
+        let child = child?;
+        let metadata = child.path().symlink_metadata()?;
+        // symlinks included, only real directories are generations
+        if metadata.is_dir() {
+            generations.push(Generation {
+                path: child.path(),
+                name: child.file_name(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    // newest first, ties broken by name so that the outcome doesn't depend on listing order
+    generations.sort_by(|a, b| {
+        let by_name = || natural_cmp(&a.name, &b.name).then_with(|| a.name.cmp(&b.name));
+        match order {
+            GenerationOrder::Modified => a.modified.cmp(&b.modified).then_with(by_name),
+            GenerationOrder::Name => by_name(),
+        }
+        .reverse()
+    });
+    let older = generations.into_iter().skip(keep).map(|older| older.path);
+    RemoveOptions::default().execute_all(older)
+}
+
+/// Compares names piecewise, runs of ASCII digits by their numeric value.
+fn natural_cmp(a: &OsString, b: &OsString) -> Ordering {
+    let (mut a, mut b) = (a.as_encoded_bytes(), b.as_encoded_bytes());
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (x, rest_a) = split_digits(a);
+                let (y, rest_b) = split_digits(b);
+                match x.len().cmp(&y.len()).then_with(|| x.cmp(y)) {
+                    Ordering::Equal => {}
+                    unequal => return unequal,
+                }
+                a = rest_a;
+                b = rest_b;
+            }
+            (Some(x), Some(y)) => {
+                match x.cmp(y) {
+                    Ordering::Equal => {}
+                    unequal => return unequal,
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// The leading run of digits of `bytes` without its leading zeros, and what follows it.
+fn split_digits(bytes: &[u8]) -> (&[u8], &[u8]) {
+    let end = bytes
+        .iter()
+        .position(|byte| !byte.is_ascii_digit())
+        .unwrap_or(bytes.len());
+    let zeros = bytes[..end]
+        .iter()
+        .take_while(|&&byte| byte == b'0')
+        .count();
+    (&bytes[zeros..end], &bytes[end..])
+}
@@ -0,0 +1,99 @@
+//! The audit trail of `RemoveOptions::journal`.
+
+use crate::{Error, RemovedKind, Result};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A journal file, opened for appending on the first entry it records.
+#[derive(Debug)]
+pub(crate) struct Journal {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    pub(crate) fn new(path: &Path) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            file: Mutex::new(None),
+        }
+    }
+
+    /// Refuses a removal of `target` that would remove the journal itself.
+    pub(crate) fn check_outside(&self, target: &Path) -> Result<()> {
+        let journal = crate::batch::identity(&self.path);
+        if journal.starts_with(crate::batch::identity(target)) {
+            return Err(Error::InvalidTarget(format!(
+                "Invalid path, target contains the journal {}",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Appends the line of an entry that was just removed.
+    ///
+    /// Each line is a single write, so concurrent removals don't interleave
+    /// and a crash leaves every completed removal recorded.
+    pub(crate) fn record(&self, path: &Path, kind: RemovedKind, size: u64) -> Result<()> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let kind = match kind {
+            RemovedKind::File => "file",
+            RemovedKind::Symlink => "symlink",
+            RemovedKind::Dir => "dir",
+        };
+        let line = format!(
+            "{}.{:09}\t{}\t{}\t{}\n",
+            now.as_secs(),
+            now.subsec_nanos(),
+            kind,
+            size,
+            escaped(path)
+        );
+        let mut file = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let written = match &mut *file {
+            Some(file) => file.write_all(line.as_bytes()),
+            None => OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .and_then(|opened| file.insert(opened).write_all(line.as_bytes())),
+        };
+        written.map_err(|err| {
+            Error::IoError(io::Error::new(
+                err.kind(),
+                format!("writing the journal {}: {}", self.path.display(), err),
+            ))
+        })
+    }
+}
+
+/// `path` on a single line: backslashes, tabs and line breaks are escaped as in Rust strings,
+/// and bytes that aren't UTF-8 as `\xNN`.
+fn escaped(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
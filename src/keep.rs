@@ -0,0 +1,74 @@
+//! Entries a removal must leave in place, from `RemoveOptions::honor_ignore_file`.
+
+use crate::{RemoveOptions, Result};
+use std::path::Path;
+
+/// The ignore files found on the way down to the directory being emptied, innermost last.
+#[derive(Clone, Default)]
+pub(crate) struct IgnoreStack {
+    #[cfg(feature = "ignore")]
+    matchers: Vec<ignore::gitignore::Gitignore>,
+}
+
+#[cfg(feature = "ignore")]
+impl IgnoreStack {
+    /// Starts honoring the ignore file of `dir`, if it has one. Returns whether it had.
+    pub(crate) fn enter(&mut self, dir: &Path, opts: &RemoveOptions) -> Result<bool> {
+        let name = match &opts.ignore_file {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        let file = dir.join(name);
+        if !file.is_file() {
+            return Ok(false);
+        }
+        let mut builder = ignore::gitignore::GitignoreBuilder::new(dir);
+        let invalid = |err: ignore::Error| {
+            crate::Error::InvalidTarget(format!("Invalid ignore file {}: {}", file.display(), err))
+        };
+        // not honoring a broken ignore file could remove what it was meant to keep
+        if let Some(err) = builder.add(&file) {
+            return Err(invalid(err));
+        }
+        self.matchers.push(builder.build().map_err(invalid)?);
+        Ok(true)
+    }
+
+    /// Stops honoring the innermost ignore file.
+    pub(crate) fn leave(&mut self) {
+        self.matchers.pop();
+    }
+
+    /// Whether `path` must be kept: the innermost ignore file that has an opinion decides,
+    /// and ignore files themselves are always kept.
+    pub(crate) fn is_kept(&self, path: &Path, is_dir: bool, opts: &RemoveOptions) -> bool {
+        let name = match &opts.ignore_file {
+            Some(name) => name,
+            None => return false,
+        };
+        if path.file_name() == Some(name.as_ref()) {
+            return true;
+        }
+        for matcher in self.matchers.iter().rev() {
+            match matcher.matched(path, is_dir) {
+                ignore::Match::Ignore(_) => return true,
+                ignore::Match::Whitelist(_) => return false,
+                ignore::Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+#[cfg(not(feature = "ignore"))]
+impl IgnoreStack {
+    pub(crate) fn enter(&mut self, _: &Path, _: &RemoveOptions) -> Result<bool> {
+        Ok(false)
+    }
+
+    pub(crate) fn leave(&mut self) {}
+
+    pub(crate) fn is_kept(&self, _: &Path, _: bool, _: &RemoveOptions) -> bool {
+        false
+    }
+}
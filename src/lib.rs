@@ -1,83 +1,702 @@
+mod analyze;
+mod batch;
+mod disk;
 mod error;
+#[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+mod extents;
+#[cfg(unix)]
+mod fast;
+mod generations;
+mod journal;
+mod keep;
+mod observe;
+mod options;
+mod owner;
+mod parallel;
+mod preserve;
+mod remover;
+mod safety;
+mod select;
+mod staging;
+mod stats;
+#[cfg(feature = "async")]
+mod stream;
+mod sys;
+#[cfg(feature = "camino")]
+pub mod utf8;
+mod walk;
 
+pub use crate::analyze::RemovalAnalysis;
 pub use crate::error::Error;
+pub use crate::error::Operation;
+pub use crate::error::PartialRemoval;
+pub use crate::error::RemoveErrors;
 pub use crate::error::Result;
-use std::fs;
-use std::io;
+pub use crate::generations::GenerationOrder;
+use crate::options::Outcome;
+pub use crate::options::{
+    RemoveOptions, RetryPolicy, SymlinkPolicy, DEFAULT_FANOUT_THRESHOLD, DEFAULT_MAX_SYMLINK_HOPS,
+    DEFAULT_RED_ZONE, DEFAULT_STACK_SIZE,
+};
+use crate::remover::Remover;
+pub use crate::safety::SafetyContext;
+use crate::select::Selection;
+pub use crate::stats::RemoveStats;
+pub use crate::stats::RemovedKind;
+pub use crate::stats::SizeHistogram;
+pub use crate::stats::SyscallCounts;
+#[cfg(feature = "async")]
+pub use crate::stream::{NextEvent, RemoveEvent, RemoveStream};
+use crate::sys::RealSys;
+use crate::walk::{TreeWalker, Visit};
+use std::borrow::Cow;
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::fs::File;
+use std::fs::{self, Metadata};
 use std::io::ErrorKind;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::thread::JoinHandle;
 
 /// Force-removes a file/directory and all descendants.
 ///
-/// In contrast to `std::fs::remove_dir_all`, it will remove
-/// empty directories that lack read access on Linux,
-/// and will remove "read-only" files and directories on Windows.
+/// In contrast to `std::fs::remove_dir_all`, it will remove directories of the current user
+/// whose permissions deny listing or unlinking their entries on Unix,
+/// empty directories that lack read access whoever owns them,
+/// and "read-only" files and directories on Windows.
+///
+/// Trailing separators are normalized away on all platforms: `foo/` removes `foo`
+/// exactly like `foo` does, even if it's a file (unlike `rm -rf foo/`), and `foo/../`
+/// is rejected like `foo/..`. Only `RemoveOptions::trailing_slash_semantics` gives them a meaning.
+///
+/// On Linux, directories are removed through directory file descriptors like `remove_fast`
+/// does, so that deep entries are reached without resolving their whole path again: removing
+/// a chain of 300 nested directories holding 2 files each took about 19µs per entry instead of
+/// 47µs (`cargo bench`, ext4). If that fails, the usual path-based traversal takes over,
+/// which reports the failure with the path it happened on. `ensure_removed` and `try_remove`
+/// do the same, `RemoveOptions::execute` always uses the path-based traversal.
 pub fn remove<P: AsRef<Path>>(path: P) -> Result<()> {
-    let path = path.as_ref();
-    let parent: &Path = path
-        .parent()
-        .ok_or_else(|| Error::InvalidTarget("Invalid path, cannot get parent".to_string()))?;
-    let last_segment = path.components().last().ok_or_else(|| {
-        Error::InvalidTarget("Invalid path, cannot get last file path component".to_string())
-    })?;
-    let last_segment_str = last_segment.as_os_str().to_str().ok_or_else(|| {
-        Error::InvalidTarget("Invalid path, cannot convert last segment to string".to_string())
-    })?;
+    RemoveOptions::default()
+        .descriptor_based()
+        .execute(path)
+        .map(|_| ())
+}
 
-    if cfg!(not(target_os = "windows")) && (last_segment_str == "." || last_segment_str == "..") {
-        return Err(Error::InvalidTarget(
-            "Invalid path, last path segment cannot be \".\" or \"..\"".to_string(),
-        ));
+/// same as `remove` above, but succeeds for non-existent target, similar to `rm -rf`.
+pub fn ensure_removed<P: AsRef<Path>>(path: P) -> Result<()> {
+    RemoveOptions::default()
+        .descriptor_based()
+        .missing_ok(true)
+        .execute(path)
+        .map(|_| ())
+}
+
+/// Same as `ensure_removed`, but tells whether the target existed.
+///
+/// Returns `Ok(true)` if it was removed and `Ok(false)` if it was already missing.
+pub fn try_remove<P: AsRef<Path>>(path: P) -> Result<bool> {
+    match RemoveOptions::default()
+        .descriptor_based()
+        .execute_if_exists(path.as_ref())?
+    {
+        Outcome::Removed(_) => Ok(true),
+        Outcome::Missing(_) => Ok(false),
     }
-    let path = parent.join(last_segment);
-    match path.symlink_metadata() {
-        Ok(_) => recursive_remove(&path).map_err(Error::IoError),
-        Err(err) => match err.kind() {
-            ErrorKind::NotFound => Err(Error::NotFound),
-            _ => Err(Error::IoError(err)),
-        },
+}
+
+/// Removes an empty directory, forcefully but without ever recursing.
+///
+/// Permissions are fixed like in `remove`, but a directory that turns out
+/// to have entries is left alone and `Error::NotEmpty` is returned.
+/// Use it as a guardrail when a directory is expected to be empty already.
+/// `RemoveOptions::execute_empty` does the same with other options.
+pub fn remove_empty<P: AsRef<Path>>(path: P) -> Result<()> {
+    RemoveOptions::default().execute_empty(path)
+}
+
+/// Empties the directory `dir` so that it appears cleared instantly, keeping `dir` itself.
+///
+/// The children are first moved into a temporary sibling directory,
+/// which is then force-removed; watchers of `dir` see it empty as soon as the moves are done.
+/// Renames only work within one filesystem: children that can't be moved
+/// (`CrossesDevices`, e.g. mount points inside `dir`) are removed in place instead.
+pub fn swap_clear<P: AsRef<Path>>(dir: P) -> Result<()> {
+    staging::swap_clear(dir.as_ref())
+}
+
+/// Resets the directory `path` to empty: force-removes it, then creates it again.
+///
+/// The new directory gets the permissions of the old one, and on Unix its owner and group
+/// too when the process is allowed to set them (silently keeping its own otherwise).
+/// A missing target is just created, with its missing parents.
+/// This is not atomic: in between, `path` briefly doesn't exist, and a half-done removal
+/// leaves it partially emptied. Use `swap_clear` to empty a directory that must stay in place.
+pub fn reset<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = validate_target(path.as_ref(), false)?;
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => metadata,
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            return std::fs::create_dir_all(&path).map_err(Error::from)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    RemoveOptions::default().execute(&path)?;
+    std::fs::create_dir(&path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        // only privileged processes may give away files, which is fine to skip
+        let _ = std::os::unix::fs::chown(&path, Some(metadata.uid()), Some(metadata.gid()));
     }
+    std::fs::set_permissions(&path, metadata.permissions())?;
+    Ok(())
 }
 
-/// same as `remove` above, but succeeds for non-existent target, similar to `rm -rf`.
-pub fn ensure_removed<P: AsRef<Path>>(path: P) -> Result<()> {
-    if let Err(err) = path.as_ref().symlink_metadata() {
-        if err.kind() == ErrorKind::NotFound {
-            return Ok(());
+/// Force-removes every non-directory entry under `root` with one of the given extensions.
+///
+/// Extensions are compared case-insensitively and without the dot, e.g. `&["o", "tmp"]`.
+/// Directories with other names and non-matching files are left alone, as is `root` itself.
+/// With `prune_empty_dirs`, directories that become empty because of the removal are removed too.
+pub fn remove_by_extension<P: AsRef<Path>>(
+    root: P,
+    extensions: &[&str],
+    prune_empty_dirs: bool,
+) -> Result<RemoveStats> {
+    select::remove_selected(
+        root.as_ref(),
+        &RemoveOptions::default(),
+        &mut |path, metadata| {
+            Selection::remove_if(!metadata.is_dir() && select::has_extension(path, extensions))
+        },
+        prune_empty_dirs,
+    )
+}
+
+/// Force-removes the files under `root` matching `pred`, then the directories left empty by that.
+///
+/// `pred` is called for every non-directory entry with its path and metadata.
+/// Directories that become empty are removed bottom-up, while directories that were
+/// empty to begin with are kept. `root` itself is never removed: this tidies it up, not wipes it.
+/// `RemoveOptions::execute_prune` does the same with other options, e.g. a cancel token.
+pub fn prune<P, F>(root: P, pred: F) -> Result<RemoveStats>
+where
+    P: AsRef<Path>,
+    F: FnMut(&Path, &Metadata) -> bool,
+{
+    RemoveOptions::default().execute_prune(root, pred)
+}
+
+/// Force-removes every entry under `root` whose path relative to `root` matches `pattern`,
+/// a matching directory as a whole.
+///
+/// The relative path is matched with `/` between its segments on every platform,
+/// e.g. `build/x/target` for `root/build/x/target` on Windows too, so that patterns are
+/// portable. It's searched like `Regex::is_match` does, anywhere unless anchored:
+/// `^build/.*/target$` matches the `target` directories at any depth below `build`.
+/// Names that aren't valid UTF-8 are matched as bytes. The contents of a matching directory
+/// aren't matched on their own, and `root` itself is never removed.
+/// Symlinks are matched and removed as links, never followed into.
+#[cfg(feature = "regex")]
+pub fn remove_matching_regex<P: AsRef<Path>>(
+    root: P,
+    pattern: &regex::bytes::Regex,
+) -> Result<RemoveStats> {
+    let root = validate_target(root.as_ref(), false)?;
+    select::remove_selected(
+        &root,
+        &RemoveOptions::default(),
+        &mut |path, _| {
+            Selection::remove_if(pattern.is_match(&select::slashed_relative(&root, path)))
+        },
+        false,
+    )
+}
+
+/// Removes every symlink under `root`, leaving regular files and directories in place.
+///
+/// Links are unlinked without ever being followed, so neither their targets nor
+/// anything reached through a symlinked directory is touched. `root` itself is kept,
+/// and must be a real directory.
+pub fn remove_symlinks<P: AsRef<Path>>(root: P) -> Result<RemoveStats> {
+    select::remove_selected(
+        root.as_ref(),
+        &RemoveOptions::default(),
+        &mut |_, metadata| Selection::remove_if(metadata.file_type().is_symlink()),
+        false,
+    )
+}
+
+/// Keeps the `keep` newest directories directly inside `parent`, and force-removes the others.
+///
+/// For rotating backups or logs: generations are the immediate child directories of `parent`,
+/// ordered according to `order`. Other children, files and symlinks included, are never
+/// generations and are left alone. Ties, e.g. directories modified at the same time, are broken
+/// by name, so the same ones are kept whatever order the directory is listed in.
+pub fn remove_older_generations<P: AsRef<Path>>(
+    parent: P,
+    keep: usize,
+    order: GenerationOrder,
+) -> Result<RemoveStats> {
+    generations::remove_older_generations(parent.as_ref(), keep, order)
+}
+
+/// Force-removes everything under `root` except the `keep` paths, which are relative to it.
+///
+/// Kept entries survive as a whole, and so do the directories leading to them,
+/// while all their other children are removed. `root` itself is kept.
+/// `keep` entries must be relative and free of `..`, others are rejected with
+/// `Error::InvalidTarget` before anything is removed. Symlinks are never followed,
+/// so a kept path through a symlinked directory doesn't protect anything: the link is removed.
+pub fn remove_except<P: AsRef<Path>>(
+    root: P,
+    keep: &[&Path],
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    select::remove_except(root.as_ref(), keep, opts)
+}
+
+/// Moves a few entries out of `root`, then force-removes `root` with everything else in it.
+///
+/// Each `(source, dest)` pair moves `root/source` to `dest`, with a copy instead of a
+/// `rename` when `dest` is on another filesystem. Sources follow the rules of
+/// `remove_manifest`, must exist and can't overlap; destinations must not exist yet,
+/// nor be inside `root`. All pairs are checked before anything is moved, so an invalid one
+/// fails with `Error::InvalidTarget` and leaves everything in place. A move that fails
+/// midway stops the operation before anything is removed, entries moved earlier staying
+/// at their destination.
+pub fn remove_preserving<P: AsRef<Path>>(
+    root: P,
+    preserve: &[(&Path, &Path)],
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    preserve::remove_preserving(root.as_ref(), preserve, opts)
+}
+
+/// Makes `path` disappear immediately and force-removes it on a background thread.
+///
+/// The target is first renamed to a hidden staging name next to it
+/// (`.<name>.rm_rf.<pid>.<counter>`), which is fast, and the returned thread then
+/// removes the staged tree; join the handle to get the outcome, or drop it to not wait.
+/// Staging names that are already taken are skipped, so existing entries never get replaced;
+/// if none of the candidates is free, nothing is renamed and `Error::StagingCollision` is returned.
+/// If the target can't be renamed within its filesystem (`CrossesDevices`, e.g. a mount point),
+/// it is removed synchronously instead and the handle just carries the result.
+/// The staged entry is checked to be the one stat-ed first: a target swapped for another
+/// entry in the meantime is moved back and `Error::InvalidTarget` returned, deleting nothing.
+pub fn remove_deferred<P: AsRef<Path>>(path: P) -> Result<JoinHandle<Result<RemoveStats>>> {
+    staging::remove_deferred(path.as_ref())
+}
+
+/// Removes the staging entries that interrupted removals left behind in `dir`.
+///
+/// `remove_deferred`, `swap_clear` and `remove_transactional` move entries to hidden
+/// `.<name>.rm_rf.<pid>.<counter>` siblings before deleting them. A crash, or exiting
+/// before a deferred removal finished, leaves those behind: this finds them among the
+/// children of `dir` and force-removes them, returning what got removed. Other entries
+/// are never touched. On Unix, entries of processes still running are left alone,
+/// except for those of the current process, so only call this when no removal of the
+/// current process is still using `dir`, e.g. at startup. A transactional removal that
+/// panics midway moves its entries back by itself, only what couldn't be moved back is left.
+pub fn recover_staging<P: AsRef<Path>>(dir: P) -> Result<RemoveStats> {
+    staging::recover_staging(dir.as_ref())
+}
+
+/// Force-removes all given paths, stopping at the first error.
+///
+/// Missing targets are skipped, as with `ensure_removed`.
+/// Paths that refer to the same entry (e.g. `a/../b` and `b`, or the same name
+/// reached through a symlinked parent directory) are only removed once.
+/// Paths inside another path of the batch are skipped whatever their order, as removing
+/// the outer one covers them; they are counted in `RemoveStats::targets_subsumed`.
+pub fn remove_all<I, P>(paths: I) -> Result<RemoveStats>
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    RemoveOptions::default().execute_all(paths)
+}
+
+/// Same as `remove_all`, but keeps going after errors and returns them all at the end.
+///
+/// `RemoveErrors::into_result` turns the errors into a `Result`, ready for `?`.
+pub fn remove_all_best_effort<I, P>(paths: I) -> (RemoveStats, RemoveErrors)
+where
+    I: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+{
+    RemoveOptions::default().execute_all_best_effort(paths)
+}
+
+/// Force-removes the given entries, relative to `root`, without ever touching anything outside of it.
+///
+/// Entries that are absolute, contain `..`, or resolve outside of `root` through
+/// symlinked directories are rejected with `Error::InvalidTarget`, in which case nothing is removed.
+/// Missing entries are skipped, the rest behaves like `remove_all`.
+pub fn remove_manifest(root: &Path, entries: &[&Path]) -> Result<RemoveStats> {
+    batch::remove_manifest(&RemoveOptions::default(), root, entries)
+}
+
+/// Force-removes `parent/components[0]/components[1]/...` with `opts`, for callers that
+/// already have the path split, e.g. virtual filesystems. Most code wants `remove`.
+///
+/// Each component must be a single segment: names that are empty, `.` or `..`, or that
+/// contain a separator are rejected with `Error::InvalidTarget`, as nothing but a name under
+/// `parent` can be meant. The path is joined once and removed like `RemoveOptions::execute`
+/// does, with the same validation. No components means `parent` itself.
+pub fn remove_components(
+    parent: &Path,
+    components: &[&OsStr],
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    let mut path = parent.to_path_buf();
+    for &component in components {
+        let mut segments = Path::new(component).components();
+        match (segments.next(), segments.next()) {
+            (Some(Component::Normal(name)), None) if name == component => path.push(name),
+            _ => {
+                return Err(Error::InvalidTarget(format!(
+                    "Invalid component {:?}, it must be a single path segment other than \".\" or \"..\"",
+                    component
+                )))
+            }
         }
+    }
+    opts.execute(path)
+}
+
+/// Force-removes `path` working directly on directory file descriptors, for throughput.
+///
+/// Children are stat-ed, opened and unlinked by name relative to their parent
+/// (`fstatat`/`openat`/`unlinkat`), so no `PathBuf` is ever built below the target.
+/// Removing a 1M-file tree (1000 directories of 1000 empty files on ext4) took
+/// about 10.6s with `remove` and 7.4s with `remove_fast`, 30% less. On trees of
+/// a few thousand entries the difference is within noise (see `cargo bench`),
+/// so this is only worth it for huge trees of small files.
+///
+/// With the `linux-getdents` feature, directories are read on Linux with raw `getdents64`
+/// calls into a 256KiB buffer reused for the whole directory, rather than `readdir`'s 32KiB.
+/// Removing a single directory of 5M empty files (ext4, `cargo bench` with
+/// `RM_RF_BENCH_WIDE_FILES=5000000`) took 104.5s without it and 88.6s with it, 15% less.
+///
+/// Options are not supported, but like `remove` it gives the caller's own directories
+/// `u+rwx` when their entries can't be listed or unlinked otherwise. Each nesting level
+/// keeps a file descriptor open, so extremely deep trees may hit the process limit on open files.
+#[cfg(unix)]
+pub fn remove_fast<P: AsRef<Path>>(path: P) -> Result<RemoveStats> {
+    fast::remove_fast(path.as_ref())
+}
+
+/// Force-removes the entry `name` of the already opened directory `dir`.
+///
+/// Nothing is resolved by path: the entry is stat-ed and unlinked relative to the
+/// directory's file descriptor, and child directories are opened relative to their
+/// parent's (`openat`, never following symlinks), so renaming or swapping any path
+/// leading to `dir` during the removal can't redirect it.
+/// `name` must be a single path segment. Of the options, `max_bytes`, `max_entries`,
+/// `cancel_token`, `deadline`, `stack_params`, `fix_permissions` and `record_permission_changes`
+/// are honored, while the ones about following or refusing entries, hooks, retries and
+/// dry runs fail with an `Unsupported` io error. Options about the order of removal or
+/// about resolving a target path change nothing here.
+/// The paths of `Error::IoFailed` are relative to `dir`, which has no path of its own here.
+#[cfg(unix)]
+pub fn remove_relative(dir: &File, name: &OsStr, opts: &RemoveOptions) -> Result<RemoveStats> {
+    fast::remove_relative(dir, name, opts)
+}
+
+/// Removes `path` all or nothing: if any entry of the tree can't be removed, none is.
+///
+/// Unlinking can't be undone, so every entry is first moved, one atomic rename at a time
+/// and deepest first, into a fresh staging directory next to `path`. Only once the whole
+/// tree is there does the staging directory get deleted. If moving any entry fails,
+/// the entries already moved are renamed back and the error is returned.
+/// Permissions are never changed, so entries that can't be moved as they are fail the removal.
+///
+/// Atomicity is approximate: a crash midway leaves part of the tree in the staging directory
+/// (a hidden `.<name>.rm_rf.<pid>.<n>` sibling of `path`), and so does a failing move back.
+/// Renames can't cross filesystems, so a tree containing a mount point is never removed.
+pub fn remove_transactional<P: AsRef<Path>>(path: P) -> Result<()> {
+    staging::remove_transactional(path.as_ref())
+}
+
+/// Same as `RemoveOptions::execute`, but wide directories are emptied by several threads.
+///
+/// A directory with more than `RemoveOptions::parallel_fanout_threshold` entries gets its
+/// children split between the threads idle at that point, up to the available parallelism;
+/// smaller ones are emptied inline by the thread that found them.
+/// Each thread stops at its first error, and the first error found is returned.
+/// With `max_bytes` or `max_entries` set, the budget can't be shared between threads,
+/// so the removal stays sequential.
+pub fn remove_parallel<P: AsRef<Path>>(path: P, opts: &RemoveOptions) -> Result<RemoveStats> {
+    let opts = RemoveOptions {
+        parallel: true,
+        ..opts.clone()
     };
-    remove(path)
+    opts.execute(path)
 }
 
-fn recursive_remove(path: &Path) -> io::Result<()> {
-    fix_permissions(path)?;
-    let metadata = path.symlink_metadata()?;
-    if !metadata.is_dir() {
-        fs::remove_file(path)
-    } else if fs::remove_dir(path).is_ok() {
-        Ok(())
-    } else {
-        for child in fs::read_dir(&path)? {
-            let child = child?;
-            let path = child.path();
-            stacker::maybe_grow(4 * 1024, 16 * 1024, ||
-        // don't die with stack overflow for deeply nested directories
-        recursive_remove(&path))?;
+/// Empties the directory `dir`, removing its children concurrently on `threads` threads.
+///
+/// `dir` itself is kept. Each child is removed as a whole by one thread, with the sequential
+/// remover, which suits the common shape of a cache made of many independent subtrees;
+/// use `remove_parallel` to also split wide directories deeper in the tree.
+/// A `threads` of `0` is taken as `1`, and no more threads are started than there are children.
+/// Once a child fails, no other child is started, and the first error is returned.
+/// The children are removed with `opts`, which `RemoveOptions::cancel_token` and
+/// `RemoveOptions::deadline` stop on every thread within an entry.
+pub fn remove_contents_parallel<P: AsRef<Path>>(
+    dir: P,
+    threads: usize,
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    parallel::remove_contents_parallel(dir.as_ref(), threads, opts)
+}
+
+/// Force-removes the entries still to come from `entries`, a listing of the directory `dir`.
+///
+/// For tools that interleave scanning and deleting: the directory isn't listed again,
+/// so entries already consumed from `entries` before the call are not removed,
+/// and neither is `dir` itself. `entries` must come from `fs::read_dir(dir)`,
+/// as `dir` is what the entries' paths and errors are reported against.
+pub fn remove_entries<P: AsRef<Path>>(
+    dir: P,
+    entries: fs::ReadDir,
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    Remover::new(opts)
+        .run_entries(dir.as_ref(), entries)
+        .map_err(Error::from)
+}
+
+/// Same as `remove`, also returning how much free space the removal actually reclaimed.
+///
+/// Free space is queried on the filesystem containing the target (`statvfs` on Unix,
+/// `GetDiskFreeSpaceExW` on Windows) before and after the removal. Unlike
+/// `RemoveStats::bytes_freed`, the summed sizes of the entries, this accounts for
+/// hard links and reflinks keeping data alive, and for block and metadata overhead.
+/// The number is approximate: anything else writing to or freeing space on the same
+/// filesystem meanwhile skews it, so it's only meaningful on a quiescent filesystem.
+/// Space that some filesystems only release asynchronously may not be reflected yet.
+pub fn remove_with_disk_check<P: AsRef<Path>>(path: P) -> Result<(RemoveStats, u64)> {
+    let path = path.as_ref();
+    let container = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let before = disk::free_space(container)?;
+    let stats = RemoveOptions::default().execute(path)?;
+    let after = disk::free_space(container)?;
+    Ok((stats, after.saturating_sub(before)))
+}
+
+/// Tells whether `std::fs::remove_dir_all` would get stuck on something in `path`
+/// that the forceful removal of this crate works around.
+///
+/// A read-only diagnostic: the tree is walked without following symlinks, and without
+/// changing or removing anything. It returns `true` at the first directory that can't be
+/// listed, which `remove` deletes as is if it's empty, or at the first entry whose permissions
+/// `remove` fixes, see `RemoveOptions::fix_permissions`: on Windows one with the read-only
+/// attribute, on Unix a directory of the current user without `u+rwx`. Entries that block
+/// both, e.g. the children of another user's directory without write permission on Unix,
+/// don't make it `true`.
+/// A missing target is `Error::NotFound`, other failures to walk the tree are returned as is.
+pub fn would_require_force<P: AsRef<Path>>(path: P) -> Result<bool> {
+    let path = validate_target(path.as_ref(), false)?;
+    if let Err(err) = path.symlink_metadata() {
+        return match err.kind() {
+            ErrorKind::NotFound => Err(Error::NotFound),
+            _ => Err(err.into()),
+        };
+    }
+    let mut walker = TreeWalker::new(&path);
+    while let Some(visit) = walker.next(&RealSys) {
+        match visit {
+            Ok(Visit::Dir(entry)) | Ok(Visit::Leaf(entry))
+                if remover::needs_writable(&entry.metadata) =>
+            {
+                return Ok(true)
+            }
+            Ok(_) => {}
+            Err(Error::IoFailed {
+                operation: Operation::ReadDir,
+                source,
+                ..
+            }) if source.kind() == ErrorKind::PermissionDenied => return Ok(true),
+            Err(err) => return Err(err),
         }
-        fs::remove_dir(path)
     }
+    Ok(false)
 }
 
-#[cfg(target_os = "windows")]
-fn fix_permissions(path: &Path) -> io::Result<()> {
-    let mut permissions = fs::symlink_metadata(&path)?.permissions();
-    permissions.set_readonly(false);
-    fs::set_permissions(&path, permissions)
+/// Surveys `path` for what may get in the way of removing it, see `RemovalAnalysis`.
+///
+/// A read-only walk, like `would_require_force`: symlinks aren't followed, and nothing
+/// is changed or removed, though files and directories are opened to read their attributes
+/// on Linux. Directories that can't be listed are counted rather than failing the walk.
+/// A missing target is `Error::NotFound`, other failures to walk the tree are returned as is.
+pub fn analyze<P: AsRef<Path>>(path: P) -> Result<RemovalAnalysis> {
+    analyze::analyze(path.as_ref())
 }
 
-#[cfg(not(target_os = "windows"))]
-fn fix_permissions(_: &Path) -> io::Result<()> {
-    Ok(())
+/// Same as `remove`, also returning what the target was, as stat-ed right before removing it.
+///
+/// The `symlink_metadata` of the target (its kind, permissions, size and timestamps)
+/// is the very one the removal acted on, so it can't be from after the removal,
+/// e.g. for audit logs or to recreate the target later.
+/// Only the top-level target is reported, not the entries inside it.
+pub fn remove_reporting_metadata<P: AsRef<Path>>(
+    path: P,
+) -> Result<(RemovedKind, Metadata, RemoveStats)> {
+    let path = validate_target(path.as_ref(), false)?;
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    let stats = Remover::new(&RemoveOptions::default()).run_with_metadata(&path, &metadata)?;
+    Ok((RemovedKind::of(&metadata), metadata, stats))
+}
+
+/// Same as `remove`, also returning every path it removed, in the order it removed them:
+/// the contents of each directory before the directory itself, the target last.
+///
+/// The paths are the ones the removal acted on, starting with `path`. All of them are held
+/// until the removal is over, which takes memory proportional to the size of the tree;
+/// `remove_stream` reports them one at a time instead, and `RemoveOptions::journal`
+/// writes them to a file. A failed removal returns the error alone.
+pub fn remove_collecting<P: AsRef<Path>>(path: P) -> Result<Vec<PathBuf>> {
+    observe::remove_collecting(path.as_ref())
+}
+
+/// Same as `remove`, calling `progress(done, total)` after each entry it removes,
+/// for a simple "X% done" display.
+///
+/// `total` is counted by walking the tree before removing anything, so the metadata
+/// of every entry is read twice: this is meant for interactive use, where the extra pass
+/// is cheap next to a user waiting. The tree can change between the passes: `total` is then
+/// raised to `done` rather than reported lower, and a removal that finds fewer entries than
+/// counted ends with a last `progress(done, done)`. A failed removal stops reporting at its error.
+pub fn remove_with_percentage<P, F>(path: P, progress: F) -> Result<RemoveStats>
+where
+    P: AsRef<Path>,
+    F: FnMut(u64, u64) + Send,
+{
+    observe::remove_with_percentage(path.as_ref(), progress)
+}
+
+/// Same as `remove`, but running on a background thread and reporting what it removes
+/// as it goes, for async code rendering progress.
+///
+/// The stream yields a `RemoveEvent` for every entry removed, then either
+/// `RemoveEvent::Finished` with the stats or the error that stopped the removal, and then ends.
+/// Dropping the stream cancels the removal, cooperatively: the background thread stops
+/// before the next directory it would enter, so whatever was removed until then stays removed
+/// and the target is left partially removed. No runtime is needed, the thread wakes the task.
+#[cfg(feature = "async")]
+pub fn remove_stream<P: AsRef<Path>>(path: P) -> RemoveStream {
+    RemoveStream::spawn(path.as_ref())
+}
+
+/// The raw forceful traversal behind `remove`, for advanced use.
+///
+/// Unlike `remove` and `RemoveOptions::execute`, the path is not validated:
+/// there is no rejection of `.`/`..` as the last segment,
+/// and a missing target is an `Error::IoFailed` stat error rather than `Error::NotFound`.
+/// The target is assumed to exist and is removed exactly as given,
+/// so validating it (if needed) is the caller's responsibility.
+pub fn remove_tree<P: AsRef<Path>>(path: P, opts: &RemoveOptions) -> Result<RemoveStats> {
+    Remover::new(opts).run(path.as_ref()).map_err(Error::from)
+}
+
+/// Same as `RemoveOptions::execute`, but a failed removal still reports
+/// what it removed before hitting the error.
+///
+/// The removal stops at the first error as usual; `PartialRemoval::stats` only tells how far it got.
+pub fn remove_with_stats<P: AsRef<Path>>(
+    path: P,
+    opts: &RemoveOptions,
+) -> std::result::Result<RemoveStats, PartialRemoval> {
+    opts.execute_reporting_partial(path.as_ref())
+}
+
+/// Checks that `path` is a sensible removal target.
+///
+/// Trailing separators and `.` segments are dropped, so that e.g. `link/`
+/// names the symlink itself rather than the directory it points to.
+/// Paths that don't need that are returned as is, without allocating.
+///
+/// A last segment of `.` or `..` is rejected on all platforms, as it names the current
+/// or the parent directory rather than an entry of its own. With `allow_dot_dot`,
+/// such paths are instead resolved to the directory they name.
+///
+/// Roots have no parent and are rejected with a message of their own: `/`, and on Windows
+/// drives (`C:\`, `C:`), shares (`\\server\share`) and their `\\?\` extended forms.
+/// Extended paths are otherwise split like any other, so `\\?\C:\dir\` is `\\?\C:\dir`.
+fn validate_target(path: &Path, allow_dot_dot: bool) -> Result<Cow<'_, Path>> {
+    // the OS would only refuse it at the first call, with a bare `InvalidInput`
+    if path.as_os_str().as_encoded_bytes().contains(&0) {
+        return Err(Error::InvalidTarget(
+            "Invalid path, target contains an interior NUL byte".to_string(),
+        ));
+    }
+    let parent: &Path = match path.parent() {
+        Some(parent) => parent,
+        None if is_root(path) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is a filesystem root (`/`, a drive or a share), \
+                 which cannot be removed"
+                    .to_string(),
+            ))
+        }
+        None => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, cannot get parent".to_string(),
+            ))
+        }
+    };
+    let last_segment = path.components().next_back().ok_or_else(|| {
+        Error::InvalidTarget("Invalid path, cannot get last file path component".to_string())
+    })?;
+    // compared as an `OsStr`, so that names that aren't valid UTF-8 can be removed too
+    let last_segment_str = last_segment.as_os_str();
+
+    if last_segment_str == "." || last_segment_str == ".." {
+        if allow_dot_dot {
+            let resolved = path.canonicalize().map_err(|err| match err.kind() {
+                ErrorKind::NotFound => Error::NotFound,
+                _ => Error::IoError(err),
+            })?;
+            return Ok(Cow::Owned(validate_target(&resolved, false)?.into_owned()));
+        }
+        return Err(Error::InvalidTarget(
+            "Invalid path, last path segment cannot be \".\" or \"..\"".to_string(),
+        ));
+    }
+    // `components` skips non-leading `.` segments, so e.g. `dotdir/.` or `dotdir/./` have
+    // `dotdir` as their last segment and get rebuilt to plain `dotdir` below:
+    // the traversal, its stats and the paths it reports never see the trailing `.`.
+    let last_segment = last_segment.as_os_str();
+    if path
+        .as_os_str()
+        .as_encoded_bytes()
+        .ends_with(last_segment.as_encoded_bytes())
+    {
+        Ok(Cow::Borrowed(path))
+    } else {
+        Ok(Cow::Owned(parent.join(last_segment)))
+    }
+}
+
+/// Whether `path` is made of nothing but a root and/or a Windows prefix.
+fn is_root(path: &Path) -> bool {
+    let mut components = path.components().peekable();
+    components.peek().is_some()
+        && components
+            .all(|component| matches!(component, Component::Prefix(_) | Component::RootDir))
 }
 
 #[cfg(test)]
@@ -86,9 +705,23 @@ mod tests {
     use crate::ensure_removed;
     use crate::error::Error;
     use crate::remove;
+    use crate::remover::Remover;
+    use crate::sys::MockSys;
+    use crate::Operation;
+    use crate::{
+        analyze, prune, recover_staging, remove_all, remove_all_best_effort, remove_by_extension,
+        remove_contents_parallel, remove_deferred, remove_empty, remove_entries, remove_except,
+        remove_fast, remove_manifest, remove_older_generations, remove_parallel, remove_preserving,
+        remove_relative, remove_symlinks, remove_transactional, remove_tree, remove_with_stats,
+        reset, swap_clear, try_remove, would_require_force, GenerationOrder, RemovalAnalysis,
+        RemoveOptions, RemoveStats, RetryPolicy, SymlinkPolicy, DEFAULT_FANOUT_THRESHOLD,
+    };
     use std::ops::Not;
+    use std::path::{Path, PathBuf};
     use std::process::{Command, ExitStatus};
-    use std::sync::Once;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, Once};
+    use std::time::Duration;
 
     static INITIALIZATION: Once = Once::new();
 
@@ -126,6 +759,82 @@ mod tests {
         sh_exec("rm -rf dotdir");
     }
 
+    #[test]
+    fn trailing_current_directory_is_normalized_test() {
+        initialize();
+        for path in ["dotdir2/.", "dotdir2/./", "dotdir2/./.", "./dotdir2/."] {
+            let validated = crate::validate_target(Path::new(path), false).unwrap();
+            assert!(
+                !validated.to_string_lossy().ends_with('.'),
+                "{} wasn't normalized",
+                path
+            );
+        }
+        sh_exec("mkdir -p dotdir2/sub; touch dotdir2/sub/f");
+        let stats = remove_with_stats("dotdir2/.", &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.dirs_removed, 2);
+        assert_eq!(stats.files_removed, 1);
+        sh_exec("! test -e dotdir2");
+    }
+
+    #[test]
+    fn descriptor_based_fallback_test() {
+        initialize();
+        sh_exec("mkdir -p fdfallback/a/b fdfallback/c; touch fdfallback/a/b/f fdfallback/c/g");
+        if !Command::new("chattr")
+            .args(["+i", "fdfallback/a/b/f"])
+            .status()
+            .is_ok_and(|status| status.success())
+        {
+            sh_exec("rm -r fdfallback");
+            return; // needs the immutable attribute, e.g. root on ext4
+        }
+        match remove("fdfallback") {
+            Err(Error::IoFailed {
+                operation: Operation::RemoveFile,
+                path,
+                ..
+            }) => assert_eq!(path, Path::new("fdfallback/a/b/f")),
+            other => panic!("expected the unlink to fail, got {:?}", other),
+        }
+        sh_exec("chattr -i fdfallback/a/b/f");
+        assert!(try_remove("fdfallback").unwrap());
+        sh_exec("! test -e fdfallback");
+    }
+
+    #[test]
+    fn trailing_current_directory_error_paths_test() {
+        initialize();
+        sh_exec("mkdir -p dotdir3/sub; touch dotdir3/sub/f; ln -s sub dotdir3/sub/link");
+        let opts = RemoveOptions::new().symlinks(SymlinkPolicy::Error);
+        for path in ["dotdir3/.", "dotdir3/./", "dotdir3/./."] {
+            let partial = remove_with_stats(path, &opts).unwrap_err();
+            match partial.error {
+                Error::UnexpectedSymlink(link) => {
+                    assert_eq!(link, Path::new("dotdir3/sub/link"), "removing {}", path)
+                }
+                other => panic!("expected the symlink to be refused, got {:?}", other),
+            }
+        }
+        let sys = MockSys::new(|op, _: &Path| match op {
+            Operation::RemoveFile => {
+                Some(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            }
+            _ => None,
+        });
+        let validated = crate::validate_target(Path::new("dotdir3/."), false).unwrap();
+        match Remover::with_sys(&RemoveOptions::new(), &sys).run(&validated) {
+            Err(partial) => match partial.error {
+                Error::IoFailed { path, .. } => {
+                    assert_eq!(path.parent(), Some(Path::new("dotdir3/sub")))
+                }
+                other => panic!("expected the unlink to fail, got {:?}", other),
+            },
+            Ok(_) => panic!("the children can't be unlinked"),
+        }
+        sh_exec("rm -r dotdir3");
+    }
+
     fn assert_invalid_target(remove_result: Result<(), Error>) {
         match remove_result {
             Err(Error::InvalidTarget(_)) => (),
@@ -160,89 +869,3094 @@ mod tests {
     }
 
     #[test]
-    fn behavior_test() {
+    fn classify_test() {
+        use crate::remover::{classify, Action, Refusal};
         initialize();
-        test_eq_behavior("");
-        test_eq_behavior("touch target");
-        test_eq_behavior("touch target; chmod 000 target");
-        test_eq_behavior("touch target; chmod 777 target");
-
-        test_eq_behavior("mkdir target");
-        test_eq_behavior("mkdir target; ln -s unexistent target/link");
-        test_eq_behavior("mkdir target; chmod 000 target");
-        test_eq_behavior("mkdir target; chmod 777 target");
+        sh_exec("mkdir -p classify/dir; touch classify/file; ln -s dir classify/dir_link");
+        sh_exec("ln -s missing classify/dangling; mkfifo classify/fifo");
+        let metadata = |name: &str| Path::new("classify").join(name).symlink_metadata().unwrap();
+        let (remove, follow, error) = (
+            SymlinkPolicy::RemoveLink,
+            SymlinkPolicy::FollowAndRemoveTarget,
+            SymlinkPolicy::Error,
+        );
+        let refused = Action::Refuse(Refusal::SpecialFile);
+        let unexpected = Action::Refuse(Refusal::Symlink);
+        #[rustfmt::skip]
+        let table = [
+            // entry      policy  refuse devices  expected
+            ("file",      remove, false, Action::Unlink),
+            ("file",      follow, true,  Action::Unlink),
+            ("file",      error,  true,  Action::Unlink),
+            ("dir",       remove, false, Action::Descend),
+            ("dir",       follow, true,  Action::Descend),
+            ("dir",       error,  true,  Action::Descend),
+            ("dir_link",  remove, false, Action::Unlink),
+            ("dir_link",  remove, true,  Action::Unlink),
+            ("dir_link",  follow, false, Action::FollowThenUnlink),
+            ("dir_link",  error,  false, unexpected),
+            ("dangling",  remove, false, Action::Unlink),
+            ("dangling",  follow, true,  Action::FollowThenUnlink),
+            ("dangling",  error,  true,  unexpected),
+            ("fifo",      remove, false, Action::Unlink),
+            ("fifo",      follow, false, Action::Unlink),
+            ("fifo",      remove, true,  refused),
+            ("fifo",      error,  true,  refused),
+        ];
+        for (name, symlinks, refuse_devices, expected) in table {
+            let opts = RemoveOptions::new()
+                .symlinks(symlinks)
+                .refuse_device_nodes(refuse_devices);
+            assert_eq!(
+                classify(&metadata(name), &opts),
+                expected,
+                "{} with {:?}, refusing device nodes: {}",
+                name,
+                symlinks,
+                refuse_devices
+            );
+            // the checks that need more than the metadata are left to the caller
+            let checking = opts.refuse_mount_points(true).only_own_files(true);
+            assert_eq!(classify(&metadata(name), &checking), expected);
+        }
+        sh_exec("rm -r classify");
+    }
 
-        test_eq_behavior("mkdir -p target/subdir; chmod 000 target");
-        test_eq_behavior("mkdir -p target/subdir; chmod 777 target");
-        test_eq_behavior("mkdir -p target/subdir; chmod 444 target");
-        test_eq_behavior("mkdir -p target/subdir; chmod 222 target");
-        test_eq_behavior("mkdir -p target/subdir; chmod 111 target");
+    #[test]
+    fn preserve_parent_times_test() {
+        initialize();
+        let setup = "mkdir -p ptimes/target/sub; touch ptimes/target/sub/f ptimes/file; touch -d @1000000000.5 ptimes";
+        let times = || sh_output("stat -c '%X %Y' ptimes").trim().to_string();
+        sh_exec(setup);
+        let preserving = RemoveOptions::new().preserve_parent_times(true);
+        preserving.execute("ptimes/target").unwrap();
+        assert_eq!(times(), "1000000000 1000000000");
+        sh_exec("test $(stat -c %y ptimes | cut -c 21-29) = 500000000");
+        preserving.execute("ptimes/file").unwrap();
+        assert_eq!(times(), "1000000000 1000000000");
 
-        test_eq_behavior("ln -s unexistent target");
-        test_eq_behavior("ln -s /abc/def target");
-        test_eq_behavior("ln -s / target");
+        sh_exec(setup);
+        RemoveOptions::new().execute("ptimes/target").unwrap();
+        assert_ne!(times(), "1000000000 1000000000");
+        sh_exec("rm -r ptimes");
     }
 
-    fn test_eq_behavior(up: &str) {
-        test_eq_behavior_fn(
-            &|| {
-                assert!(sh_exec_status(up).success());
-            },
-            up,
+    #[test]
+    fn top_level_symlink_policies_test() {
+        initialize();
+        let create = || {
+            sh_exec("mkdir -p topsym/dir/sub; touch topsym/dir/sub/f topsym/other; ln -s dir topsym/link")
+        };
+        create();
+        let policy = |policy| RemoveOptions::new().symlinks(policy);
+        let stats = policy(SymlinkPolicy::RemoveLink)
+            .execute("topsym/link")
+            .unwrap();
+        let counts = (
+            stats.symlinks_removed,
+            stats.files_removed,
+            stats.dirs_removed,
         );
-    }
+        assert_eq!(counts, (1, 0, 0));
+        sh_exec("! test -L topsym/link && test -f topsym/dir/sub/f && test -f topsym/other");
 
-    fn sh_exec(script: &str) {
-        if sh_exec_status(script).success().not() {
-            panic!("Non-zero exit status of `{}`", script)
+        sh_exec("ln -s dir topsym/link");
+        match policy(SymlinkPolicy::Error).execute("topsym/link") {
+            Err(Error::UnexpectedSymlink(path)) => assert_eq!(path, Path::new("topsym/link")),
+            other => panic!("expected the link to be refused, got {:?}", other),
+        }
+        sh_exec("test -L topsym/link && test -f topsym/dir/sub/f");
+
+        let stats = policy(SymlinkPolicy::FollowAndRemoveTarget)
+            .execute("topsym/link")
+            .unwrap();
+        let counts = (
+            stats.symlinks_removed,
+            stats.files_removed,
+            stats.dirs_removed,
+        );
+        assert_eq!(counts, (1, 1, 2));
+        sh_exec("! test -L topsym/link && ! test -e topsym/dir && test -f topsym/other");
+
+        // the contents of the directory behind the link go, the link and the directory stay
+        sh_exec("rm -r topsym");
+        create();
+        for symlinks in [
+            SymlinkPolicy::RemoveLink,
+            SymlinkPolicy::FollowAndRemoveTarget,
+        ] {
+            let stats = policy(symlinks)
+                .trailing_slash_semantics(true)
+                .execute("topsym/link/")
+                .unwrap();
+            let counts = (
+                stats.symlinks_removed,
+                stats.files_removed,
+                stats.dirs_removed,
+            );
+            assert_eq!(counts, (0, 1, 1), "{:?}", symlinks);
+            sh_exec("test -L topsym/link && test -d topsym/dir && ! test -e topsym/dir/sub");
+            sh_exec("mkdir topsym/dir/sub; touch topsym/dir/sub/f");
         }
+        sh_exec("rm -r topsym");
     }
 
-    fn sh_exec_status(script: &str) -> ExitStatus {
-        Command::new("sh")
-            .arg("-c")
-            .arg(script)
-            .status()
-            .expect("failed to execute `sh`")
+    #[test]
+    fn remove_tree_stats_test() {
+        initialize();
+        sh_exec("mkdir -p treestats/a/b; touch treestats/f treestats/a/b/g");
+        sh_exec("ln -s f treestats/link");
+        let stats = remove_tree("treestats", &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.files_removed, 2);
+        assert_eq!(stats.symlinks_removed, 1);
+        assert_eq!(stats.dirs_removed, 3);
+        assert_eq!(stats.max_dir_fanout, 3);
+        sh_exec("! test -e treestats");
     }
 
-    fn test_eq_behavior_fn<F>(up: &F, test_name: &str)
-    where
-        F: Fn(),
-    {
-        clean();
-        up();
-        let rm_success = rm_rf_success();
-        clean();
-        up();
-        let rust_success = rust_remove_success();
-        clean();
+    #[test]
+    fn directory_swapped_for_file_test() {
+        initialize();
+        sh_exec("mkdir -p swapdir/sub; touch swapdir/sub/inner");
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::ReadDir && path == Path::new("swapdir/sub") {
+                sh_exec("rm -rf swapdir/sub; touch swapdir/sub");
+            }
+            None
+        });
+        let opts = RemoveOptions::default();
+        let stats = Remover::with_sys(&opts, &sys).run(Path::new("swapdir"));
+        let stats = stats.expect("a directory replaced by a file should still be removed");
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.dirs_removed, 1);
+        sh_exec("! test -e swapdir");
+    }
+
+    #[test]
+    fn max_bytes_test() {
+        initialize();
+        sh_exec("mkdir budgetdir; head -c 100 /dev/zero > budgetdir/a; head -c 100 /dev/zero > budgetdir/b");
+        match remove_tree("budgetdir", &RemoveOptions::new().max_bytes(150)) {
+            Err(Error::BudgetExceeded { removed, limit }) => {
+                assert_eq!(removed, 200);
+                assert_eq!(limit, 150);
+            }
+            other => panic!("expected the byte budget to be exceeded, got {:?}", other),
+        }
+        sh_exec("test -d budgetdir");
         assert_eq!(
-            rm_success, rust_success,
-            "`rm -rf` and `force_remove` behaved differently for test: {}",
-            test_name
+            remove_tree("budgetdir", &RemoveOptions::new().max_bytes(200))
+                .unwrap()
+                .dirs_removed,
+            1
         );
     }
 
-    fn clean() {
-        Command::new("sh")
-            .arg("-c")
-            .arg("chmod 777 target; rm -rf target")
-            .output()
-            .unwrap();
+    #[test]
+    fn max_entries_test() {
+        initialize();
+        sh_exec("mkdir -p entrybudget/a entrybudget/b; touch entrybudget/a/1 entrybudget/a/2 entrybudget/b/3");
+        let partial = remove_with_stats("entrybudget", &RemoveOptions::new().max_entries(3))
+            .expect_err("the tree has 6 entries");
+        match partial.error {
+            Error::BudgetExceeded { removed, limit } => {
+                assert_eq!(removed, 4);
+                assert_eq!(limit, 3);
+            }
+            other => panic!("expected the entry budget to be exceeded, got {:?}", other),
+        }
+        assert_eq!(partial.stats.entries_removed(), 4);
+        sh_exec("test -d entrybudget");
+        let stats = remove_tree("entrybudget", &RemoveOptions::new().max_entries(2)).unwrap();
+        assert_eq!(stats.entries_removed(), 2);
+        sh_exec("! test -e entrybudget");
     }
 
-    fn rm_rf_success() -> bool {
-        Command::new("rm")
-            .arg("-rf")
-            .arg("target")
-            .output()
-            .unwrap()
-            .status
-            .success()
+    #[test]
+    fn remove_entries_test() {
+        initialize();
+        sh_exec("mkdir -p entries/a/b entries/c; touch entries/a/b/f entries/d entries/e");
+        let mut listing = std::fs::read_dir("entries").unwrap();
+        let consumed = listing.next().unwrap().unwrap().path();
+        let stats = remove_entries("entries", listing, &RemoveOptions::default()).unwrap();
+        assert!(
+            consumed.symlink_metadata().is_ok(),
+            "consumed entries are left alone"
+        );
+        let remaining: Vec<_> = std::fs::read_dir("entries").unwrap().collect();
+        assert_eq!(remaining.len(), 1);
+        assert!(stats.entries_removed() >= 2);
+        sh_exec("rm -rf entries");
+    }
+
+    #[test]
+    fn execute_into_test() {
+        initialize();
+        sh_exec("mkdir -p into/a into/b; touch into/a/f into/b/f into/b/g");
+        let opts = RemoveOptions::new();
+        let mut total = RemoveStats::default();
+        opts.execute_into("into/a", &mut total).unwrap();
+        opts.execute_into("into/b", &mut total).unwrap();
+        assert_eq!(total.files_removed, 3);
+        assert_eq!(total.dirs_removed, 2);
+
+        sh_exec("mkdir -p into/c; touch into/c/1 into/c/2");
+        let result = opts
+            .clone()
+            .max_entries(1)
+            .execute_into("into/c", &mut total);
+        assert!(matches!(result, Err(Error::BudgetExceeded { .. })));
+        assert_eq!(total.entries_removed(), 7, "partial progress is added too");
+        sh_exec("rm -rf into");
+    }
+
+    #[test]
+    fn remove_contents_parallel_test() {
+        initialize();
+        sh_exec(
+            "mkdir -p cpar; for i in $(seq 20); do mkdir -p cpar/$i/sub; touch cpar/$i/sub/f; done",
+        );
+        sh_exec("touch cpar/file");
+        let stats = remove_contents_parallel("cpar", 4, &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.files_removed, 21);
+        assert_eq!(stats.dirs_removed, 40);
+        assert_eq!(stats.max_dir_fanout, 21);
+        sh_exec("test -d cpar && test -z \"$(ls -A cpar)\"");
+        assert_eq!(
+            remove_contents_parallel("cpar", 0, &RemoveOptions::default())
+                .unwrap()
+                .entries_removed(),
+            0
+        );
+        sh_exec("touch cpar/file");
+        assert!(matches!(
+            remove_contents_parallel("cpar/file", 2, &RemoveOptions::default()),
+            Err(Error::InvalidTarget(_))
+        ));
+        remove("cpar").unwrap();
+        assert!(matches!(
+            remove_contents_parallel("cpar", 2, &RemoveOptions::default()),
+            Err(Error::NotFound)
+        ));
+    }
+
+    #[test]
+    fn remove_all_aliases_test() {
+        initialize();
+        sh_exec("mkdir -p aliasreal/t/sub; touch aliasreal/t/sub/f");
+        sh_exec("ln -s aliasreal aliaslink");
+        let stats = remove_all(["aliasreal/t", "aliaslink/t", "aliasreal/../aliasreal/t"]).unwrap();
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.dirs_removed, 2);
+        sh_exec("! test -e aliasreal/t");
+
+        sh_exec("mkdir aliasreal/t");
+        let (stats, errors) = remove_all_best_effort(["aliaslink", "aliasreal/t", "aliasreal"]);
+        assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+        assert_eq!(
+            stats.symlinks_removed, 1,
+            "the link itself is not an alias of its target"
+        );
+        assert_eq!(stats.dirs_removed, 2);
+        sh_exec("! test -e aliaslink && ! test -e aliasreal");
+    }
+
+    #[test]
+    fn remove_all_overlapping_test() {
+        initialize();
+        sh_exec("mkdir -p overlap/a/b overlap/a/c/d overlap/e; touch overlap/a/b/f overlap/e/g");
+        let opts = RemoveOptions::new().count_syscalls(true);
+        let stats = opts
+            .execute_all([
+                "overlap/a/b",
+                "overlap/e/g",
+                "overlap/a",
+                "overlap/a/c/d",
+                "overlap/e",
+            ])
+            .unwrap();
+        assert_eq!(stats.targets_subsumed, 3);
+        assert_eq!(stats.files_removed, 2);
+        assert_eq!(stats.dirs_removed, 5);
+        assert_eq!(
+            stats.syscalls.stat, 7,
+            "only the topmost targets are stat-ed"
+        );
+        sh_exec("test -d overlap && ! test -e overlap/a && ! test -e overlap/e");
+        sh_exec("rmdir overlap");
+
+        // `overlap/x/..` would be `overlap` itself, but it is invalid and covers nothing
+        sh_exec("mkdir -p overlap/b");
+        let (stats, errors) = opts.execute_all_best_effort(["overlap/x/..", "overlap/b"]);
+        assert_eq!(stats.targets_subsumed, 0);
+        assert_eq!(stats.dirs_removed, 1);
+        match errors.into_vec().as_slice() {
+            [(path, Error::InvalidTarget(_))] => assert_eq!(path, Path::new("overlap/x/..")),
+            other => panic!("expected only `overlap/x/..` to fail, got {:?}", other),
+        }
+        sh_exec("test -d overlap && ! test -e overlap/b");
+        sh_exec("rmdir overlap");
+    }
+
+    #[test]
+    fn partial_stats_test() {
+        initialize();
+        sh_exec("mkdir -p partialdir/sub; touch partialdir/a partialdir/sub/b partialdir/sub/c");
+        let sys = MockSys::new(|op, path: &Path| match op {
+            Operation::RemoveFile if path.ends_with("c") => {
+                Some(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            }
+            _ => None,
+        });
+        let opts = RemoveOptions::default();
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(Path::new("partialdir"))
+            .expect_err("removal of `c` was made to fail");
+        match &partial.error {
+            Error::IoFailed {
+                operation, path, ..
+            } => {
+                assert_eq!(*operation, Operation::RemoveFile);
+                assert!(path.ends_with("c"));
+            }
+            other => panic!("expected the failed unlink of `c`, got {:?}", other),
+        }
+        assert!(partial
+            .error
+            .to_string()
+            .starts_with("remove_file failed on partialdir/"));
+        let remaining = Command::new("sh")
+            .arg("-c")
+            .arg("find partialdir -type f | wc -l")
+            .output()
+            .unwrap();
+        let remaining: u64 = String::from_utf8(remaining.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(partial.stats.files_removed, 3 - remaining);
+        sh_exec("test -e partialdir/sub/c");
+        sh_exec("rm -rf partialdir");
+
+        let partial = remove_with_stats("partialdir", &opts).expect_err("target is missing");
+        assert!(matches!(partial.error, Error::NotFound));
+        assert_eq!(partial.stats.entries_removed(), 0);
+    }
+
+    #[test]
+    fn trailing_slash_semantics_test() {
+        initialize();
+        let up =
+            "mkdir -p slashreal/sub; touch slashreal/f slashreal/sub/g; ln -s slashreal slashlink";
+        let listing = "find slashreal slashlink | sort";
+        sh_exec(up);
+        sh_exec("rm -rf slashlink/");
+        let rm_tree = sh_output(listing);
+        sh_exec("rm -rf slashreal slashlink");
+
+        sh_exec(up);
+        let opts = RemoveOptions::new().trailing_slash_semantics(true);
+        let stats = opts.execute("slashlink/").unwrap();
+        assert_eq!(stats.entries_removed(), 3);
+        assert_eq!(
+            rm_tree,
+            sh_output(listing),
+            "should behave like `rm -rf link/`"
+        );
+
+        opts.execute("slashlink").unwrap();
+        sh_exec("! test -e slashlink && test -d slashreal");
+        sh_exec("ln -s slashreal slashlink");
+        RemoveOptions::new().execute("slashlink/").unwrap();
+        sh_exec("! test -e slashlink && test -d slashreal");
+        sh_exec("rm -rf slashreal");
+    }
+
+    #[test]
+    fn require_match_test() {
+        initialize();
+        let missing = ["nomatch1", "nomatch2"];
+        assert!(remove_all(missing).is_ok());
+        let strict = RemoveOptions::new().require_match(true);
+        assert!(matches!(strict.execute_all(missing), Err(Error::NoMatch)));
+        sh_exec("touch nomatch2");
+        assert_eq!(strict.execute_all(missing).unwrap().files_removed, 1);
+    }
+
+    #[test]
+    fn remove_empty_test() {
+        initialize();
+        sh_exec("mkdir -p emptydir nonemptydir/sub");
+        assert!(remove_empty("emptydir").is_ok());
+        sh_exec("! test -e emptydir");
+        assert!(matches!(remove_empty("nonemptydir"), Err(Error::NotEmpty)));
+        sh_exec("test -d nonemptydir/sub");
+        assert!(matches!(remove_empty("emptydir"), Err(Error::NotFound)));
+        sh_exec("rm -rf nonemptydir");
+    }
+
+    #[test]
+    fn swap_clear_test() {
+        initialize();
+        sh_exec("mkdir -p swapclear/a/b; touch swapclear/f swapclear/a/b/g");
+        swap_clear("swapclear").unwrap();
+        sh_exec("test -d swapclear && test -z \"$(ls -A swapclear)\"");
+        sh_exec("! ls -A | grep -q swapclear.rm_rf");
+        sh_exec("touch swapclear/f");
+        assert!(matches!(
+            swap_clear("swapclear/f"),
+            Err(Error::InvalidTarget(_))
+        ));
+        sh_exec("rm -rf swapclear");
+    }
+
+    #[test]
+    fn remove_by_extension_test() {
+        initialize();
+        sh_exec("mkdir -p byext/a/b byext/c byext/keep.o byext/empty");
+        sh_exec("touch byext/x.o byext/a/y.TMP byext/a/b/z.o byext/c/keep.txt byext/c/w.o byext/keep.o/f");
+        let stats = remove_by_extension("byext", &["o", "tmp"], false).unwrap();
+        assert_eq!(stats.files_removed, 4);
+        assert_eq!(stats.dirs_removed, 0);
+        sh_exec("test -d byext/a/b && test -f byext/c/keep.txt && test -f byext/keep.o/f");
+
+        sh_exec("touch byext/a/b/z.o");
+        let stats = remove_by_extension("byext", &["o"], true).unwrap();
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.dirs_removed, 2, "a/b and then a became empty");
+        sh_exec("! test -e byext/a && test -d byext/empty && test -d byext/c");
+        sh_exec("rm -rf byext");
+    }
+
+    #[test]
+    fn top_level_swap_test() {
+        initialize();
+        sh_exec("mkdir -p toctou_victim; touch toctou_victim/precious toctou_file");
+        let metadata = Path::new("toctou_file").symlink_metadata().unwrap();
+        let mut swapped = false;
+        let mut restats = 0;
+        let sys = MockSys::new(|op, path: &Path| {
+            if path == Path::new("toctou_file") {
+                if !swapped {
+                    sh_exec("rm toctou_file; ln -s toctou_victim toctou_file");
+                    swapped = true;
+                }
+                if op == Operation::Stat {
+                    restats += 1;
+                }
+            }
+            None
+        });
+        let opts = RemoveOptions::default();
+        Remover::with_sys(&opts, &sys)
+            .run_with_metadata(Path::new("toctou_file"), &metadata)
+            .unwrap();
+        assert_eq!(
+            restats, 0,
+            "the snapshot must be the only stat of the target"
+        );
+        sh_exec("! test -e toctou_file && test -f toctou_victim/precious");
+        sh_exec("rm -rf toctou_victim");
+    }
+
+    #[test]
+    fn dry_run_test() {
+        initialize();
+        sh_exec("mkdir -p dryrun/a/b dryrun/empty; touch dryrun/f dryrun/a/g dryrun/a/b/h");
+        sh_exec("ln -s f dryrun/link");
+        let simulated = RemoveOptions::new()
+            .dry_run(true)
+            .execute("dryrun")
+            .unwrap();
+        sh_exec("test -f dryrun/a/b/h && test -L dryrun/link && test -d dryrun/empty");
+        let real = RemoveOptions::new().execute("dryrun").unwrap();
+        sh_exec("! test -e dryrun");
+        assert_eq!(simulated.files_removed, real.files_removed);
+        assert_eq!(simulated.symlinks_removed, real.symlinks_removed);
+        assert_eq!(simulated.dirs_removed, real.dirs_removed);
+        assert_eq!(simulated.dirs_removed, 4);
+    }
+
+    #[test]
+    fn missing_ok_test() {
+        initialize();
+        let opts = RemoveOptions::new().missing_ok(true);
+        let stats = opts.execute("missingok").unwrap();
+        assert_eq!(stats.entries_removed(), 0);
+        let pruned = crate::select::remove_selected(
+            Path::new("missingok"),
+            &opts,
+            &mut |_, _| crate::select::Selection::Remove,
+            false,
+        );
+        assert!(pruned.is_ok());
+        assert!(matches!(
+            opts.clone().missing_ok(false).execute("missingok"),
+            Err(Error::NotFound)
+        ));
+        assert!(matches!(
+            opts.execute("missingok/.."),
+            Err(Error::InvalidTarget(_))
+        ));
+        sh_exec("mkdir -p missingok/sub");
+        assert_eq!(opts.execute("missingok").unwrap().dirs_removed, 2);
+    }
+
+    #[test]
+    fn raw_not_found_test() {
+        initialize();
+        assert!(matches!(remove("rawmissing"), Err(Error::NotFound)));
+        let raw = RemoveOptions::new().raw_not_found(true);
+        match raw.execute("rawmissing") {
+            Err(Error::IoError(err)) => assert_eq!(err.raw_os_error(), Some(2)),
+            other => panic!("expected the raw io error, got {:?}", other),
+        }
+        assert!(raw.execute_all(["rawmissing"]).is_ok());
+    }
+
+    #[test]
+    fn prune_test() {
+        initialize();
+        sh_exec("mkdir -p prunedir/a/b/c prunedir/d prunedir/pristine");
+        sh_exec("touch prunedir/stale prunedir/a/stale prunedir/a/b/c/stale prunedir/d/stale prunedir/d/fresh");
+        let stats = prune("prunedir", |path, _| path.ends_with("stale")).unwrap();
+        assert_eq!(stats.files_removed, 4);
+        assert_eq!(stats.dirs_removed, 3, "a, a/b and a/b/c became empty");
+        sh_exec("! test -e prunedir/a && test -f prunedir/d/fresh && test -d prunedir/pristine");
+        let stats = prune("prunedir", |_, _| true).unwrap();
+        assert_eq!(stats.dirs_removed, 1);
+        sh_exec("test -d prunedir/pristine && ! test -e prunedir/d");
+        sh_exec("rm -rf prunedir");
+    }
+
+    #[test]
+    fn unix_permission_fixing_test() {
+        initialize();
+        // SAFETY: `geteuid` has no preconditions
+        if unsafe { libc::geteuid() } == 0 {
+            return; // root is never denied for lack of permissions
+        }
+        sh_exec("mkdir -p permfix/locked/sub permfix/readonly; touch permfix/locked/sub/f permfix/readonly/g");
+        sh_exec("chmod 000 permfix/locked; chmod 500 permfix/readonly");
+        assert!(RemoveOptions::new()
+            .fix_permissions(false)
+            .execute("permfix")
+            .is_err());
+        remove("permfix").unwrap();
+        sh_exec("! test -e permfix");
+
+        // the directory holding the target isn't part of the removal
+        sh_exec("mkdir outside_ro; touch outside_ro/f; chmod 500 outside_ro");
+        assert!(remove("outside_ro/f").is_err());
+        sh_exec("test $(stat -c %a outside_ro) = 500 && chmod 700 outside_ro && rm -r outside_ro");
+
+        let up =
+            "mkdir -p restoring/ro; touch restoring/ro/a restoring/ro/b; chmod 500 restoring/ro";
+        sh_exec(up);
+        let stopping = RemoveOptions::new().sorted(true).max_entries(0);
+        assert!(stopping.execute("restoring").is_err());
+        sh_exec("test $(stat -c %a restoring/ro) = 700 && test -f restoring/ro/b && chmod 500 restoring/ro");
+        assert!(stopping
+            .restore_permissions(true)
+            .execute("restoring")
+            .is_err());
+        sh_exec("test $(stat -c %a restoring/ro) = 500 && ! test -e restoring/ro/b");
+        remove("restoring").unwrap();
+    }
+
+    #[test]
+    fn analyze_test() {
+        initialize();
+        sh_exec(
+            "mkdir -p analysis/sub/deep analysis/locked; touch analysis/ro analysis/sub/deep/f",
+        );
+        sh_exec("touch analysis/sub/imm; ln -s sub analysis/link; mkfifo analysis/fifo");
+        sh_exec("chmod 444 analysis/ro; chmod 000 analysis/locked");
+        let immutable = Command::new("chattr")
+            .args(["+i", "analysis/sub/imm"])
+            .status()
+            .is_ok_and(|status| status.success());
+        // SAFETY: `geteuid` has no preconditions
+        let root = unsafe { libc::geteuid() } == 0;
+        let analysis = analyze("analysis").unwrap();
+        sh_exec(
+            "chmod 755 analysis/locked; chattr -i analysis/sub/imm 2>/dev/null; rm -r analysis",
+        );
+        let expected = RemovalAnalysis {
+            entries: 9,
+            read_only: 2,
+            immutable: if cfg!(target_os = "linux") && immutable {
+                1
+            } else {
+                0
+            },
+            unreadable_dirs: if root { 0 } else { 1 },
+            symlinks: 1,
+            mount_points: 0,
+            special_files: 1,
+            max_depth: 3,
+            max_fanout: 5,
+        };
+        assert_eq!(analysis, expected);
+        assert!(matches!(analyze("analysis"), Err(Error::NotFound)));
+
+        // `/dev` holds device nodes, and usually a few mounts such as `/dev/pts`
+        let mounts = std::fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+        let dev_mounts = mounts
+            .lines()
+            .filter(|line| {
+                line.split(' ')
+                    .nth(4)
+                    .is_some_and(|at| at.starts_with("/dev/"))
+            })
+            .count();
+        let dev = analyze("/dev").unwrap();
+        assert!(dev.special_files > 0);
+        assert_eq!(dev.mount_points > 0, dev_mounts > 0, "{:?}", dev);
+    }
+
+    #[test]
+    fn would_require_force_unreadable_test() {
+        initialize();
+        // SAFETY: `geteuid` has no preconditions
+        if unsafe { libc::geteuid() } == 0 {
+            return; // root lists any directory
+        }
+        sh_exec("mkdir -p unreadable/locked unreadable/open; chmod 000 unreadable/locked");
+        assert!(would_require_force("unreadable").unwrap());
+        assert!(std::fs::remove_dir_all("unreadable").is_err());
+        assert!(!would_require_force("unreadable/open").unwrap());
+        remove("unreadable").unwrap();
+    }
+
+    #[test]
+    fn only_own_files_test() {
+        initialize();
+        // SAFETY: `geteuid` has no preconditions
+        if unsafe { libc::geteuid() } != 0 {
+            return; // files of other users can only be made as root
+        }
+        sh_exec("mkdir -p shared/mine shared/mixed/theirs_dir shared/theirs_tree/inner");
+        sh_exec(
+            "touch shared/mine/f shared/mixed/f shared/mixed/theirs shared/theirs_tree/inner/f",
+        );
+        sh_exec("chown 65534 shared/mixed/theirs shared/mixed/theirs_dir shared/theirs_tree");
+        let opts = RemoveOptions::new().only_own_files(true);
+        let stats = opts.execute("shared").unwrap();
+        assert_eq!(stats.entries_skipped, 3);
+        assert_eq!((stats.files_removed, stats.dirs_removed), (2, 1));
+        sh_exec("test -f shared/mixed/theirs && test -d shared/mixed/theirs_dir");
+        sh_exec("test -f shared/theirs_tree/inner/f && ! test -e shared/mine && ! test -e shared/mixed/f");
+
+        let stats = opts.execute("shared/theirs_tree").unwrap();
+        assert_eq!((stats.entries_skipped, stats.entries_removed()), (1, 0));
+        sh_exec("rm -r shared");
+    }
+
+    #[test]
+    fn traversal_order_test() {
+        initialize();
+        let create =
+            || sh_exec("mkdir -p order/a/b order/c; touch order/a/b/f order/a/g order/c/h order/i");
+        create();
+        let walked: Vec<PathBuf> = crate::walk::TreeWalker::new(Path::new("order"))
+            .post_order(&crate::sys::RealSys)
+            .map(|entry| entry.unwrap().path)
+            .collect();
+        assert_eq!(walked.len(), 8);
+        assert_eq!(walked.last().unwrap(), Path::new("order"));
+
+        let mut staged = Vec::new();
+        crate::staging::transactional(Path::new("order"), |from, to| {
+            staged.push(from.to_path_buf());
+            std::fs::rename(from, to)
+        })
+        .unwrap();
+        assert_eq!(staged, walked);
+
+        // the same order, except that each directory is first tried while still full,
+        // see `crate::walk`
+        create();
+        let mut walker = crate::walk::TreeWalker::new(Path::new("order"));
+        let mut expected = Vec::new();
+        while let Some(visit) = walker.next(&crate::sys::RealSys) {
+            match visit.unwrap() {
+                crate::walk::Visit::Dir(entry)
+                | crate::walk::Visit::Leaf(entry)
+                | crate::walk::Visit::Leave(entry) => expected.push(entry.path),
+            }
+        }
+        assert_eq!(
+            expected.len(),
+            walked.len() + 4,
+            "four directories tried twice"
+        );
+        let removals = Mutex::new(Vec::new());
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::RemoveFile || op == Operation::RemoveDir {
+                removals.lock().unwrap().push(path.to_path_buf());
+            }
+            None
+        });
+        Remover::with_sys(&RemoveOptions::default(), &sys)
+            .run(Path::new("order"))
+            .unwrap();
+        assert_eq!(removals.into_inner().unwrap(), expected);
+    }
+
+    #[test]
+    fn sorted_test() {
+        initialize();
+        let create = || {
+            sh_exec("mkdir -p sorted/m sorted/b sorted/z; touch sorted/m/y sorted/m/a sorted/q sorted/c");
+            sh_exec("touch sorted/z/k sorted/z/e sorted/b/x")
+        };
+        create();
+        let expected = ["b/x", "c", "m/a", "m/y", "q", "z/e", "z/k"];
+        let unlinked = Mutex::new(Vec::new());
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::RemoveFile {
+                let relative = path.strip_prefix("sorted").unwrap();
+                unlinked
+                    .lock()
+                    .unwrap()
+                    .push(relative.to_str().unwrap().to_string());
+            }
+            None
+        });
+        let entered = Arc::new(Mutex::new(Vec::new()));
+        let recorded = entered.clone();
+        let opts = RemoveOptions::new()
+            .sorted(true)
+            .on_enter(move |dir| recorded.lock().unwrap().push(dir.to_path_buf()));
+        Remover::with_sys(&opts, &sys)
+            .run(Path::new("sorted"))
+            .unwrap();
+        assert_eq!(unlinked.into_inner().unwrap(), expected);
+        let dirs = ["sorted", "sorted/b", "sorted/m", "sorted/z"];
+        assert_eq!(*entered.lock().unwrap(), dirs.map(PathBuf::from));
+
+        create();
+        let mut seen = Vec::new();
+        let opts = RemoveOptions::new().sorted(true);
+        let mut select = |path: &Path, _: &std::fs::Metadata| {
+            seen.push(
+                path.strip_prefix("sorted")
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string(),
+            );
+            crate::select::Selection::Descend
+        };
+        crate::select::remove_selected(Path::new("sorted"), &opts, &mut select, false).unwrap();
+        let walked = ["b", "b/x", "c", "m", "m/a", "m/y", "q", "z", "z/e", "z/k"];
+        assert_eq!(seen, walked);
+        sh_exec("rm -r sorted");
+    }
+
+    #[test]
+    fn two_phase_test() {
+        initialize();
+        sh_exec("mkdir -p two_phase/a/b/c two_phase/d; touch two_phase/f two_phase/a/b/c/f");
+        sh_exec("touch two_phase/a/f two_phase/d/f; ln -s a two_phase/l");
+        let calls = Mutex::new(Vec::new());
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::RemoveFile || op == Operation::RemoveDir {
+                calls.lock().unwrap().push((op, path.to_path_buf()));
+            }
+            None
+        });
+        let opts = RemoveOptions::new().two_phase(true);
+        let stats = Remover::with_sys(&opts, &sys)
+            .run(Path::new("two_phase"))
+            .unwrap();
+        let counts = (
+            stats.files_removed,
+            stats.symlinks_removed,
+            stats.dirs_removed,
+        );
+        assert_eq!(counts, (4, 1, 5));
+        assert!(!Path::new("two_phase").exists());
+        let calls = calls.into_inner().unwrap();
+        let first_dir = calls
+            .iter()
+            .position(|(op, _)| *op == Operation::RemoveDir)
+            .unwrap();
+        assert!(calls[..first_dir]
+            .iter()
+            .all(|(op, _)| *op == Operation::RemoveFile));
+        let dirs: Vec<&Path> = calls[first_dir..]
+            .iter()
+            .map(|(op, path)| {
+                assert_eq!(*op, Operation::RemoveDir);
+                path.as_path()
+            })
+            .collect();
+        assert_eq!(dirs.len(), 5, "each directory is only removed once emptied");
+        assert!(dirs
+            .windows(2)
+            .all(|pair| pair[0].components().count() >= pair[1].components().count()));
+        assert_eq!(dirs[0], Path::new("two_phase/a/b/c"));
+        assert_eq!(dirs[4], Path::new("two_phase"));
+    }
+
+    #[test]
+    fn remove_older_generations_test() {
+        initialize();
+        sh_exec("mkdir -p gens/backup-1/data gens/backup-9 gens/backup-10 gens/backup-010 gens/backup-2");
+        sh_exec("touch gens/backup-1/data/f gens/backup-99.tar; ln -s backup-1 gens/backup-100");
+        sh_exec("touch -d @3000 gens/backup-1; touch -d @1000 gens/backup-9 gens/backup-2");
+        sh_exec("touch -d @2000 gens/backup-10 gens/backup-010");
+
+        let stats = remove_older_generations("gens", 3, GenerationOrder::Name).unwrap();
+        assert_eq!(
+            stats.dirs_removed, 3,
+            "backup-2, then backup-1 and its data"
+        );
+        sh_exec("test -d gens/backup-10 && test -d gens/backup-010 && test -d gens/backup-9");
+        sh_exec("test -f gens/backup-99.tar && test -L gens/backup-100 && ! test -e gens/backup-2");
+
+        sh_exec("touch -d @1000 gens/backup-10");
+        let stats = remove_older_generations("gens", 1, GenerationOrder::Modified).unwrap();
+        assert_eq!(stats.dirs_removed, 2);
+        sh_exec("test -d gens/backup-010 && ! test -e gens/backup-10 && ! test -e gens/backup-9");
+
+        // the tie between the two oldest is broken by name
+        sh_exec("mkdir gens/a gens/b; touch -d @500 gens/a gens/b");
+        remove_older_generations("gens", 2, GenerationOrder::Modified).unwrap();
+        sh_exec("test -d gens/backup-010 && test -d gens/b && ! test -e gens/a");
+        remove_older_generations("gens", 0, GenerationOrder::Name).unwrap();
+        sh_exec("test -f gens/backup-99.tar && test -z \"$(find gens -type d -mindepth 1)\"");
+        sh_exec("rm -r gens");
+    }
+
+    #[test]
+    fn remove_symlinks_test() {
+        initialize();
+        sh_exec("mkdir -p linkfarm/pkg/real linkfarm/empty linkfarm_target; touch linkfarm/f linkfarm/pkg/real/g linkfarm_target/h");
+        sh_exec("ln -s ../linkfarm_target linkfarm/dirlink; ln -s f linkfarm/pkg/filelink; ln -s missing linkfarm/dangling");
+        let stats = remove_symlinks("linkfarm").unwrap();
+        assert_eq!(stats.symlinks_removed, 3);
+        assert_eq!(stats.files_removed + stats.dirs_removed, 0);
+        sh_exec("test -f linkfarm/f && test -f linkfarm/pkg/real/g && test -d linkfarm/empty");
+        sh_exec("test -f linkfarm_target/h && test -z \"$(find linkfarm -type l)\"");
+        sh_exec("rm -r linkfarm linkfarm_target");
+    }
+
+    #[test]
+    fn read_dir_retry_test() {
+        initialize();
+        sh_exec("mkdir -p retrydir/sub; touch retrydir/sub/f");
+        let mut failures = 0;
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::ReadDir && path == Path::new("retrydir/sub") && failures == 0 {
+                failures += 1;
+                return Some(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+            }
+            None
+        });
+        let opts = RemoveOptions::default();
+        let stats = Remover::with_sys(&opts, &sys)
+            .run(Path::new("retrydir"))
+            .unwrap();
+        assert_eq!(failures, 1);
+        assert_eq!(stats.dirs_removed, 2);
+        sh_exec("! test -e retrydir");
+    }
+
+    #[test]
+    fn read_dir_vanished_test() {
+        initialize();
+        sh_exec("mkdir -p vanishdir/gone vanishdir/kept; touch vanishdir/gone/f vanishdir/kept/g");
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::ReadDir && path == Path::new("vanishdir/gone") {
+                // a concurrent removal got there first
+                sh_exec("rm -r vanishdir/gone");
+                return Some(std::io::Error::from(std::io::ErrorKind::NotFound));
+            }
+            None
+        });
+        let opts = RemoveOptions::new();
+        let stats = Remover::with_sys(&opts, &sys)
+            .run(Path::new("vanishdir"))
+            .expect("a directory removed concurrently is already where we want it");
+        // only what this removal removed is counted
+        assert_eq!((stats.files_removed, stats.dirs_removed), (1, 2));
+        sh_exec("! test -e vanishdir");
+    }
+
+    #[test]
+    fn count_syscalls_test() {
+        initialize();
+        let up = "mkdir -p syscalls/a; touch syscalls/f syscalls/a/g";
+        sh_exec(up);
+        let stats = RemoveOptions::new().execute("syscalls").unwrap();
+        assert_eq!(stats.syscalls.total(), 0, "counting is opt-in");
+        sh_exec(up);
+        let stats = RemoveOptions::new()
+            .count_syscalls(true)
+            .execute("syscalls")
+            .unwrap();
+        assert_eq!(stats.syscalls.stat, 4, "one stat per entry");
+        assert_eq!(stats.syscalls.unlink, 2);
+        assert_eq!(stats.syscalls.read_dir, 2);
+        assert_eq!(
+            stats.syscalls.rmdir, 4,
+            "a failed speculative and a final rmdir per directory"
+        );
+    }
+
+    #[test]
+    fn staging_collision_test() {
+        use crate::staging::{stage, staging_name, MAX_STAGING_ATTEMPTS};
+        initialize();
+        sh_exec("mkdir -p stagingcollision");
+        let target = Path::new("stagingcollision/t");
+        for taken in 0..3 {
+            std::fs::create_dir(staging_name(target, taken).unwrap()).unwrap();
+        }
+        let candidates = (0..).map(|counter| staging_name(target, counter));
+        let chosen = stage(target, candidates, |name| std::fs::create_dir(name)).unwrap();
+        assert_eq!(chosen, staging_name(target, 3).unwrap());
+        assert_eq!(
+            chosen.file_name().unwrap().to_string_lossy(),
+            format!(".t.rm_rf.{}.3", std::process::id())
+        );
+
+        let mut attempts = 0;
+        let always_taken = stage(target, (0..).map(|_| staging_name(target, 0)), |name| {
+            attempts += 1;
+            std::fs::create_dir(name)
+        });
+        assert!(matches!(always_taken, Err(Error::StagingCollision(_))));
+        assert_eq!(attempts, MAX_STAGING_ATTEMPTS);
+        sh_exec("rm -rf stagingcollision");
+    }
+
+    #[test]
+    fn remove_transactional_test() {
+        initialize();
+        sh_exec(
+            "mkdir -p transactional/a/b transactional/c; touch transactional/a/b/f transactional/g",
+        );
+        let failing = |from: &Path, to: &Path| {
+            if from.ends_with("c") {
+                Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            } else {
+                std::fs::rename(from, to)
+            }
+        };
+        let result = crate::staging::transactional(Path::new("transactional"), failing);
+        assert!(matches!(result, Err(Error::IoError(_))));
+        sh_exec(
+            "test -f transactional/a/b/f && test -f transactional/g && test -d transactional/c",
+        );
+        sh_exec("! ls -a | grep -q rm_rf");
+
+        remove_transactional("transactional").unwrap();
+        sh_exec("! test -e transactional && ! ls -a | grep -q rm_rf");
+        assert!(matches!(
+            remove_transactional("transactional"),
+            Err(Error::NotFound)
+        ));
+        sh_exec("touch transactional_file");
+        remove_transactional("transactional_file").unwrap();
+        sh_exec("! test -e transactional_file");
+    }
+
+    #[test]
+    fn recover_staging_test() {
+        initialize();
+        sh_exec("mkdir -p recover/t/sub; touch recover/t/f recover/t/sub/g");
+        let mut renames = 0;
+        let panicking = |from: &Path, to: &Path| {
+            renames += 1;
+            if renames == 3 {
+                panic!("interrupted while staging");
+            }
+            std::fs::rename(from, to)
+        };
+        let interrupted = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            crate::staging::transactional(Path::new("recover/t"), panicking)
+        }));
+        assert!(interrupted.is_err());
+        sh_exec(
+            "test -f recover/t/f && test -f recover/t/sub/g && ! ls -a recover | grep -q rm_rf",
+        );
+
+        // what a crash during a deferred removal or a swap_clear leaves behind
+        let orphan = crate::staging::staging_name(Path::new("recover/t"), 7).unwrap();
+        sh_exec(&format!("mkdir {0}; touch {0}/f", orphan.display()));
+        sh_exec("mkdir recover/.t.rm_rf.1.0 recover/.t.rm_rf.notes; touch recover/.hidden");
+        let stats = recover_staging("recover").unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (1, 1));
+        sh_exec(&format!("! test -e {}", orphan.display()));
+        // pid 1 is still running, the rest isn't staging
+        sh_exec("test -d recover/.t.rm_rf.1.0 && test -d recover/.t.rm_rf.notes && test -f recover/.hidden");
+        sh_exec("test -f recover/t/f && rm -r recover");
+    }
+
+    #[test]
+    fn follow_within_filesystem_test() {
+        initialize();
+        let other_fs = Path::new("/dev/shm");
+        let device = |path: &Path| std::os::unix::fs::MetadataExt::dev(&path.metadata().unwrap());
+        if !other_fs.is_dir() || device(other_fs) == device(Path::new(".")) {
+            return; // needs a directory on another filesystem
+        }
+        let backup = other_fs.join(format!("rm_rf_backup_{}", std::process::id()));
+        sh_exec(&format!(
+            "mkdir -p {0} xdev/same_target; touch {0}/f xdev/same_target/f",
+            backup.display()
+        ));
+        sh_exec(&format!(
+            "ln -s {} xdev/backup_link; ln -s same_target xdev/same_link",
+            backup.display()
+        ));
+        sh_exec("cp -a xdev xdev_copy");
+        let following = RemoveOptions::new().symlinks(SymlinkPolicy::FollowAndRemoveTarget);
+
+        let stats = following
+            .clone()
+            .follow_within_filesystem(true)
+            .execute("xdev")
+            .unwrap();
+        assert_eq!(stats.symlinks_removed, 2);
+        assert!(
+            backup.join("f").is_file(),
+            "the other filesystem must be left alone"
+        );
+
+        match following
+            .clone()
+            .refuse_mount_points(true)
+            .execute("xdev_copy")
+        {
+            Err(Error::MountPoint(path)) => assert_eq!(path, backup),
+            other => panic!("expected the link target to be refused, got {:?}", other),
+        }
+        assert!(backup.join("f").is_file());
+        std::fs::remove_dir_all(&backup).unwrap();
+        sh_exec("rm -r xdev_copy");
+    }
+
+    #[test]
+    fn symlink_policy_test() {
+        initialize();
+        sh_exec("mkdir -p symp/a symp_dir; touch symp/a/f symp_dir/f symp_file");
+        sh_exec("ln -s ../../symp_dir symp/a/dir_link; ln -s ../symp_file symp/file_link");
+        sh_exec("ln -s missing symp/dangling; ln -s a/f symp/inside_link");
+        sh_exec("ln -s symp_dir top_link; cp -a symp symp_copy");
+
+        let erroring = RemoveOptions::new().symlinks(SymlinkPolicy::Error);
+        match erroring.execute("top_link") {
+            Err(Error::UnexpectedSymlink(path)) => assert_eq!(path, Path::new("top_link")),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(matches!(
+            erroring.execute("symp"),
+            Err(Error::UnexpectedSymlink(_))
+        ));
+        sh_exec("test -L top_link && test -f symp_dir/f && test -f symp_file");
+
+        let stats = RemoveOptions::new()
+            .symlinks(SymlinkPolicy::RemoveLink)
+            .execute("symp_copy")
+            .unwrap();
+        assert_eq!(stats.symlinks_removed, 4);
+        sh_exec("! test -e symp_copy && test -f symp_dir/f && test -f symp_file");
+
+        let following = RemoveOptions::new().symlinks(SymlinkPolicy::FollowAndRemoveTarget);
+        let stats = following.execute("symp").unwrap();
+        assert_eq!(stats.symlinks_removed, 4);
+        sh_exec("! test -e symp && ! test -e symp_dir && ! test -e symp_file");
+        assert_eq!(following.execute("top_link").unwrap().symlinks_removed, 1);
+        sh_exec("! test -L top_link");
+
+        sh_exec("mkdir -p symloop/a symloop/b; ln -s ../b symloop/a/l; ln -s ../a symloop/b/l");
+        sh_exec("mkdir -p symself; ln -s .. symself/up; ln -s cycle symself/cycle");
+        for (target, link) in [
+            ("symloop/a", "symloop/b/l"),
+            ("symself/up", "symself/up"),
+            ("symself/cycle", "symself/cycle"),
+        ] {
+            match following.execute(target) {
+                Err(Error::SymlinkLoop(path)) => assert!(path.ends_with(link), "{:?}", path),
+                other => panic!("{}: expected a symlink loop, got {:?}", target, other),
+            }
+        }
+        sh_exec("rm -rf symloop symself");
+    }
+
+    #[test]
+    fn max_symlink_hops_test() {
+        initialize();
+        sh_exec("mkdir -p hops/tree hops/target; touch hops/target/f; ln -s target hops/l5");
+        sh_exec("cd hops; for i in 4 3 2 1; do ln -s l$((i + 1)) l$i; done; ln -s ../l1 tree/link");
+        let following = RemoveOptions::new().symlinks(SymlinkPolicy::FollowAndRemoveTarget);
+        // six links from `tree/link` to `target`, five from `l1`
+        for (target, link) in [("hops/tree", "hops/tree/link"), ("hops/l1", "hops/l1")] {
+            match following.clone().max_symlink_hops(4).execute(target) {
+                Err(Error::SymlinkLoop(path)) => assert_eq!(path, Path::new(link)),
+                other => panic!("{}: expected too many hops, got {:?}", target, other),
+            }
+        }
+        sh_exec("test -L hops/tree/link && test -f hops/target/f");
+        let stats = following.max_symlink_hops(6).execute("hops/tree").unwrap();
+        assert_eq!((stats.symlinks_removed, stats.files_removed), (1, 1));
+        sh_exec("! test -e hops/target && test -L hops/l1");
+        sh_exec("rm -r hops");
+    }
+
+    #[test]
+    fn refuse_device_nodes_test() {
+        initialize();
+        sh_exec("mkdir -p special/a; touch special/a/f; mkfifo special/a/fifo");
+        let opts = RemoveOptions::new().refuse_device_nodes(true);
+        match opts.execute("special") {
+            Err(Error::RefusedSpecialFile(path)) => {
+                assert_eq!(path, Path::new("special/a/fifo"))
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        sh_exec("test -p special/a/fifo");
+        assert!(matches!(
+            opts.execute("special/a/fifo"),
+            Err(Error::RefusedSpecialFile(_))
+        ));
+        let stats = remove_with_stats("special", &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.files_removed, 2);
+        sh_exec("! test -e special");
+    }
+
+    #[test]
+    fn non_utf8_names_test() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        initialize();
+        sh_exec("mkdir -p nonutf8");
+        let name = Path::new("nonutf8").join(OsStr::from_bytes(b"bad\xffname"));
+        std::fs::create_dir(&name).unwrap();
+        std::fs::write(name.join("f"), b"").unwrap();
+        let sys = MockSys::new(|op, _: &Path| match op {
+            Operation::RemoveFile => {
+                Some(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            }
+            _ => None,
+        });
+        let opts = RemoveOptions::default();
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(&name)
+            .expect_err("removal of `f` was made to fail");
+        let message = partial.error.to_string();
+        assert!(
+            message.starts_with("remove_file failed on nonutf8/bad\u{FFFD}name/f: "),
+            "{}",
+            message
+        );
+        assert!(partial.to_string().contains("bad\u{FFFD}name"));
+        remove(&name).unwrap();
+        sh_exec("rmdir nonutf8");
+    }
+
+    #[test]
+    fn dangling_top_level_symlink_test() {
+        initialize();
+        sh_exec("touch dangling_file");
+        for (policy, removed) in [
+            (SymlinkPolicy::RemoveLink, true),
+            (SymlinkPolicy::FollowAndRemoveTarget, true),
+            (SymlinkPolicy::Error, false),
+        ] {
+            sh_exec("ln -s dangling_missing dangling; ln -s dangling_file/x dangling_through_file");
+            let opts = RemoveOptions::new().symlinks(policy).missing_ok(false);
+            for link in ["dangling", "dangling_through_file"] {
+                match opts.execute(link) {
+                    Ok(stats) if removed => assert_eq!(stats.symlinks_removed, 1),
+                    Err(Error::UnexpectedSymlink(_)) if !removed => {}
+                    other => panic!("{:?} on {}: unexpected {:?}", policy, link, other),
+                }
+                assert_eq!(Path::new(link).symlink_metadata().is_err(), removed);
+            }
+            sh_exec("rm -f dangling dangling_through_file");
+        }
+        sh_exec("rm dangling_file");
+    }
+
+    #[test]
+    fn symlink_loop_in_path_test() {
+        initialize();
+        sh_exec("mkdir -p pathloop; ln -s loop_b pathloop/loop_a; ln -s loop_a pathloop/loop_b");
+        let target = Path::new("pathloop/loop_a/x");
+        match remove(target) {
+            Err(Error::SymlinkLoop(path)) => assert_eq!(path, target),
+            other => panic!("expected a symlink loop, got {:?}", other),
+        }
+        assert!(matches!(
+            RemoveOptions::new().missing_ok(true).execute(target),
+            Err(Error::SymlinkLoop(_))
+        ));
+        remove("pathloop").unwrap();
+    }
+
+    #[test]
+    fn presets_test() {
+        initialize();
+        let cwd_parent = std::env::current_dir()
+            .unwrap()
+            .parent()
+            .unwrap()
+            .to_path_buf();
+        assert!(matches!(
+            RemoveOptions::safe().dry_run(true).execute(&cwd_parent),
+            Err(Error::InvalidTarget(_))
+        ));
+        #[cfg(target_os = "linux")]
+        match RemoveOptions::safe().dry_run(true).execute("/proc") {
+            Err(Error::MountPoint(path)) => assert_eq!(path, Path::new("/proc")),
+            other => panic!("expected /proc to be refused, got {:?}", other),
+        }
+        sh_exec("mkdir -p presets/dir presets_outside; touch presets_outside/f");
+        sh_exec("ln -s ../presets_outside presets/dir/link");
+        assert!(matches!(
+            RemoveOptions::safe().execute("presets"),
+            Err(Error::UnexpectedSymlink(_))
+        ));
+        let stats = RemoveOptions::fast().execute("presets").unwrap();
+        assert_eq!(stats.symlinks_removed, 1);
+        sh_exec("! test -e presets && test -f presets_outside/f && rm -r presets_outside");
+    }
+
+    #[test]
+    fn remove_deferred_test() {
+        initialize();
+        sh_exec("mkdir -p deferred/a; touch deferred/a/f");
+        let handle = remove_deferred("deferred").unwrap();
+        sh_exec("! test -e deferred");
+        let stats = handle.join().unwrap().unwrap();
+        assert_eq!(stats.files_removed, 1);
+        assert_eq!(stats.dirs_removed, 2);
+        sh_exec("! ls -A | grep -q deferred.rm_rf");
+        assert!(matches!(remove_deferred("deferred"), Err(Error::NotFound)));
+
+        sh_exec("mkdir -p deferred/a; touch deferred/a/f deferred_other");
+        let mut swapped = false;
+        let result = crate::staging::deferred(Path::new("deferred"), |from, to| {
+            if !swapped {
+                // between the stat and the rename, the target gets replaced
+                swapped = true;
+                sh_exec("mv deferred deferred_moved && ln -s deferred_moved deferred");
+            }
+            crate::staging::rename_no_replace(from, to)
+        });
+        assert!(matches!(result, Err(Error::InvalidTarget(_))));
+        sh_exec("test -L deferred && test -f deferred_moved/a/f");
+        sh_exec("! ls -A | grep -q deferred.rm_rf");
+
+        sh_exec("touch deferred_taken");
+        assert_eq!(
+            crate::staging::rename_no_replace(
+                Path::new("deferred_other"),
+                Path::new("deferred_taken")
+            )
+            .unwrap_err()
+            .kind(),
+            std::io::ErrorKind::AlreadyExists
+        );
+        sh_exec("rm -r deferred deferred_moved deferred_other deferred_taken");
+    }
+
+    #[test]
+    fn callback_panic_test() {
+        initialize();
+        sh_exec("mkdir -p panicdir/a panicdir/b; touch panicdir/a/f panicdir/b/g");
+        let panicked = Arc::new(AtomicBool::new(false));
+        let once = panicked.clone();
+        let opts = RemoveOptions::new().sorted(true).on_enter(move |path| {
+            if path.ends_with("b") && !once.swap(true, Ordering::Relaxed) {
+                panic!("refusing {}", path.display());
+            }
+        });
+        let partial = remove_with_stats("panicdir", &opts).unwrap_err();
+        match partial.error {
+            Error::CallbackPanicked(Some(message)) => assert_eq!(message, "refusing panicdir/b"),
+            other => panic!("expected the panic to be caught, got {:?}", other),
+        }
+        assert_eq!(
+            (partial.stats.files_removed, partial.stats.dirs_removed),
+            (1, 1)
+        );
+        // the callback isn't poisoned, and the removal can go on
+        assert!(panicked.load(Ordering::Relaxed));
+        opts.execute("panicdir").unwrap();
+
+        sh_exec("mkdir -p panicdir/a; touch panicdir/a/f panicdir/g");
+        let result = prune("panicdir", |path, _| {
+            if path.ends_with("g") {
+                panic!("bad predicate");
+            }
+            false
+        });
+        assert!(
+            matches!(result, Err(Error::CallbackPanicked(Some(message))) if message == "bad predicate")
+        );
+        let result = prune("panicdir", |_, _| std::panic::panic_any(42));
+        assert!(matches!(result, Err(Error::CallbackPanicked(None))));
+        sh_exec("rm -r panicdir");
+    }
+
+    #[test]
+    fn on_enter_test() {
+        initialize();
+        sh_exec("mkdir -p enterdir/a/empty enterdir/b; touch enterdir/a/f");
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let entered = events.clone();
+        let opts = RemoveOptions::new().on_enter(move |path| {
+            entered
+                .lock()
+                .unwrap()
+                .push(format!("enter {}", path.display()))
+        });
+        let sys = MockSys::new(|op, path: &Path| {
+            events
+                .lock()
+                .unwrap()
+                .push(format!("{:?} {}", op, path.display()));
+            None
+        });
+        Remover::with_sys(&opts, &sys)
+            .run(Path::new("enterdir"))
+            .unwrap();
+        let events = events.lock().unwrap();
+        let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+        for dir in ["enterdir", "enterdir/a", "enterdir/a/empty", "enterdir/b"] {
+            let entered = position(&format!("enter {}", dir));
+            assert!(
+                entered < position(&format!("RemoveDir {}", dir)),
+                "{:?}",
+                events
+            );
+            let first_child = events.iter().position(|e| e.contains(&format!("{}/", dir)));
+            assert!(
+                first_child.is_none_or(|child| entered < child),
+                "{:?}",
+                events
+            );
+        }
+        assert_eq!(events.iter().filter(|e| e.starts_with("enter")).count(), 4);
+    }
+
+    #[test]
+    fn retry_timeouts_test() {
+        initialize();
+        sh_exec("mkdir -p retrytimeout; touch retrytimeout/f");
+        let failures = Mutex::new(0);
+        let sys = MockSys::new(|op, path: &Path| {
+            let mut failures = failures.lock().unwrap();
+            if op == Operation::RemoveFile && path.ends_with("f") && *failures < 3 {
+                *failures += 1;
+                return Some(std::io::Error::from(std::io::ErrorKind::TimedOut));
+            }
+            None
+        });
+        let policy = RetryPolicy::new(2, Duration::from_millis(1));
+        let opts = RemoveOptions::new().retry(policy).count_syscalls(true);
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(Path::new("retrytimeout"))
+            .expect_err("the file timed out three times");
+        match partial.error {
+            Error::Timeout {
+                operation: Operation::RemoveFile,
+                path,
+                source,
+            } => {
+                assert_eq!(path, Path::new("retrytimeout/f"));
+                assert_eq!(source.kind(), std::io::ErrorKind::TimedOut);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert_eq!(
+            partial.stats.syscalls.unlink, 3,
+            "one attempt and two retries"
+        );
+
+        *failures.lock().unwrap() = 1;
+        let stats = Remover::with_sys(&opts, &sys)
+            .run(Path::new("retrytimeout"))
+            .expect("two timeouts are retried away");
+        assert_eq!(stats.files_removed, 1);
+        sh_exec("! test -e retrytimeout");
+    }
+
+    #[test]
+    fn per_op_timeout_test() {
+        use crate::sys::TimeoutSys;
+        initialize();
+        sh_exec("mkdir -p optimeout; touch optimeout/fast optimeout/slow");
+        let hung = MockSys::new(|op, path: &Path| {
+            if op == Operation::RemoveFile && path.ends_with("slow") {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            None
+        });
+        let sys = TimeoutSys::new(hung, Duration::from_millis(50));
+        let opts = RemoveOptions::new();
+        let started = std::time::Instant::now();
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(Path::new("optimeout"))
+            .expect_err("unlinking `slow` hangs");
+        assert!(started.elapsed() < Duration::from_millis(400));
+        match partial.error {
+            Error::Timeout {
+                operation: Operation::RemoveFile,
+                path,
+                source,
+            } => {
+                assert_eq!(path, Path::new("optimeout/slow"));
+                assert_eq!(source.kind(), std::io::ErrorKind::TimedOut);
+            }
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        std::thread::sleep(Duration::from_millis(600));
+        let stats = RemoveOptions::new()
+            .per_op_timeout(Duration::from_secs(10))
+            .execute("optimeout")
+            .unwrap();
+        assert_eq!(stats.dirs_removed, 1);
+        sh_exec("! test -e optimeout");
+    }
+
+    #[test]
+    fn journal_test() {
+        initialize();
+        sh_exec("mkdir -p journaled/sub; printf abc > journaled/f; ln -s f journaled/link; touch 'journaled/sub/tab	bed'");
+        let opts = RemoveOptions::new()
+            .sorted(true)
+            .journal(PathBuf::from("journal.log"));
+        assert!(matches!(
+            opts.clone()
+                .journal(PathBuf::from("journaled/sub/journal.log"))
+                .execute("journaled"),
+            Err(Error::InvalidTarget(_))
+        ));
+        opts.clone().dry_run(true).execute("journaled").unwrap();
+        sh_exec(
+            "test -f journaled/f && ! test -e journal.log && ! test -e journaled/sub/journal.log",
+        );
+
+        opts.execute("journaled").unwrap();
+        let journal = std::fs::read_to_string("journal.log").unwrap();
+        let entries: Vec<Vec<&str>> = journal
+            .lines()
+            .map(|line| line.split('\t').collect())
+            .collect();
+        let recorded: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry[1], entry[2], entry[3]))
+            .collect();
+        assert_eq!(
+            recorded,
+            [
+                ("file", "3", "journaled/f"),
+                ("symlink", "1", "journaled/link"),
+                ("file", "0", "journaled/sub/tab\\tbed"),
+                ("dir", "0", "journaled/sub"),
+                ("dir", "0", "journaled"),
+            ]
+        );
+        assert!(entries.iter().all(|entry| entry[0].parse::<f64>().is_ok()));
+        sh_exec("rm journal.log");
+    }
+
+    #[test]
+    fn cancel_token_test() {
+        initialize();
+        sh_exec("mkdir -p canceldir/a canceldir/b; touch canceldir/a/f canceldir/b/g");
+        let token = Arc::new(AtomicBool::new(false));
+        let opts = RemoveOptions::new().cancel_token(token.clone());
+        let sys = MockSys::new(|op, _: &Path| {
+            // checked again before the next entry, whichever directory it's in
+            if op == Operation::RemoveFile {
+                token.store(true, Ordering::Relaxed);
+            }
+            None
+        });
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(Path::new("canceldir"))
+            .expect_err("the second directory must not be entered");
+        assert!(matches!(partial.error, Error::Cancelled));
+        assert_eq!(partial.stats.files_removed, 1);
+        sh_exec("test -d canceldir");
+
+        let (stats, errors) = opts.execute_all_best_effort(["canceldir", "missing"]);
+        assert_eq!(stats.entries_removed(), 0);
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors.iter().next(), Some((_, Error::Cancelled))));
+        assert!(matches!(
+            opts.execute_prune("canceldir", |_, _| true),
+            Err(Error::Cancelled)
+        ));
+        sh_exec("mkdir canceldir/empty");
+        assert!(matches!(
+            opts.execute_empty("canceldir/empty"),
+            Err(Error::Cancelled)
+        ));
+        sh_exec("test -f canceldir/a/f && test -d canceldir/empty");
+        sh_exec("rm -rf canceldir");
+    }
+
+    #[test]
+    fn parallel_cancel_stress_test() {
+        initialize();
+        // 8 subtrees of 500 files, and a directory of 2000 files to split between threads
+        let tree = "for d in $(seq 8); do mkdir -p pcancel/$d; (cd pcancel/$d && touch $(seq 500)); done; \
+            mkdir -p pcancel/wide; (cd pcancel/wide && touch $(seq 2000))";
+        let files = || {
+            sh_output("find pcancel -type f | wc -l")
+                .trim()
+                .parse::<usize>()
+                .unwrap()
+        };
+        let cancelling = |token: Arc<AtomicBool>, after: usize| {
+            let sized = AtomicUsize::new(0);
+            RemoveOptions::new()
+                .cancel_token(token.clone())
+                .parallel_fanout_threshold(64)
+                .size_fn(move |_, metadata| {
+                    if sized.fetch_add(1, Ordering::Relaxed) + 1 == after {
+                        token.store(true, Ordering::Relaxed);
+                    }
+                    metadata.len()
+                })
+        };
+        for round in 0..2 {
+            sh_exec(tree);
+            let after = 100 + round * 1000;
+            let opts = cancelling(Arc::new(AtomicBool::new(false)), after);
+            let result = if round == 0 {
+                remove_parallel("pcancel", &opts)
+            } else {
+                remove_contents_parallel("pcancel", 8, &opts)
+            };
+            assert!(matches!(result, Err(Error::Cancelled)), "{:?}", result);
+            // every thread stops once its current entry is removed
+            let threads = std::thread::available_parallelism()
+                .map_or(1, |n| n.get())
+                .max(8);
+            let left = files();
+            assert!(
+                left >= 6000 - after - threads,
+                "round {}: {} left",
+                round,
+                left
+            );
+            sh_exec("rm -rf pcancel");
+        }
+    }
+
+    #[test]
+    fn deadline_test() {
+        initialize();
+        for d in 0..50 {
+            sh_exec(&format!("mkdir -p deadline/{0}; touch deadline/{0}/f", d));
+        }
+        let opts = RemoveOptions::new()
+            .sorted(true)
+            .deadline(std::time::Instant::now() + Duration::from_millis(100));
+        let sys = MockSys::new(|op, path: &Path| {
+            if op == Operation::RemoveFile && path.ends_with("10/f") {
+                std::thread::sleep(Duration::from_millis(150));
+            }
+            None
+        });
+        let partial = Remover::with_sys(&opts, &sys)
+            .run(Path::new("deadline"))
+            .expect_err("the deadline must stop the removal");
+        assert!(matches!(partial.error, Error::DeadlineExceeded));
+        // directories are listed in name order: 0, 1, 10, 11...
+        assert_eq!(partial.stats.files_removed, 3);
+        assert_eq!(partial.stats.dirs_removed, 3);
+        sh_exec("test -d deadline/11 && ! test -e deadline/10");
+
+        let (stats, errors) = opts.execute_all_best_effort(["deadline/11", "deadline/12"]);
+        assert_eq!(stats.entries_removed(), 0);
+        assert!(matches!(
+            errors.iter().next(),
+            Some((_, Error::DeadlineExceeded))
+        ));
+        assert_eq!(errors.len(), 1);
+        let stats = opts
+            .deadline(std::time::Instant::now() + Duration::from_secs(60))
+            .execute("deadline")
+            .unwrap();
+        assert_eq!(stats.dirs_removed, 48);
+    }
+
+    #[test]
+    fn remove_relative_test() {
+        initialize();
+        sh_exec("mkdir -p relative/sub/a relative_outside; printf 123 > relative/sub/a/f; touch relative_outside/g");
+        sh_exec("ln -s ../relative_outside relative/sub/link; printf 12345 > relative/big");
+        let dir = std::fs::File::open("relative").unwrap();
+        sh_exec("mv relative relative_moved"); // the handle keeps pointing at the moved directory
+        let opts = RemoveOptions::default();
+        let stats = remove_relative(&dir, "sub".as_ref(), &opts).unwrap();
+        assert_eq!(
+            (
+                stats.files_removed,
+                stats.symlinks_removed,
+                stats.dirs_removed
+            ),
+            (1, 1, 2)
+        );
+        sh_exec("! test -e relative_moved/sub && test -f relative_outside/g");
+
+        assert!(matches!(
+            remove_relative(&dir, "sub".as_ref(), &opts),
+            Err(Error::NotFound)
+        ));
+        for name in ["", ".", "..", "sub/a", "/big"] {
+            let result = remove_relative(&dir, name.as_ref(), &opts);
+            assert!(
+                matches!(result, Err(Error::InvalidTarget(_))),
+                "{:?} must be rejected",
+                name
+            );
+        }
+        let result = remove_relative(&dir, "big".as_ref(), &RemoveOptions::new().max_bytes(4));
+        assert!(matches!(
+            result,
+            Err(Error::BudgetExceeded {
+                removed: 5,
+                limit: 4
+            })
+        ));
+        for opts in [
+            RemoveOptions::new().dry_run(true),
+            RemoveOptions::new().symlinks(SymlinkPolicy::Error),
+            RemoveOptions::new().only_own_files(true),
+            RemoveOptions::new().protect_system_paths(true),
+        ] {
+            match remove_relative(&dir, "big".as_ref(), &opts) {
+                Err(Error::IoError(err)) => assert_eq!(err.kind(), std::io::ErrorKind::Unsupported),
+                other => panic!("expected an unsupported option, got {:?}", other),
+            }
+        }
+
+        sh_exec("mkdir relative_moved/locked; touch relative_moved/locked/f; chmod 500 relative_moved/locked");
+        let kept = remove_relative(
+            &dir,
+            "locked".as_ref(),
+            &RemoveOptions::new().fix_permissions(false),
+        );
+        // SAFETY: `geteuid` has no preconditions
+        if unsafe { libc::geteuid() } != 0 {
+            assert!(kept.is_err(), "only root unlinks from a 500 directory");
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let opts = RemoveOptions::new().record_permission_changes(log.clone());
+            remove_relative(&dir, "locked".as_ref(), &opts).unwrap();
+            assert_eq!(*log.lock().unwrap(), [PathBuf::from("locked")]);
+        }
+        sh_exec("rm -rf relative_moved relative_outside");
+    }
+
+    #[test]
+    fn inodes_freed_test() {
+        initialize();
+        let setup = "mkdir -p inodes/sub; touch inodes/a inodes/b inodes_outside; ln inodes/b inodes/sub/c; ln inodes_outside inodes/d";
+        sh_exec(setup);
+        let simulated = RemoveOptions::new()
+            .dry_run(true)
+            .execute("inodes")
+            .unwrap();
+        let real = RemoveOptions::new().execute("inodes").unwrap();
+        for stats in [simulated, real] {
+            assert_eq!(stats.files_removed, 4);
+            assert_eq!(
+                stats.inodes_freed,
+                2 + 2,
+                "a and b/c, plus the two directories"
+            );
+        }
+        sh_exec("rm inodes_outside");
+        sh_exec(setup);
+        assert_eq!(remove_fast("inodes").unwrap().inodes_freed, 4);
+        sh_exec("rm inodes_outside");
+    }
+
+    #[test]
+    fn exclusive_bytes_freed_test() {
+        initialize();
+        let setup = "mkdir -p exclusive; printf 1234 > exclusive/a; printf 12345678 > exclusive/b; ln exclusive/b exclusive/c; printf 12 > exclusive_outside; ln exclusive_outside exclusive/d";
+        sh_exec(setup);
+        let simulated = RemoveOptions::new()
+            .dry_run(true)
+            .execute("exclusive")
+            .unwrap();
+        let real = RemoveOptions::new().execute("exclusive").unwrap();
+        sh_exec(setup);
+        let fast = remove_fast("exclusive").unwrap();
+        for stats in [simulated, real, fast] {
+            assert_eq!(stats.bytes_freed, 4 + 8 + 8 + 2);
+            assert_eq!(
+                stats.exclusive_bytes_freed,
+                4 + 8,
+                "d still has a link outside"
+            );
+        }
+        sh_exec("rm exclusive_outside");
+    }
+
+    #[test]
+    #[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+    fn measure_exclusive_bytes_test() {
+        initialize();
+        sh_exec("mkdir -p measured; head -c 10000 /dev/zero > measured/data; truncate -s 1M measured/sparse");
+        let mapped = crate::extents::exclusive_bytes(Path::new("measured/sparse"));
+        let measuring = RemoveOptions::new().measure_exclusive_bytes(true);
+        let stats = measuring.execute("measured").unwrap();
+        assert_eq!(stats.bytes_freed, 10000 + (1 << 20));
+        if mapped.is_err() {
+            // no extents on this filesystem, sizes are all there is
+            assert_eq!(stats.exclusive_bytes_freed, stats.bytes_freed);
+            return;
+        }
+        assert_eq!(mapped.unwrap(), 0, "a hole takes no space");
+        assert!(
+            (10000..20000).contains(&stats.exclusive_bytes_freed),
+            "{:?}",
+            stats
+        );
+
+        // data shared with a copy outside of the tree isn't released
+        sh_exec("mkdir -p measured; head -c 100000 /dev/urandom > measured/data");
+        if Command::new("cp")
+            .args(["--reflink=always", "measured/data", "measured_copy"])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+        {
+            sh_exec("sync");
+            let stats = measuring.execute("measured").unwrap();
+            assert_eq!(stats.exclusive_bytes_freed, 0, "{:?}", stats);
+            sh_exec("rm measured_copy");
+        } else {
+            sh_exec("rm -r measured");
+        }
+    }
+
+    #[test]
+    fn remove_fast_test() {
+        initialize();
+        sh_exec("mkdir -p fast/a/b/c fast_outside; printf 12345 > fast/a/b/c/f; touch fast/a/g fast_outside/h");
+        sh_exec("ln -s ../../fast_outside fast/a/link; chmod 500 fast/a/b/c; chmod 000 fast/a/b");
+        let stats = remove_fast("fast").unwrap();
+        assert_eq!(stats.files_removed, 2);
+        assert_eq!(stats.symlinks_removed, 1);
+        assert_eq!(stats.dirs_removed, 4);
+        assert_eq!(stats.bytes_freed, 5 + "../../fast_outside".len() as u64);
+        sh_exec("! test -e fast && test -f fast_outside/h");
+        sh_exec("touch fast_file");
+        assert_eq!(remove_fast("fast_file").unwrap().files_removed, 1);
+        assert!(matches!(remove_fast("fast_file"), Err(Error::NotFound)));
+        sh_exec("rm -rf fast_outside");
+    }
+
+    #[test]
+    fn fast_error_context_test() {
+        initialize();
+        sh_exec("mkdir -p fastctx/a/b; touch fastctx/a/b/f");
+        if !Command::new("chattr")
+            .args(["+i", "fastctx/a/b/f"])
+            .status()
+            .is_ok_and(|status| status.success())
+        {
+            sh_exec("rm -r fastctx");
+            return; // needs the immutable attribute, e.g. root on ext4
+        }
+        match remove_fast("fastctx") {
+            Err(Error::IoFailed {
+                operation: Operation::RemoveFile,
+                path,
+                ..
+            }) => assert_eq!(path, Path::new("fastctx/a/b/f")),
+            other => panic!("expected the unlink to fail, got {:?}", other),
+        }
+        let dir = std::fs::File::open("fastctx/a").unwrap();
+        match remove_relative(&dir, "b".as_ref(), &RemoveOptions::default()) {
+            // relative to the directory handle
+            Err(Error::IoFailed {
+                operation: Operation::RemoveFile,
+                path,
+                ..
+            }) => assert_eq!(path, Path::new("b/f")),
+            other => panic!("expected the unlink to fail, got {:?}", other),
+        }
+        sh_exec("chattr -i fastctx/a/b/f");
+        assert_eq!(remove_fast("fastctx").unwrap().entries_removed(), 4);
+    }
+
+    #[test]
+    fn remove_parallel_test() {
+        initialize();
+        sh_exec("mkdir -p parallel/wide parallel/deep/a/b/c; chmod 500 parallel/deep/a");
+        sh_exec(
+            "for i in $(seq 100); do mkdir parallel/wide/$i; printf x > parallel/wide/$i/f; done",
+        );
+        sh_exec("ln parallel/wide/1/f parallel/deep/a/b/c/link");
+        for threshold in [0, 10, DEFAULT_FANOUT_THRESHOLD, 1000] {
+            sh_exec("cp -a parallel parallel_copy");
+            let opts = RemoveOptions::new().parallel_fanout_threshold(threshold);
+            let stats = remove_parallel("parallel_copy", &opts).unwrap();
+            assert_eq!(stats.files_removed, 101);
+            assert_eq!(stats.dirs_removed, 106);
+            assert_eq!(stats.inodes_freed, 206);
+            assert_eq!(stats.max_dir_fanout, 100);
+            sh_exec("! test -e parallel_copy");
+        }
+        let opts = RemoveOptions::new()
+            .parallel_fanout_threshold(0)
+            .dry_run(true);
+        let stats = remove_parallel("parallel", &opts).unwrap();
+        assert_eq!(stats.inodes_freed, 206);
+        remove_parallel("parallel", &RemoveOptions::default()).unwrap();
+        sh_exec("! test -e parallel");
+    }
+
+    #[test]
+    fn remove_except_test() {
+        initialize();
+        sh_exec("mkdir -p except/config/deep except/cache/x except/link_target; touch except/a except/config/keep.toml");
+        sh_exec(
+            "touch except/config/drop except/config/deep/d except/cache/x/f except/link_target/t",
+        );
+        sh_exec("ln -s link_target except/link; mkdir except/keptdir; touch except/keptdir/inner");
+        let keep = [
+            Path::new("config/keep.toml"),
+            Path::new("./keptdir"),
+            Path::new("link/t"),
+            Path::new("missing/file"),
+        ];
+        let stats = remove_except("except", &keep, &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.symlinks_removed, 1);
+        sh_exec("test -f except/config/keep.toml && test -f except/keptdir/inner");
+        sh_exec("! test -e except/config/drop && ! test -e except/config/deep && ! test -e except/cache");
+        sh_exec("! test -e except/a && ! test -e except/link && ! test -e except/link_target");
+
+        for bad in ["../except", "/tmp", "config/../../x", "."] {
+            let result = remove_except("except", &[Path::new(bad)], &RemoveOptions::default());
+            assert!(
+                matches!(result, Err(Error::InvalidTarget(_))),
+                "{} must be rejected",
+                bad
+            );
+        }
+        sh_exec("test -f except/config/keep.toml");
+        sh_exec("rm -rf except");
+    }
+
+    #[test]
+    fn stack_params_test() {
+        initialize();
+        let deep = "d/".repeat(1000);
+        std::fs::create_dir_all(Path::new("stackparams").join(deep)).unwrap();
+        let opts = RemoveOptions::new().stack_params(8 * 1024, 32 * 1024);
+        let stats = std::thread::Builder::new()
+            .stack_size(128 * 1024)
+            .spawn(move || opts.execute("stackparams"))
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(stats.dirs_removed, 1001);
+    }
+
+    #[test]
+    fn try_remove_test() {
+        initialize();
+        sh_exec("mkdir -p tryremove/sub");
+        assert!(try_remove("tryremove").unwrap());
+        assert!(!try_remove("tryremove").unwrap());
+        assert!(matches!(
+            try_remove("tryremove/.."),
+            Err(Error::InvalidTarget(_))
+        ));
+    }
+
+    #[test]
+    fn reset_test() {
+        initialize();
+        sh_exec("mkdir -p resetdir/sub; touch resetdir/sub/f resetdir/g; chmod 750 resetdir");
+        reset("resetdir").unwrap();
+        sh_exec("test -d resetdir && test -z \"$(ls -A resetdir)\"");
+        assert_eq!(sh_output("ls -ld resetdir | cut -c1-10"), "drwxr-x---\n");
+        reset("resetmissing/nested").unwrap();
+        sh_exec("test -d resetmissing/nested");
+        sh_exec("touch resetfile");
+        assert!(matches!(reset("resetfile"), Err(Error::InvalidTarget(_))));
+        sh_exec("test -f resetfile");
+        sh_exec("rm -rf resetdir resetmissing resetfile");
+    }
+
+    #[test]
+    #[cfg(feature = "ignore")]
+    fn honor_ignore_file_test() {
+        initialize();
+        sh_exec("mkdir -p rmignore/config rmignore/sub/deep rmignore/empty; cd rmignore; printf '*.keep\\nconfig/\\n' > .rmignore");
+        sh_exec("cd rmignore; touch a.keep b.tmp config/x sub/c.keep sub/local.txt sub/other sub/deep/z.keep");
+        sh_exec("printf '!*.keep\\nlocal.txt\\n' > rmignore/sub/.rmignore");
+        let opts = RemoveOptions::new().honor_ignore_file(".rmignore");
+        let stats = opts.execute("rmignore").unwrap();
+        assert_eq!(stats.files_removed, 4);
+        assert_eq!(stats.dirs_removed, 2);
+        let remaining = sh_output("cd rmignore && find . | sort | tr '\\n' ' '");
+        assert_eq!(
+            remaining,
+            ". ./.rmignore ./a.keep ./config ./config/x ./sub ./sub/.rmignore ./sub/local.txt "
+        );
+        sh_exec("rm -rf rmignore");
+    }
+
+    #[test]
+    fn remove_manifest_test() {
+        initialize();
+        sh_exec("mkdir -p manifest/out/sub manifest/keep manifest_outside; touch manifest/out/sub/f manifest/keep/g");
+        sh_exec("touch manifest_outside/h; ln -s ../manifest_outside manifest/escape");
+        let root = Path::new("manifest");
+        let entries = [
+            Path::new("out"),
+            Path::new("./keep/g"),
+            Path::new("missing"),
+        ];
+        let stats = remove_manifest(root, &entries).unwrap();
+        assert_eq!(stats.files_removed, 2);
+        sh_exec("! test -e manifest/out && ! test -e manifest/keep/g && test -d manifest/keep");
+
+        for bad in [
+            "../manifest_outside",
+            "/tmp",
+            "escape/h",
+            ".",
+            "keep/../../x",
+        ] {
+            let result = remove_manifest(root, &[Path::new("keep"), Path::new(bad)]);
+            assert!(
+                matches!(result, Err(Error::InvalidTarget(_))),
+                "{} must be rejected",
+                bad
+            );
+        }
+        sh_exec("test -d manifest/keep && test -f manifest_outside/h");
+        sh_exec("rm -rf manifest manifest_outside");
+    }
+
+    #[test]
+    fn remove_preserving_test() {
+        initialize();
+        sh_exec("mkdir -p extract/logs/old extract/junk; echo kept > extract/logs/old/a; echo gone > extract/junk/b");
+        sh_exec("echo config > extract/config; ln -s config extract/logs/link; chmod 500 extract/logs/old");
+        let bad_pairs = [
+            ("../extract", "extracted_bad"),
+            ("missing", "extracted_bad"),
+            ("logs/old", "extract/junk/c"),
+            ("config", "extract"),
+        ];
+        for (source, dest) in bad_pairs.iter() {
+            let pairs = [
+                (Path::new("config"), Path::new("extracted_ok")),
+                (Path::new(source), Path::new(dest)),
+            ];
+            let result = remove_preserving("extract", &pairs, &RemoveOptions::new());
+            assert!(
+                matches!(result, Err(Error::InvalidTarget(_))),
+                "{} -> {} must be rejected",
+                source,
+                dest
+            );
+        }
+        let overlapping = [
+            (Path::new("logs"), Path::new("a")),
+            (Path::new("./logs/old"), Path::new("b")),
+        ];
+        let result = remove_preserving("extract", &overlapping, &RemoveOptions::new());
+        assert!(matches!(result, Err(Error::InvalidTarget(_))));
+        sh_exec("test -f extract/config && ! test -e extracted_ok");
+
+        let other_fs = Path::new("/dev/shm");
+        let device = |path: &Path| std::os::unix::fs::MetadataExt::dev(&path.metadata().unwrap());
+        // a copy rather than a rename, when there's a directory on another filesystem
+        let copied = other_fs.is_dir() && device(other_fs) != device(Path::new("."));
+        let logs_dest = if copied {
+            other_fs.join(format!("rm_rf_extracted_{}", std::process::id()))
+        } else {
+            PathBuf::from("extracted_logs")
+        };
+        let pairs = [
+            (Path::new("config"), Path::new("extracted_config")),
+            (Path::new("logs"), logs_dest.as_path()),
+        ];
+        let stats = remove_preserving("extract", &pairs, &RemoveOptions::new()).unwrap();
+        // the originals of what was copied go with the rest
+        assert_eq!(stats.files_removed, if copied { 2 } else { 1 });
+        sh_exec(&format!(
+            "! test -e extract && test $(cat extracted_config) = config && test $(cat {0}/old/a) = kept && test $(readlink {0}/link) = config && test $(stat -c %a {0}/old) = 500",
+            logs_dest.display()
+        ));
+        sh_exec(&format!(
+            "chmod 700 {0}/old; rm -r {0} extracted_config",
+            logs_dest.display()
+        ));
+    }
+
+    #[test]
+    fn behavior_test() {
+        initialize();
+        test_eq_behavior("");
+        test_eq_behavior("touch target");
+        test_eq_behavior("touch target; chmod 000 target");
+        test_eq_behavior("touch target; chmod 777 target");
+
+        test_eq_behavior("mkdir target");
+        test_eq_behavior("mkdir target; ln -s unexistent target/link");
+        test_eq_behavior("mkdir target; chmod 000 target");
+        test_eq_behavior("mkdir target; chmod 777 target");
+
+        test_fixed_behavior("mkdir -p target/subdir; chmod 000 target");
+        test_eq_behavior("mkdir -p target/subdir; chmod 777 target");
+        test_eq_behavior("mkdir -p target/subdir; chmod 444 target");
+        test_fixed_behavior("mkdir -p target/subdir; chmod 222 target");
+        test_fixed_behavior("mkdir -p target/subdir; chmod 111 target");
+
+        test_eq_behavior("ln -s unexistent target");
+        test_eq_behavior("ln -s /abc/def target");
+        test_eq_behavior("ln -s / target");
+    }
+
+    fn test_eq_behavior(up: &str) {
+        test_eq_behavior_fn(
+            &|| {
+                assert!(sh_exec_status(up).success());
+            },
+            up,
+        );
+    }
+
+    /// For a directory of the caller that can't be emptied as it is: `rm -rf` fails unless
+    /// run as root, while `remove` fixes the permissions, see `RemoveOptions::fix_permissions`.
+    fn test_fixed_behavior(up: &str) {
+        clean();
+        assert!(sh_exec_status(up).success());
+        let rm_success = rm_rf_success();
+        clean();
+        assert!(sh_exec_status(up).success());
+        let rust_success = rust_remove_success();
+        clean();
+        // SAFETY: `geteuid` has no preconditions
+        let root = unsafe { libc::geteuid() } == 0;
+        assert_eq!(rm_success, root, "`rm -rf` for test: {}", up);
+        assert!(rust_success, "`force_remove` failed for test: {}", up);
+    }
+
+    fn sh_exec(script: &str) {
+        if sh_exec_status(script).success().not() {
+            panic!("Non-zero exit status of `{}`", script)
+        }
+    }
+
+    fn sh_output(script: &str) -> String {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .expect("failed to execute `sh`");
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    fn sh_exec_status(script: &str) -> ExitStatus {
+        Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .status()
+            .expect("failed to execute `sh`")
+    }
+
+    fn test_eq_behavior_fn<F>(up: &F, test_name: &str)
+    where
+        F: Fn(),
+    {
+        clean();
+        up();
+        let rm_success = rm_rf_success();
+        clean();
+        up();
+        let rust_success = rust_remove_success();
+        clean();
+        assert_eq!(
+            rm_success, rust_success,
+            "`rm -rf` and `force_remove` behaved differently for test: {}",
+            test_name
+        );
+    }
+
+    fn clean() {
+        Command::new("sh")
+            .arg("-c")
+            .arg("chmod 777 target; rm -rf target")
+            .output()
+            .unwrap();
+    }
+
+    fn rm_rf_success() -> bool {
+        Command::new("rm")
+            .arg("-rf")
+            .arg("target")
+            .output()
+            .unwrap()
+            .status
+            .success()
     }
 
     fn rust_remove_success() -> bool {
         ensure_removed("target").is_ok()
     }
 }
+
+#[cfg(test)]
+mod portable_tests {
+    use crate::{
+        ensure_removed, remove, remove_all, remove_collecting, remove_components,
+        remove_with_percentage, remove_with_stats, would_require_force, Error, Operation,
+        PartialRemoval, RemoveErrors, RemoveOptions, RemoveStats, RemovedKind, RetryPolicy,
+        SafetyContext, SizeHistogram, SyscallCounts,
+    };
+    use std::collections::HashSet;
+    use std::ffi::{OsStr, OsString};
+    use std::fmt::Debug;
+    use std::fs;
+    use std::hash::Hash;
+    use std::io::ErrorKind;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// An absolute directory, as the Unix tests change the current directory.
+    fn test_root(name: &str) -> PathBuf {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("target")
+            .join("portable_testdir")
+            .join(name);
+        let _ = crate::ensure_removed(&root);
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn thread_safety_test() {
+        assert_send_sync::<RemoveOptions>();
+        assert_send_sync::<RemoveStats>();
+        assert_send_sync::<Error>();
+        assert_send_sync::<PartialRemoval>();
+    }
+
+    fn assert_value_type<T: Debug + Copy + Default + Eq + Hash>() {}
+
+    #[test]
+    fn remove_errors_test() {
+        let root = test_root("remove_errors");
+        let file = root.join("file");
+        fs::write(&file, b"").unwrap();
+        let (_, errors) = crate::remove_all_best_effort([file.join("a"), file.join("b")]);
+        assert_eq!(errors.len(), 2);
+        let display = errors.to_string();
+        assert_eq!(display.lines().count(), 2);
+        assert!(display.starts_with(&format!("{}: ", file.join("a").display())));
+        assert!(std::error::Error::source(&errors).is_some());
+
+        let many: Vec<_> = (0..12)
+            .map(|i| (PathBuf::from(i.to_string()), Error::NotEmpty))
+            .collect();
+        let display = RemoveErrors::from(many).to_string();
+        assert_eq!(display.lines().count(), RemoveErrors::DISPLAYED + 1);
+        assert!(display.ends_with("\n... and 2 more"));
+        assert!(RemoveErrors::default().into_result().is_ok());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn remove_with_disk_check_test() {
+        let root = test_root("disk_check");
+        let file = root.join("big");
+        fs::write(&file, vec![1; 1 << 20]).unwrap();
+        let (stats, _reclaimed) = crate::remove_with_disk_check(&file).unwrap();
+        assert_eq!(stats.bytes_freed, 1 << 20);
+        assert!(!file.exists());
+        assert!(matches!(
+            crate::remove_with_disk_check(&file),
+            Err(Error::NotFound)
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn protect_system_paths_test() {
+        let root = test_root("protect");
+        for dir in ["home/user/docs", "work/project", "chroot/etc", "other"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let context = SafetyContext::new()
+            .home(Some(root.join("home/user")))
+            .cwd(Some(root.join("work/project")))
+            .root_marker(root.join("chroot"));
+        let opts = RemoveOptions::new().safety_context(context);
+        for protected in ["home/user", "home", "work/project", "work", "chroot", "."] {
+            match opts.execute(root.join(protected)) {
+                Err(Error::InvalidTarget(reason)) => assert!(reason.contains(" it "), "{}", reason),
+                other => panic!("{} must be protected, got {:?}", protected, other),
+            }
+        }
+        assert!(matches!(
+            opts.execute(Path::new("/")),
+            Err(Error::InvalidTarget(_))
+        ));
+        for allowed in ["home/user/docs", "chroot/etc", "other"] {
+            opts.execute(root.join(allowed)).unwrap();
+        }
+        let batch = opts.execute_all([root.join("home"), root.join("work")]);
+        assert!(matches!(batch, Err(Error::InvalidTarget(_))));
+        assert!(root.join("home/user").is_dir() && root.join("work/project").is_dir());
+        RemoveOptions::new().execute(&root).unwrap();
+    }
+
+    #[test]
+    fn remove_reporting_metadata_test() {
+        let root = test_root("reporting_metadata");
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/sub/f"), b"x").unwrap();
+        fs::write(root.join("file"), b"12345").unwrap();
+        let (kind, metadata, stats) = crate::remove_reporting_metadata(root.join("dir")).unwrap();
+        assert_eq!(kind, RemovedKind::Dir);
+        assert!(metadata.is_dir());
+        assert_eq!(stats.entries_removed(), 3);
+        let (kind, metadata, _) = crate::remove_reporting_metadata(root.join("file")).unwrap();
+        assert_eq!(kind, RemovedKind::File);
+        assert_eq!(metadata.len(), 5);
+        assert!(matches!(
+            crate::remove_reporting_metadata(root.join("file")),
+            Err(Error::NotFound)
+        ));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn remove_stream_test() {
+        use crate::RemoveEvent;
+        use futures_core::Stream;
+        use std::future::Future;
+        use std::pin::Pin;
+        use std::sync::Arc;
+        use std::task::{Context, Poll, Wake};
+
+        struct Unpark(std::thread::Thread);
+        impl Wake for Unpark {
+            fn wake(self: Arc<Self>) {
+                self.0.unpark()
+            }
+        }
+        fn block_on<F: Future>(future: F) -> F::Output {
+            let waker = Arc::new(Unpark(std::thread::current())).into();
+            let mut cx = Context::from_waker(&waker);
+            let mut future = Box::pin(future);
+            loop {
+                match future.as_mut().poll(&mut cx) {
+                    Poll::Ready(output) => return output,
+                    Poll::Pending => std::thread::park(),
+                }
+            }
+        }
+        // through the trait, as stream adapters do
+        fn next<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+            block_on(std::future::poll_fn(|cx| {
+                Pin::new(&mut *stream).poll_next(cx)
+            }))
+        }
+
+        let root = test_root("remove_stream");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("a/b/f"), "x").unwrap();
+        fs::write(root.join("g"), "y").unwrap();
+        let mut stream = crate::remove_stream(root.join("a"));
+        let mut events = Vec::new();
+        while let Some(event) = next(&mut stream) {
+            events.push(event.unwrap());
+        }
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[0], RemoveEvent::FileRemoved(root.join("a/b/f")));
+        assert_eq!(events[1], RemoveEvent::DirRemoved(root.join("a/b")));
+        assert_eq!(events[2], RemoveEvent::DirRemoved(root.join("a")));
+        match &events[3] {
+            RemoveEvent::Finished(stats) => assert_eq!(stats.entries_removed(), 3),
+            other => panic!("expected the stats last, got {:?}", other),
+        }
+        assert!(!root.join("a").exists());
+
+        let mut stream = crate::remove_stream(root.join("missing"));
+        assert!(matches!(
+            block_on(stream.next_event()),
+            Some(Err(Error::NotFound))
+        ));
+        assert!(block_on(stream.next_event()).is_none());
+
+        // an unread stream holds the removal back
+        let files = crate::stream::EVENT_CAPACITY + 100;
+        fs::create_dir_all(root.join("wide")).unwrap();
+        for i in 0..files {
+            fs::write(root.join("wide").join(i.to_string()), "").unwrap();
+        }
+        let mut stream = crate::remove_stream(root.join("wide"));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        let left = fs::read_dir(root.join("wide")).unwrap().count();
+        // the entry whose event didn't fit is removed before waiting
+        assert!(
+            left >= files - crate::stream::EVENT_CAPACITY - 1,
+            "{} left",
+            left
+        );
+        let mut removed = 0;
+        while let Some(event) = next(&mut stream) {
+            if let RemoveEvent::FileRemoved(_) = event.unwrap() {
+                removed += 1;
+            }
+        }
+        assert_eq!(removed, files);
+        assert!(!root.join("wide").exists());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn lazy_permission_fixing_test() {
+        let root = test_root("lazy_permissions");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("writable"), b"x").unwrap();
+        fs::write(root.join("readonly"), b"x").unwrap();
+        let mut permissions = fs::metadata(root.join("readonly")).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(root.join("readonly"), permissions).unwrap();
+        let opts = RemoveOptions::new().count_syscalls(true);
+        let stats = opts.execute(&root).unwrap();
+        assert_eq!(stats.files_removed, 2);
+        // a read-only file in a writable directory can be unlinked as is on Unix,
+        // only Windows refuses it, and then gets it fixed
+        let expected_chmods = if cfg!(windows) { 1 } else { 0 };
+        assert_eq!(stats.syscalls.chmod, expected_chmods);
+    }
+
+    #[test]
+    fn io_accessors_test() {
+        let root = test_root("io_accessors");
+        let err = RemoveOptions::new()
+            .raw_not_found(true)
+            .execute(root.join("missing"))
+            .unwrap_err();
+        assert_eq!(err.as_io().map(|err| err.kind()), Some(ErrorKind::NotFound));
+        assert_eq!(err.into_io().unwrap().kind(), ErrorKind::NotFound);
+        let err = Error::IoFailed {
+            operation: Operation::RemoveFile,
+            path: root.join("f"),
+            source: std::io::Error::from(ErrorKind::PermissionDenied),
+        };
+        assert_eq!(err.as_io().unwrap().kind(), ErrorKind::PermissionDenied);
+        assert!(Error::NotFound.as_io().is_none());
+        assert!(matches!(Error::NotFound.into_io(), Err(Error::NotFound)));
+        assert!(remove(root.join("..")).unwrap_err().as_io().is_none());
+    }
+
+    #[test]
+    fn remove_components_test() {
+        let root = test_root("components");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        fs::write(root.join("a").join("b").join("f"), b"x").unwrap();
+        let opts = RemoveOptions::new();
+        let name = |name: &'static str| OsStr::new(name);
+        for bad in ["", ".", "..", "b/f", "f/"] {
+            let result = remove_components(&root, &[name("a"), name(bad)], &opts);
+            assert!(
+                matches!(result, Err(Error::InvalidTarget(_))),
+                "{:?} must be rejected",
+                bad
+            );
+        }
+        let stats = remove_components(&root, &[name("a"), name("b")], &opts).unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (1, 1));
+        assert!(root.join("a").is_dir());
+        assert!(matches!(
+            remove_components(&root, &[name("a"), name("b")], &opts),
+            Err(Error::NotFound)
+        ));
+        remove_components(&root, &[], &opts).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn order_by_test() {
+        let root = test_root("order_by");
+        // the larger the file, the later its name and the older it is
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"];
+        let create = || {
+            fs::create_dir_all(&root).unwrap();
+            for (i, name) in names.iter().enumerate() {
+                fs::write(root.join(name), vec![0; (i + 1) * 1000]).unwrap();
+                let modified = UNIX_EPOCH + Duration::from_secs(100_000 - i as u64 * 1000);
+                let file = fs::File::options()
+                    .write(true)
+                    .open(root.join(name))
+                    .unwrap();
+                file.set_modified(modified).unwrap();
+            }
+        };
+        let freed = |opts: RemoveOptions| {
+            create();
+            let partial = remove_with_stats(&root, &opts.max_entries(2)).unwrap_err();
+            assert!(matches!(partial.error, Error::BudgetExceeded { .. }));
+            partial.stats.bytes_freed
+        };
+        let largest = freed(RemoveOptions::new().largest_first());
+        assert_eq!(largest, 10_000 + 9_000 + 8_000);
+        assert!(freed(RemoveOptions::new()) <= largest);
+        assert_eq!(
+            freed(RemoveOptions::new().sorted(true)),
+            1_000 + 2_000 + 3_000
+        );
+        assert_eq!(freed(RemoveOptions::new().oldest_first()), largest);
+        let by_name_desc = RemoveOptions::new().order_by(|a, _, b, _| b.cmp(a));
+        assert_eq!(freed(by_name_desc), largest);
+
+        let panicking = RemoveOptions::new().order_by(|_, _, _, _| panic!("no order"));
+        match panicking.execute(&root) {
+            Err(Error::CallbackPanicked(Some(message))) => assert_eq!(message, "no order"),
+            other => panic!("expected the comparator panic, got {:?}", other),
+        }
+        RemoveOptions::new().largest_first().execute(&root).unwrap();
+        assert!(!root.exists());
+    }
+
+    #[test]
+    fn remove_with_percentage_test() {
+        let root = test_root("percentage");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        fs::write(root.join("a").join("f"), b"x").unwrap();
+        fs::write(root.join("g"), b"x").unwrap();
+        let mut reported = Vec::new();
+        let stats =
+            remove_with_percentage(&root, |done, total| reported.push((done, total))).unwrap();
+        assert_eq!(stats.entries_removed(), 5);
+        assert_eq!(reported, (1..=5).map(|done| (done, 5)).collect::<Vec<_>>());
+        assert!(!root.exists());
+        let result = remove_with_percentage(&root, |_, _| panic!("nothing to report"));
+        assert!(matches!(result, Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn hard_links_test() {
+        let root = test_root("hard_links");
+        let outside = test_root("hard_links_outside");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("a"), b"12345").unwrap();
+        fs::hard_link(root.join("a"), root.join("sub/b")).unwrap();
+        fs::hard_link(root.join("a"), outside.join("c")).unwrap();
+        fs::write(root.join("single"), b"x").unwrap();
+        let stats = remove_with_stats(&root, &RemoveOptions::default()).unwrap();
+        assert_eq!(stats.files_removed, 3);
+        // the directories and `single`: the other file keeps a link outside
+        assert_eq!(stats.inodes_freed, 3);
+        assert_eq!(stats.exclusive_bytes_freed, 1);
+        remove(&outside).unwrap();
+    }
+
+    #[test]
+    fn size_fn_test() {
+        let root = test_root("size_fn");
+        let build = |root: &Path| {
+            fs::create_dir_all(root.join("sub")).unwrap();
+            for (name, size) in [("empty", 0), ("one", 1), ("block", 4096), ("more", 5000)] {
+                fs::write(root.join("sub").join(name), vec![0; size]).unwrap();
+            }
+        };
+        build(&root);
+        let blocks = RemoveOptions::new().size_fn(|_, metadata| {
+            if metadata.is_dir() {
+                4096
+            } else {
+                metadata.len().div_ceil(4096) * 4096
+            }
+        });
+        let stats = blocks.execute(&root).unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (4, 2));
+        // no block for the empty file, one each for the others and the directories
+        assert_eq!(stats.bytes_freed, (1 + 1 + 2 + 2) * 4096);
+
+        build(&root);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let one_each = RemoveOptions::new().size_fn(move |path, metadata| {
+            if metadata.is_file() {
+                // still there to be measured
+                assert!(path.exists());
+            }
+            recorded.lock().unwrap().push(path.to_path_buf());
+            1
+        });
+        assert_eq!(one_each.execute(&root).unwrap().bytes_freed, 6);
+        assert!(seen.lock().unwrap().contains(&root));
+
+        build(&root);
+        let panicking = RemoveOptions::new().size_fn(|_, _| panic!("no size"));
+        match panicking.execute(&root) {
+            Err(Error::CallbackPanicked(Some(message))) => assert_eq!(message, "no size"),
+            other => panic!("expected the size function panic, got {:?}", other),
+        }
+        remove(&root).unwrap();
+    }
+
+    #[test]
+    fn size_histogram_test() {
+        let root = test_root("histogram");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let sizes = [0, 1, 3, 1000, 1024, 1500, 5000];
+        for (i, size) in sizes.iter().enumerate() {
+            fs::write(root.join("sub").join(i.to_string()), vec![0; *size]).unwrap();
+        }
+        let histogram = Arc::new(Mutex::new(SizeHistogram::new()));
+        let opts = RemoveOptions::new().size_histogram(histogram.clone());
+        let stats = opts.execute(&root).unwrap();
+        let histogram = histogram.lock().unwrap();
+        assert_eq!(histogram.files(), stats.files_removed);
+        assert_eq!(histogram.bytes(), stats.bytes_freed);
+        let mut expected = [0; SizeHistogram::BUCKETS];
+        // empty, 1 B, 2-3 B, 512-1023 B, 1-2 KiB twice, 4-8 KiB
+        for (bucket, count) in [(0, 1), (1, 1), (2, 1), (10, 1), (11, 2), (13, 1)] {
+            expected[bucket] = count;
+        }
+        assert_eq!(histogram.counts(), &expected);
+
+        let rendered = histogram.to_string();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(
+            lines.len(),
+            14,
+            "from the empty files to 4-8 KiB:\n{}",
+            rendered
+        );
+        assert_eq!(
+            lines[0],
+            format!("{:>17} | {:<40} 1", "0 B", "#".repeat(20))
+        );
+        assert_eq!(
+            lines[11],
+            format!("{:>17} | {:<40} 2", "[1 KiB, 2 KiB)", "#".repeat(40))
+        );
+        assert_eq!(
+            lines[12],
+            format!("{:>17} | {:<40} 0", "[2 KiB, 4 KiB)", "")
+        );
+        // 1023 B is the largest size of its bucket, 1 KiB the smallest of the next one
+        assert_eq!(SizeHistogram::bucket(1023), 10);
+        assert_eq!(SizeHistogram::bucket(1024), 11);
+        assert!(lines[10].starts_with(&format!("{:>17} |", "[512 B, 1 KiB)")));
+        assert_eq!(SizeHistogram::new().to_string(), "no files");
+        assert_eq!(SizeHistogram::bucket(u64::MAX), SizeHistogram::BUCKETS - 1);
+    }
+
+    #[test]
+    fn interior_nul_test() {
+        let root = test_root("nul");
+        fs::create_dir_all(&root).unwrap();
+        let names = [
+            OsString::from("a\0b"),
+            OsString::from("\0"),
+            OsString::from("a\0/b"),
+        ];
+        for name in names {
+            let path = root.join(&name);
+            let results = [
+                remove(&path),
+                ensure_removed(&path),
+                RemoveOptions::new()
+                    .missing_ok(true)
+                    .execute(&path)
+                    .map(|_| ()),
+                remove_all([&path]).map(|_| ()),
+            ];
+            for result in results {
+                match result {
+                    Err(Error::InvalidTarget(message)) => {
+                        assert!(message.contains("NUL"), "{:?}: {}", name, message)
+                    }
+                    other => panic!("{:?} must be rejected, got {:?}", name, other),
+                }
+            }
+        }
+        remove(&root).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "regex")]
+    fn remove_matching_regex_test() {
+        use crate::remove_matching_regex;
+        use regex::bytes::Regex;
+        let root = test_root("regex");
+        let dirs = [
+            "build/a/target",
+            "build/b/c/target/deep",
+            "build/target",
+            "src/target",
+            "sub",
+        ];
+        for dir in dirs {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        let files = [
+            "build/a/target/f",
+            "build/notes.log",
+            "x.log",
+            "sub/y.log",
+            "sub/y.log.gz",
+        ];
+        for file in files {
+            fs::write(root.join(file), b"x").unwrap();
+        }
+        let targets = Regex::new("^build/.*/target$").unwrap();
+        let stats = remove_matching_regex(&root, &targets).unwrap();
+        assert_eq!((stats.files_removed, stats.dirs_removed), (1, 3));
+        assert!(!root.join("build/a/target").exists());
+        assert!(!root.join("build/b/c/target").exists());
+        assert!(root.join("build/b/c").is_dir());
+        assert!(
+            root.join("build/target").is_dir(),
+            "no directory between build and target"
+        );
+        assert!(root.join("src/target").is_dir());
+
+        let logs = Regex::new(r"\.log$").unwrap();
+        let stats = remove_matching_regex(&root, &logs).unwrap();
+        assert_eq!(stats.files_removed, 3);
+        assert!(!root.join("sub/y.log").exists() && root.join("sub/y.log.gz").is_file());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::OsStrExt;
+            fs::write(
+                root.join("sub").join(OsStr::from_bytes(b"bad\xff.bin")),
+                b"x",
+            )
+            .unwrap();
+            let bytes = Regex::new(r"(?-u)\xff\.bin$").unwrap();
+            assert_eq!(
+                remove_matching_regex(&root, &bytes).unwrap().files_removed,
+                1
+            );
+        }
+
+        // segments are joined with `/` whatever the platform's separator
+        let nested = Regex::new("^sub/y\\.log\\.gz$").unwrap();
+        assert_eq!(
+            remove_matching_regex(&root, &nested).unwrap().files_removed,
+            1
+        );
+        let everything = Regex::new("").unwrap();
+        remove_matching_regex(&root, &everything).unwrap();
+        assert!(root.is_dir() && fs::read_dir(&root).unwrap().next().is_none());
+        remove(&root).unwrap();
+    }
+
+    #[test]
+    fn remove_collecting_test() {
+        let root = test_root("collecting");
+        fs::create_dir_all(root.join("a").join("b")).unwrap();
+        let files = [
+            root.join("a").join("f"),
+            root.join("a").join("b").join("f"),
+            root.join("g"),
+        ];
+        for file in &files {
+            fs::write(file, b"x").unwrap();
+        }
+        let mut permissions = fs::metadata(&files[1]).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(&files[1], permissions).unwrap();
+        let removed = remove_collecting(&root).unwrap();
+        assert!(!root.exists());
+        let mut expected = vec![root.clone(), root.join("a"), root.join("a").join("b")];
+        expected.extend(files);
+        let mut sorted = removed.clone();
+        sorted.sort();
+        expected.sort();
+        assert_eq!(sorted, expected);
+        for (i, path) in removed.iter().enumerate() {
+            assert!(
+                removed[i + 1..]
+                    .iter()
+                    .all(|later| !later.starts_with(path)),
+                "{:?} must come after its contents: {:?}",
+                path,
+                removed
+            );
+        }
+        assert!(matches!(remove_collecting(&root), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn would_require_force_test() {
+        let root = test_root("would_require_force");
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir").join("readonly"), b"x").unwrap();
+        assert!(!would_require_force(&root).unwrap());
+        let mut permissions = fs::metadata(root.join("dir").join("readonly"))
+            .unwrap()
+            .permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(root.join("dir").join("readonly"), permissions).unwrap();
+        // only Windows refuses to remove a read-only file, see lazy_permission_fixing_test
+        assert_eq!(would_require_force(&root).unwrap(), cfg!(windows));
+        assert!(root.join("dir").join("readonly").is_file());
+        RemoveOptions::new().execute(&root).unwrap();
+        assert!(matches!(would_require_force(&root), Err(Error::NotFound)));
+    }
+
+    #[test]
+    fn value_types_test() {
+        assert_value_type::<RemoveStats>();
+        assert_value_type::<SyscallCounts>();
+        let kinds = HashSet::from([RemovedKind::File, RemovedKind::Dir, RemovedKind::File]);
+        assert_eq!(kinds.len(), 2);
+        let counts = HashSet::from([Operation::Stat, Operation::Stat, Operation::ReadDir]);
+        assert_eq!(counts.len(), 2);
+        let policy = RetryPolicy::new(3, Duration::from_millis(1));
+        assert_eq!(policy, RetryPolicy::new(3, Duration::from_millis(1)));
+        let stats = RemoveStats {
+            files_removed: 1,
+            ..RemoveStats::default()
+        };
+        let copy = stats;
+        assert_eq!(copy, stats);
+        assert_ne!(stats, RemoveStats::default());
+    }
+
+    #[test]
+    fn dot_segments_test() {
+        let root = test_root("dot_segments");
+        fs::create_dir_all(root.join("foo")).unwrap();
+        assert!(matches!(remove(".."), Err(Error::InvalidTarget(_))));
+        assert!(matches!(remove("."), Err(Error::InvalidTarget(_))));
+        match remove("/") {
+            Err(Error::InvalidTarget(reason)) => assert!(reason.contains("filesystem root")),
+            other => panic!("expected / to be refused as a root, got {:?}", other),
+        }
+        assert!(matches!(
+            remove(root.join("foo").join("..")),
+            Err(Error::InvalidTarget(_))
+        ));
+        assert!(root.join("foo").is_dir());
+        assert!(remove(root.join("foo").join(".")).is_ok());
+        assert!(!root.join("foo").exists());
+
+        fs::create_dir_all(root.join("outer").join("inner")).unwrap();
+        let opts = RemoveOptions::new().allow_dot_dot(true);
+        assert!(opts
+            .execute(root.join("outer").join("inner").join(".."))
+            .is_ok());
+        assert!(!root.join("outer").exists());
+        assert!(root.is_dir());
+    }
+
+    /// `path` with a trailing separator added, the platform's own and `/` on Windows.
+    fn with_trailing_separators(path: &Path) -> Vec<PathBuf> {
+        let mut variants = vec![PathBuf::from(format!(
+            "{}{}",
+            path.display(),
+            std::path::MAIN_SEPARATOR
+        ))];
+        if cfg!(target_os = "windows") {
+            variants.push(PathBuf::from(format!("{}/", path.display())));
+        }
+        variants
+    }
+
+    #[test]
+    fn trailing_separator_test() {
+        let root = test_root("trailing_separator");
+        for target in with_trailing_separators(&root.join("dir")) {
+            fs::create_dir_all(root.join("dir").join("sub")).unwrap();
+            fs::write(root.join("dir").join("sub").join("f"), b"xy").unwrap();
+            let stats = RemoveOptions::default().execute(&target).unwrap();
+            assert_eq!(
+                (stats.dirs_removed, stats.files_removed, stats.bytes_freed),
+                (2, 1, 2)
+            );
+            assert!(!root.join("dir").exists());
+        }
+        for target in with_trailing_separators(&root.join("file")) {
+            fs::write(root.join("file"), b"xyz").unwrap();
+            let stats = RemoveOptions::default().execute(&target).unwrap();
+            assert_eq!((stats.files_removed, stats.bytes_freed), (1, 3));
+            assert!(!root.join("file").exists());
+            assert!(matches!(remove(&target), Err(Error::NotFound)));
+        }
+        fs::create_dir_all(root.join("foo")).unwrap();
+        for target in with_trailing_separators(&root.join("foo").join("..")) {
+            assert!(matches!(remove(&target), Err(Error::InvalidTarget(_))));
+        }
+        for target in with_trailing_separators(Path::new(".")) {
+            assert!(matches!(remove(&target), Err(Error::InvalidTarget(_))));
+        }
+        assert!(root.join("foo").is_dir());
+    }
+}
+
+#[cfg(test)]
+#[cfg(target_os = "windows")]
+mod windows_tests {
+    use crate::error::Error;
+    use crate::remove;
+    use crate::remover::Remover;
+    use crate::sys::MockSys;
+    use crate::Operation;
+    use crate::RemoveOptions;
+    use crate::RetryPolicy;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    fn test_root() -> PathBuf {
+        let root = Path::new("target").join("windows_testdir");
+        fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    fn powershell_available() -> bool {
+        Command::new("powershell.exe")
+            .args(["-NoProfile", "-Command", "exit 0"])
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(false)
+    }
+
+    fn set_readonly(path: &Path) {
+        let mut permissions = fs::metadata(path).unwrap().permissions();
+        permissions.set_readonly(true);
+        fs::set_permissions(path, permissions).unwrap();
+    }
+
+    fn junction(link: &Path, target: &Path) {
+        let status = Command::new("cmd")
+            .arg("/C")
+            .arg("mklink")
+            .arg("/J")
+            .arg(link)
+            .arg(target)
+            .output()
+            .expect("failed to execute `cmd`")
+            .status;
+        assert!(status.success(), "failed to create junction {:?}", link);
+    }
+
+    /// Forcefully cleans up leftovers of a scenario, regardless of what went wrong.
+    fn clean(path: &Path) {
+        Command::new("powershell.exe")
+            .arg("-NoProfile")
+            .arg("-Command")
+            .arg(format!(
+                "Remove-Item -Recurse -Force -LiteralPath '{}' -ErrorAction SilentlyContinue",
+                path.display()
+            ))
+            .output()
+            .unwrap();
+    }
+
+    fn powershell_remove_success(path: &Path) -> bool {
+        Command::new("powershell.exe")
+            .arg("-NoProfile")
+            .arg("-Command")
+            .arg(format!(
+                "try {{ Remove-Item -Recurse -Force -LiteralPath '{}' -ErrorAction Stop; exit 0 }} catch {{ exit 1 }}",
+                path.display()
+            ))
+            .status()
+            .expect("failed to execute `powershell.exe`")
+            .success()
+    }
+
+    fn test_eq_behavior(name: &str, up: &dyn Fn(&Path)) {
+        let root = test_root().join(name);
+        let target = root.join("target");
+        clean(&root);
+        fs::create_dir_all(&root).unwrap();
+        up(&target);
+        let powershell_success = powershell_remove_success(&target);
+        clean(&target);
+        up(&target);
+        let rust_success = remove(&target).is_ok();
+        clean(&root);
+        assert_eq!(
+            powershell_success, rust_success,
+            "`Remove-Item -Recurse -Force` and `remove` behaved differently for test: {}",
+            name
+        );
+    }
+
+    #[test]
+    fn failing_set_permissions_test() {
+        let target = test_root().join("failing_set_permissions");
+        fs::write(&target, b"x").unwrap();
+        let sys = MockSys::new(|op, _: &Path| match op {
+            Operation::SetPermissions => {
+                Some(std::io::Error::from(std::io::ErrorKind::PermissionDenied))
+            }
+            _ => None,
+        });
+        let opts = RemoveOptions::default();
+        let stats = Remover::with_sys(&opts, &sys).run(&target);
+        assert!(
+            stats.is_ok(),
+            "removal should not depend on set_permissions"
+        );
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn roots_test() {
+        for root in [
+            r"C:\",
+            "C:",
+            r"\\server\share",
+            r"\\server\share\",
+            r"\\?\C:\",
+            r"\\?\UNC\server\share",
+            r"\\.\C:\",
+        ] {
+            match remove(root) {
+                Err(Error::InvalidTarget(reason)) => {
+                    assert!(reason.contains("filesystem root"), "{}: {}", root, reason)
+                }
+                other => panic!("{} must be refused as a root, got {:?}", root, other),
+            }
+        }
+        let extended = Path::new(r"\\?\C:\dir\sub\");
+        assert_eq!(
+            crate::validate_target(extended, false).unwrap(),
+            Path::new(r"\\?\C:\dir\sub")
+        );
+
+        let dir = test_root().join("extended");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let target = format!(r"{}\", dir.canonicalize().unwrap().display());
+        assert!(target.starts_with(r"\\?\"));
+        remove(&target).unwrap();
+        assert!(!dir.exists() && test_root().is_dir());
+    }
+
+    #[test]
+    fn delete_pending_test() {
+        let dir = test_root().join("delete_pending");
+        fs::create_dir_all(&dir).unwrap();
+        drop(fs::File::create(dir.join("just_closed")).unwrap());
+        let policy = RetryPolicy::new(20, Duration::from_millis(10));
+        RemoveOptions::new().retry(policy).execute(&dir).unwrap();
+        assert!(!dir.exists());
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("pending"), b"x").unwrap();
+        let rmdirs = Mutex::new(0);
+        let sys = MockSys::new(|op, path: &Path| {
+            if op != Operation::RemoveDir || !path.ends_with("delete_pending") {
+                return None;
+            }
+            let mut rmdirs = rmdirs.lock().unwrap();
+            *rmdirs += 1;
+            // the first attempt is before emptying, the next two see a pending delete
+            match *rmdirs {
+                2 | 3 => Some(std::io::Error::from(std::io::ErrorKind::DirectoryNotEmpty)),
+                _ => None,
+            }
+        });
+        let opts = RemoveOptions::new().retry(policy);
+        Remover::with_sys(&opts, &sys).run(&dir).unwrap();
+        assert_eq!(*rmdirs.lock().unwrap(), 4);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn keep_permissions_test() {
+        let target = test_root().join("keep_permissions");
+        fs::write(&target, b"x").unwrap();
+        set_readonly(&target);
+        let sys = MockSys::new(|op, _: &Path| {
+            assert!(
+                op != Operation::SetPermissions,
+                "permissions must be left alone"
+            );
+            None
+        });
+        let opts = RemoveOptions::new().fix_permissions(false);
+        if Remover::with_sys(&opts, &sys).run(&target).is_err() {
+            assert!(fs::metadata(&target).unwrap().permissions().readonly());
+        }
+        clean(&target);
+    }
+
+    #[test]
+    fn record_permission_changes_test() {
+        let root = test_root().join("record_permission_changes");
+        clean(&root);
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("readonly"), b"x").unwrap();
+        fs::write(root.join("writable"), b"x").unwrap();
+        set_readonly(&root.join("readonly"));
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let opts = RemoveOptions::new().record_permission_changes(log.clone());
+        opts.execute(&root).unwrap();
+        assert_eq!(*log.lock().unwrap(), vec![root.join("readonly")]);
+    }
+
+    #[test]
+    fn powershell_behavior_test() {
+        if !powershell_available() {
+            return; // compares against PowerShell's Remove-Item
+        }
+        test_eq_behavior("missing", &|_| ());
+        test_eq_behavior("file", &|target| fs::write(target, b"x").unwrap());
+        test_eq_behavior("readonly_file", &|target| {
+            fs::write(target, b"x").unwrap();
+            set_readonly(target);
+        });
+        test_eq_behavior("empty_dir", &|target| fs::create_dir(target).unwrap());
+        test_eq_behavior("readonly_dir", &|target| {
+            fs::create_dir(target).unwrap();
+            set_readonly(target);
+        });
+        test_eq_behavior("nested", &|target| {
+            fs::create_dir_all(target.join("a").join("b")).unwrap();
+            fs::write(target.join("a").join("b").join("f"), b"x").unwrap();
+            fs::write(target.join("g"), b"x").unwrap();
+        });
+        test_eq_behavior("nested_readonly", &|target| {
+            let inner = target.join("a");
+            fs::create_dir_all(&inner).unwrap();
+            fs::write(inner.join("f"), b"x").unwrap();
+            set_readonly(&inner.join("f"));
+            set_readonly(&inner);
+        });
+        test_eq_behavior("junction", &|target| {
+            let real = target.with_file_name("junction_target");
+            fs::create_dir_all(&real).unwrap();
+            fs::write(real.join("f"), b"x").unwrap();
+            junction(target, &real);
+        });
+    }
+}
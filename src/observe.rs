@@ -0,0 +1,118 @@
+//! Removal reporting every path it removes, for `remove_collecting` and `remove_with_percentage`.
+
+use crate::remover::Remover;
+use crate::sys::{RealSys, Sys};
+use crate::walk::{TreeWalker, Visit};
+use crate::{Error, Operation, RemoveOptions, RemoveStats, Result};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+pub(crate) fn remove_collecting(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    remove_observing(path, |path| removed.push(path.to_path_buf()))?;
+    Ok(removed)
+}
+
+pub(crate) fn remove_with_percentage(
+    path: &Path,
+    mut progress: impl FnMut(u64, u64) + Send,
+) -> Result<RemoveStats> {
+    let total = count_entries(&crate::validate_target(path, false)?)?;
+    let mut done = 0;
+    let stats = remove_observing(path, |_| {
+        done += 1;
+        // the tree may have grown since it was counted
+        progress(done, total.max(done))
+    })?;
+    if done < total {
+        // or shrunk
+        progress(done, done);
+    }
+    Ok(stats)
+}
+
+/// The entries in the tree at `path`, itself included, without removing anything.
+fn count_entries(path: &Path) -> Result<u64> {
+    let mut entries = 0;
+    let mut walker = TreeWalker::new(path);
+    while let Some(visit) = walker.next(&RealSys) {
+        match visit {
+            Ok(Visit::Dir(_)) | Ok(Visit::Leaf(_)) => entries += 1,
+            Ok(Visit::Leave(_)) => {}
+            Err(Error::IoFailed {
+                operation: Operation::Stat,
+                source,
+                ..
+            }) if source.kind() == ErrorKind::NotFound && entries == 0 => {
+                return Err(Error::NotFound)
+            }
+            // the removal may still get in, and counts whatever it finds there
+            Err(Error::IoFailed {
+                operation: Operation::ReadDir,
+                ..
+            }) => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(entries)
+}
+
+/// Removes `path` like `remove`, calling `removed` with each entry right after removing it.
+fn remove_observing(path: &Path, removed: impl FnMut(&Path) + Send) -> Result<RemoveStats> {
+    let path = crate::validate_target(path, false)?;
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    let sys = ObservingSys {
+        inner: RealSys,
+        removed: Mutex::new(removed),
+    };
+    Ok(Remover::with_sys(&RemoveOptions::default(), &sys).run_with_metadata(&path, &metadata)?)
+}
+
+/// Reports every successful removal, in the order they happen.
+struct ObservingSys<S, F> {
+    inner: S,
+    removed: Mutex<F>,
+}
+
+impl<S: Sys, F: FnMut(&Path) + Send> ObservingSys<S, F> {
+    fn push(&self, path: &Path) {
+        let mut removed = self
+            .removed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        (*removed)(path)
+    }
+}
+
+impl<S: Sys, F: FnMut(&Path) + Send> Sys for ObservingSys<S, F> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)?;
+        self.push(path);
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)?;
+        self.push(path);
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.inner.set_permissions(path, permissions)
+    }
+}
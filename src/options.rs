@@ -0,0 +1,964 @@
+use crate::error::is_symlink_loop;
+use crate::remover::Remover;
+use crate::safety::SafetyContext;
+use crate::select::Selection;
+use crate::{Error, PartialRemoval, RemoveErrors, RemoveStats, Result, SizeHistogram};
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How close to the end of the stack the traversal may get before growing it, by default.
+pub const DEFAULT_RED_ZONE: usize = 4 * 1024;
+/// How much stack the traversal allocates each time it grows the stack, by default.
+pub const DEFAULT_STACK_SIZE: usize = 16 * 1024;
+/// How many entries a directory needs for `remove_parallel` to split it between threads, by default.
+pub const DEFAULT_FANOUT_THRESHOLD: usize = 64;
+/// How many symlinks in a row a removal follows before giving up, by default, as on Linux.
+pub const DEFAULT_MAX_SYMLINK_HOPS: usize = 40;
+
+/// Options for the configurable removal functions.
+///
+/// `RemoveOptions::default().execute(path)` behaves exactly like `remove(path)`.
+///
+/// `RemoveOptions` is `Send + Sync` in every configuration, so one set of options
+/// can be shared between threads and used for concurrent removals.
+#[derive(Debug, Clone, Default)]
+pub struct RemoveOptions {
+    pub(crate) max_bytes: Option<u64>,
+    pub(crate) max_entries: Option<u64>,
+    pub(crate) trailing_slash_semantics: bool,
+    pub(crate) require_match: bool,
+    pub(crate) allow_dot_dot: bool,
+    pub(crate) dry_run: bool,
+    pub(crate) raw_not_found: bool,
+    pub(crate) count_syscalls: bool,
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    pub(crate) deadline: Option<Instant>,
+    pub(crate) keep_permissions: bool,
+    pub(crate) restore_permissions: bool,
+    pub(crate) permission_log: Option<Arc<Mutex<Vec<PathBuf>>>>,
+    pub(crate) size_histogram: Option<Arc<Mutex<SizeHistogram>>>,
+    pub(crate) size_fn: Option<EntrySize>,
+    pub(crate) journal: Option<PathBuf>,
+    pub(crate) missing_ok: bool,
+    pub(crate) stack_params: Option<(usize, usize)>,
+    pub(crate) on_enter: Option<PathCallback>,
+    pub(crate) retry: Option<RetryPolicy>,
+    pub(crate) op_timeout: Option<Duration>,
+    pub(crate) protect_system_paths: bool,
+    pub(crate) safety: Option<SafetyContext>,
+    pub(crate) symlinks: SymlinkPolicy,
+    pub(crate) refuse_device_nodes: bool,
+    pub(crate) refuse_mount_points: bool,
+    pub(crate) only_own_files: bool,
+    pub(crate) preserve_parent_times: bool,
+    pub(crate) follow_within_filesystem: bool,
+    pub(crate) sorted: bool,
+    pub(crate) order: Option<ChildOrder>,
+    pub(crate) two_phase: bool,
+    #[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+    pub(crate) measure_exclusive_bytes: bool,
+    pub(crate) max_symlink_hops: Option<usize>,
+    pub(crate) parallel: bool,
+    pub(crate) fanout_threshold: Option<usize>,
+    #[cfg(feature = "ignore")]
+    pub(crate) ignore_file: Option<String>,
+    /// Removes directories through file descriptors first, see `descriptor_based`.
+    pub(crate) descriptor_based: bool,
+}
+
+/// What a removal does with the symlinks it finds, the target included.
+///
+/// A target that is a symlink is handled exactly like a link inside the tree: it's
+/// never descended into as a directory, whichever policy, and the link itself never survives
+/// a successful removal. To empty the directory a link points to while keeping the link,
+/// see `RemoveOptions::trailing_slash_semantics`.
+///
+/// A dangling symlink, whose target doesn't exist, is still an entry of its own:
+/// it's removed and counted in `RemoveStats::symlinks_removed` like any other link,
+/// and a dangling target is never reported as `Error::NotFound`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum SymlinkPolicy {
+    /// Removes the link itself, never touching what it points to. This is the default,
+    /// and what `rm -rf` does. Dangling links are removed the same way.
+    #[default]
+    RemoveLink,
+    /// Removes what the link resolves to, then the link itself.
+    ///
+    /// A dangling link has nothing to follow, its target counts as already removed:
+    /// only the link is removed, whatever `RemoveOptions::missing_ok` says.
+    /// A link whose target contains the link, or a directory being emptied through
+    /// another followed link, fails the removal with `Error::SymlinkLoop` instead of looping,
+    /// and so do cycles of links, which never resolve.
+    FollowAndRemoveTarget,
+    /// Fails the removal with `Error::UnexpectedSymlink` on the first symlink found,
+    /// dangling or not.
+    Error,
+}
+
+/// How filesystem calls failing with a transient error get retried.
+///
+/// Transient errors are `Interrupted` (`EINTR`), `ResourceBusy` (`EBUSY`),
+/// and the `TimedOut`/`WouldBlock` of network filesystems.
+/// On Windows, they also include `ERROR_SHARING_VIOLATION`, and a directory that is
+/// still not empty right after being emptied: its children may linger in a "delete pending"
+/// state until their last handle is closed, which the retries wait out.
+/// Policies are `Copy`, comparable and hashable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RetryPolicy {
+    pub(crate) retries: u32,
+    pub(crate) delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Retries each failing call up to `retries` times, waiting `delay` before each retry.
+    pub fn new(retries: u32, delay: Duration) -> RetryPolicy {
+        RetryPolicy { retries, delay }
+    }
+}
+
+impl RemoveOptions {
+    pub fn new() -> RemoveOptions {
+        RemoveOptions::default()
+    }
+
+    /// A cautious starting point, for interactive tools and untrusted targets.
+    ///
+    /// On top of the defaults, it sets `protect_system_paths(true)`,
+    /// `refuse_mount_points(true)` and `symlinks(SymlinkPolicy::Error)`.
+    pub fn safe() -> RemoveOptions {
+        RemoveOptions::new()
+            .protect_system_paths(true)
+            .refuse_mount_points(true)
+            .symlinks(SymlinkPolicy::Error)
+    }
+
+    /// A starting point for trusted contexts that need throughput, such as a build system
+    /// deleting its own output.
+    ///
+    /// Every guard stays off, as with the defaults, and wide directories are split between
+    /// threads like `remove_parallel` does, with the default fanout threshold.
+    pub fn fast() -> RemoveOptions {
+        RemoveOptions {
+            parallel: true,
+            ..RemoveOptions::default()
+        }
+    }
+
+    /// Stops the removal with `Error::BudgetExceeded` once more than `limit` bytes were freed.
+    ///
+    /// Sizes are only known as entries get removed, so this is a "stop after" guard
+    /// rather than a "check before" one: when the limit is hit, everything removed so far
+    /// stays removed and the rest of the tree is left in place.
+    /// Measure the tree up front if you need to refuse oversized removals entirely.
+    pub fn max_bytes(mut self, limit: u64) -> RemoveOptions {
+        self.max_bytes = Some(limit);
+        self
+    }
+
+    /// Stops the removal with `Error::BudgetExceeded` once more than `limit` entries
+    /// (files, symlinks and directories alike) were removed.
+    ///
+    /// A safety valve against a mis-targeted removal wiping far more than expected.
+    /// Like `max_bytes`, it's a "stop after" guard: the entry going over the limit
+    /// is already removed when the removal stops, and so is everything before it.
+    /// Count the entries of the tree up front to refuse oversized removals entirely.
+    pub fn max_entries(mut self, limit: u64) -> RemoveOptions {
+        self.max_entries = Some(limit);
+        self
+    }
+
+    /// Honors the POSIX meaning of a trailing separator on a symlink to a directory.
+    ///
+    /// `rm -rf link` removes just the symlink, while `rm -rf link/` names the directory
+    /// the link points to: GNU `rm` then deletes that directory's *contents*,
+    /// but neither the directory itself nor the link.
+    /// With this enabled, a target whose raw path ends in a separator and that is
+    /// a symlink to a directory gets the same treatment.
+    /// Without it (the default), trailing separators are ignored and the link is removed.
+    pub fn trailing_slash_semantics(mut self, enabled: bool) -> RemoveOptions {
+        self.trailing_slash_semantics = enabled;
+        self
+    }
+
+    /// Makes batch and pattern-based removals fail with `Error::NoMatch` if they removed nothing.
+    ///
+    /// By default, like `rm -f`, a batch whose targets are all missing silently succeeds.
+    /// Strict mode lets scripts notice a wrong pattern or an unexpectedly empty directory.
+    pub fn require_match(mut self, enabled: bool) -> RemoveOptions {
+        self.require_match = enabled;
+        self
+    }
+
+    /// Allows targets whose last segment is `.` or `..`, such as `dir/..` or `.`.
+    ///
+    /// By default these are rejected with `Error::InvalidTarget`, as removing them
+    /// removes the current or the parent directory, which is rarely intended.
+    /// When allowed, the path is resolved and the directory it names is removed.
+    pub fn allow_dot_dot(mut self, enabled: bool) -> RemoveOptions {
+        self.allow_dot_dot = enabled;
+        self
+    }
+
+    /// Simulates the removal without changing anything on disk.
+    ///
+    /// The traversal is exactly the one of a real removal, with all other options applied,
+    /// but removing entries and fixing their permissions become no-ops.
+    /// The returned `RemoveStats` describe what would have been removed.
+    pub fn dry_run(mut self, enabled: bool) -> RemoveOptions {
+        self.dry_run = enabled;
+        self
+    }
+
+    /// Treats a missing target as already removed, succeeding with zeroed stats.
+    ///
+    /// This is what `ensure_removed` does, while `remove` (the default) fails with
+    /// `Error::NotFound`. It only concerns the top-level target (or the root of
+    /// pattern-based removals): entries vanishing during the traversal are tolerated either way.
+    pub fn missing_ok(mut self, enabled: bool) -> RemoveOptions {
+        self.missing_ok = enabled;
+        self
+    }
+
+    /// Calls `callback` with each directory the traversal enters, before anything inside it
+    /// is touched and before the directory itself is removed.
+    ///
+    /// Every directory of the tree is entered once, the top-level target included,
+    /// parents always before their children; empty directories are entered too.
+    /// The callback is shared by all clones of the options, and calls are serialized.
+    pub fn on_enter<F>(mut self, callback: F) -> RemoveOptions
+    where
+        F: FnMut(&Path) + Send + 'static,
+    {
+        self.on_enter = Some(PathCallback(Arc::new(Mutex::new(callback))));
+        self
+    }
+
+    /// Keeps the entries listed in ignore files named `name`, and the ignore files themselves.
+    ///
+    /// When a directory being emptied contains a file called `name`, its gitignore-style
+    /// patterns apply to the whole subtree: matching entries are left in place, and so
+    /// are the directories containing them. Nested ignore files compose like in git,
+    /// the innermost matching pattern wins, so `!pattern` can re-enable removal.
+    /// Patterns are only consulted for entries inside the target, never for the target itself.
+    /// An unreadable or malformed ignore file fails the removal with `Error::InvalidTarget`.
+    #[cfg(feature = "ignore")]
+    pub fn honor_ignore_file(mut self, name: &str) -> RemoveOptions {
+        self.ignore_file = Some(name.to_string());
+        self
+    }
+
+    /// Fails with `Error::RefusedSpecialFile` instead of unlinking a special file.
+    ///
+    /// Special files are block and character devices, and also FIFOs and sockets,
+    /// e.g. the `/dev` of a chroot left inside the tree. The check happens before
+    /// each unlink, so what was removed before reaching the special file stays removed.
+    /// Off by default, in which case special files are removed like regular ones.
+    /// Windows has no such files, so this changes nothing there.
+    pub fn refuse_device_nodes(mut self, enabled: bool) -> RemoveOptions {
+        self.refuse_device_nodes = enabled;
+        self
+    }
+
+    /// Fails with `Error::MountPoint` instead of removing anything on another filesystem
+    /// than the directory containing it, like `rm --one-file-system`.
+    ///
+    /// The target itself is compared to its parent directory, so passing a mount point
+    /// fails right away, before anything is removed. Mount points aren't detected on Windows.
+    /// Off by default.
+    pub fn refuse_mount_points(mut self, enabled: bool) -> RemoveOptions {
+        self.refuse_mount_points = enabled;
+        self
+    }
+
+    /// Leaves alone the entries owned by other users, like well-behaved `/tmp` cleaners do.
+    ///
+    /// An entry is owned by the current user if its owner is the effective user id on Unix,
+    /// and on Windows if its owner SID is the current user or the default owner of what
+    /// this process creates (the Administrators group when elevated). Foreign entries are
+    /// kept as a whole, a directory's contents included, and so is every directory still
+    /// containing one; each is counted once in `RemoveStats::entries_skipped`.
+    /// A foreign target is skipped too, without an error. Off by default.
+    pub fn only_own_files(mut self, enabled: bool) -> RemoveOptions {
+        self.only_own_files = enabled;
+        self
+    }
+
+    /// Restores the access and modification times of the directory containing the target
+    /// once it's removed, for tools that need a reproducible filesystem state.
+    ///
+    /// Removing an entry updates the times of its parent directory; this puts back the ones
+    /// stat-ed right before the removal, with `utimensat`, whether the removal succeeded or not.
+    /// Only that immediate parent is restored, not the directories above it, and not its
+    /// change time, which can't be set. It's best-effort: times that can't be set, e.g.
+    /// on a directory of another user, are left as the removal made them. Unix only,
+    /// changing nothing elsewhere. Off by default.
+    pub fn preserve_parent_times(mut self, enabled: bool) -> RemoveOptions {
+        self.preserve_parent_times = enabled;
+        self
+    }
+
+    /// Chooses what to do with symlinks, `SymlinkPolicy::RemoveLink` by default.
+    ///
+    /// The policy applies to the target itself as well as to links found inside it.
+    /// Following links can remove anything on the system the links point to: only use
+    /// `SymlinkPolicy::FollowAndRemoveTarget` on trees whose links are trusted.
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> RemoveOptions {
+        self.symlinks = policy;
+        self
+    }
+
+    /// With `SymlinkPolicy::FollowAndRemoveTarget`, gives up on a chain of links, each pointing
+    /// to the next, once more than `hops` of them were followed, `DEFAULT_MAX_SYMLINK_HOPS` by default.
+    ///
+    /// Such a chain fails with `Error::SymlinkLoop`, as the OS does past its own limit
+    /// (`MAXSYMLINKS`), and without looking for an actual cycle. This applies to the target
+    /// as well as to the links inside it; links within the path leading to an entry are
+    /// left to the OS, as always. Other symlink policies never follow links.
+    pub fn max_symlink_hops(mut self, hops: usize) -> RemoveOptions {
+        self.max_symlink_hops = Some(hops);
+        self
+    }
+
+    /// Fails with `Error::SymlinkLoop` if `link` starts a chain of more than
+    /// `max_symlink_hops` links, each pointing to the next.
+    pub(crate) fn check_symlink_hops(&self, link: &Path) -> Result<()> {
+        let max = self.max_symlink_hops.unwrap_or(DEFAULT_MAX_SYMLINK_HOPS);
+        let mut current = link.to_path_buf();
+        for _ in 0..=max {
+            match std::fs::read_link(&current) {
+                // relative targets are relative to the directory of the link
+                Ok(target) => current = current.with_file_name("").join(target),
+                // not a link, or dangling: the chain ends here, resolving it reports the rest
+                Err(_) => return Ok(()),
+            }
+        }
+        Err(Error::SymlinkLoop(link.to_path_buf()))
+    }
+
+    /// With `SymlinkPolicy::FollowAndRemoveTarget`, only follows links whose target is on the
+    /// same filesystem as the directory containing the link; other links are merely unlinked.
+    ///
+    /// This keeps a cleanup that follows links from wandering onto e.g. a mounted backup
+    /// volume. How it combines with `refuse_mount_points`, for a link to another filesystem:
+    ///
+    /// | `follow_within_filesystem` | `refuse_mount_points` | outcome                          |
+    /// |----------------------------|-----------------------|----------------------------------|
+    /// | off                        | off                   | the target is followed, removed  |
+    /// | off                        | on                    | fails with `Error::MountPoint`   |
+    /// | on                         | either                | only the link is removed         |
+    ///
+    /// Other symlink policies never follow links, so this changes nothing for them.
+    /// Filesystems aren't told apart on Windows, where links are always followed. Off by default.
+    pub fn follow_within_filesystem(mut self, enabled: bool) -> RemoveOptions {
+        self.follow_within_filesystem = enabled;
+        self
+    }
+
+    /// Processes the entries of each directory sorted by the bytes of their names,
+    /// rather than in the order the filesystem lists them.
+    ///
+    /// The filesystem order differs between runs and platforms, this one doesn't: callbacks,
+    /// dry runs and the order entries get removed in become reproducible, e.g. for golden tests.
+    /// The cost is memory: each directory is listed completely before anything inside it is
+    /// removed, holding all of its entries at once, where the default streams the listing.
+    /// The handle-based `remove_fast` and `remove_relative` always stream. Off by default.
+    pub fn sorted(mut self, enabled: bool) -> RemoveOptions {
+        self.sorted = enabled;
+        self
+    }
+
+    /// Processes the entries of each directory in the order of `compare`, which is given
+    /// the path and `symlink_metadata` of two siblings, e.g. to free the most space first
+    /// when a budget or a deadline may stop the removal.
+    ///
+    /// The sort is stable: entries `compare` finds equal stay in listing order, or in name
+    /// order with `sorted`. Like `sorted`, each directory is listed completely first, and
+    /// its entries are held with their metadata while it's emptied, each stat-ed once more
+    /// than by default. A panicking `compare` fails the removal with `Error::CallbackPanicked`.
+    /// The handle-based `remove_fast` and `remove_relative` ignore it. Unordered by default.
+    pub fn order_by<F>(mut self, compare: F) -> RemoveOptions
+    where
+        F: Fn(&Path, &std::fs::Metadata, &Path, &std::fs::Metadata) -> std::cmp::Ordering
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.order = Some(ChildOrder(Arc::new(compare)));
+        self
+    }
+
+    /// `order_by` the largest entries first. Directories count with their own size,
+    /// as `Metadata::len` reports it, not with that of their contents.
+    pub fn largest_first(self) -> RemoveOptions {
+        self.order_by(|_, a, _, b| b.len().cmp(&a.len()))
+    }
+
+    /// `order_by` the least recently modified entries first.
+    pub fn oldest_first(self) -> RemoveOptions {
+        self.order_by(|_, a, _, b| a.modified().ok().cmp(&b.modified().ok()))
+    }
+
+    /// Removes in two phases: first every file, symlink and other non-directory of the tree,
+    /// then its emptied directories, deepest first.
+    ///
+    /// By default each directory is removed as soon as it's empty, which interleaves
+    /// directory and file removals. Some filesystems, network and copy-on-write ones in
+    /// particular, handle a run of file removals followed by a run of directory removals
+    /// better. The cost is memory: every emptied directory is held until the second phase.
+    /// On a local ext4 it makes little difference: removing 200 directories of 50 files
+    /// took 12.2µs per entry in two phases against 12.8µs in one (`cargo bench --bench remove`).
+    ///
+    /// If the first phase fails, the directories it emptied are still removed.
+    /// The handle-based `remove_fast` and `remove_relative` ignore it. Off by default.
+    pub fn two_phase(mut self, enabled: bool) -> RemoveOptions {
+        self.two_phase = enabled;
+        self
+    }
+
+    /// Counts in `RemoveStats::exclusive_bytes_freed` only the data of each removed file
+    /// that no other file shares, rather than its whole size.
+    ///
+    /// On btrfs and XFS, copies made with reflinks and files kept in snapshots share their
+    /// extents, which removing one of them doesn't release. Each regular file is opened
+    /// before being unlinked, to ask the filesystem which of its extents are shared
+    /// (`FS_IOC_FIEMAP`). The figure is approximate: extents are whole blocks, compressed
+    /// ones count with their uncompressed length, and an extent that is only partly
+    /// referenced elsewhere counts as shared as a whole. Files that can't be opened, or on
+    /// filesystems without extents such as tmpfs, count with their size. Off by default.
+    #[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+    pub fn measure_exclusive_bytes(mut self, enabled: bool) -> RemoveOptions {
+        self.measure_exclusive_bytes = enabled;
+        self
+    }
+
+    /// Retries filesystem calls that fail transiently, according to `policy`.
+    ///
+    /// Without it, the first error stops the removal. When a call keeps timing out
+    /// until the retries are exhausted, the removal fails with `Error::Timeout`.
+    pub fn retry(mut self, policy: RetryPolicy) -> RemoveOptions {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Gives up on any single filesystem call that takes longer than `timeout`,
+    /// failing the removal with `Error::Timeout`, e.g. to escape a hung network mount.
+    ///
+    /// Blocking calls can't be interrupted reliably (a hard NFS mount ignores signals),
+    /// so each call is made on a helper thread, and the removal stops waiting for it
+    /// after `timeout`. The timed-out call stays blocked on its thread, which is abandoned,
+    /// and may still complete later: the entry it was removing may go away after the error.
+    /// Each hung call costs one thread until the OS returns from it, which `retry` multiplies,
+    /// as timeouts count as transient. Going through a helper thread also adds
+    /// a few microseconds to every call, so this is for flaky storage rather than the default.
+    /// Reading a directory listing, once opened, is not covered.
+    pub fn per_op_timeout(mut self, timeout: Duration) -> RemoveOptions {
+        self.op_timeout = Some(timeout);
+        self
+    }
+
+    /// Tunes how the recursive traversal grows its stack on deeply nested trees.
+    ///
+    /// Each directory level takes a stack frame; once less than `red_zone` bytes of stack
+    /// are left, a new `stack_size` bytes segment is allocated on the heap and the traversal
+    /// continues there. Defaults are `DEFAULT_RED_ZONE` (4 KiB) and `DEFAULT_STACK_SIZE` (16 KiB).
+    /// The red zone must fit the deepest frame of one level, a few KiB at most: keep it
+    /// within 4-64 KiB, raising it for small platform stacks that overflow otherwise.
+    /// Bigger segments, 16 KiB up to a few MiB, mean fewer allocations on very deep trees.
+    /// A `stack_size` smaller than `red_zone` is raised to it.
+    pub fn stack_params(mut self, red_zone: usize, stack_size: usize) -> RemoveOptions {
+        self.stack_params = Some((red_zone, stack_size.max(red_zone)));
+        self
+    }
+
+    /// Sets how many entries a directory must have for `remove_parallel` to farm its children
+    /// out to other threads, `DEFAULT_FANOUT_THRESHOLD` by default.
+    ///
+    /// Directories with at most `threshold` entries are emptied inline by the thread that
+    /// found them, so deep and narrow trees stay single-threaded and pay no coordination cost.
+    /// Only wide directories get split, between as many threads as are idle at that point.
+    /// A threshold of `0` splits every non-empty directory. Sequential removals ignore it.
+    pub fn parallel_fanout_threshold(mut self, threshold: usize) -> RemoveOptions {
+        self.fanout_threshold = Some(threshold);
+        self
+    }
+
+    pub(crate) fn fanout_threshold(&self) -> usize {
+        self.fanout_threshold.unwrap_or(DEFAULT_FANOUT_THRESHOLD)
+    }
+
+    /// The `(red_zone, stack_size)` to give to `stacker::maybe_grow`.
+    pub(crate) fn stack(&self) -> (usize, usize) {
+        self.stack_params
+            .unwrap_or((DEFAULT_RED_ZONE, DEFAULT_STACK_SIZE))
+    }
+
+    /// Reports a missing target as the original `Error::IoError` instead of `Error::NotFound`.
+    ///
+    /// `Error::NotFound` carries no OS details, which is friendly but lossy;
+    /// with this enabled, the `io::Error` from stat-ing the target is kept as is
+    /// (e.g. for tools forwarding `raw_os_error` to monitoring).
+    /// Batch removals still skip missing targets either way.
+    pub fn raw_not_found(mut self, enabled: bool) -> RemoveOptions {
+        self.raw_not_found = enabled;
+        self
+    }
+
+    /// Counts the filesystem calls made, into `RemoveStats::syscalls`.
+    ///
+    /// Off by default, in which case nothing is counted and it costs nothing.
+    /// In a dry run, only the calls actually made are counted, not the simulated mutations.
+    pub fn count_syscalls(mut self, enabled: bool) -> RemoveOptions {
+        self.count_syscalls = enabled;
+        self
+    }
+
+    /// Refuses with `Error::InvalidTarget` any target that is a filesystem root,
+    /// or that contains the home or the current directory, e.g. `$HOME/` from an empty variable.
+    ///
+    /// What's protected is taken from `SafetyContext::from_env` when the removal starts,
+    /// unless `safety_context` says otherwise. Only the targets given to the removal are
+    /// checked, thus the protection doesn't cover symlinks followed inside the tree.
+    /// The raw `remove_tree` never checks anything. Off by default.
+    pub fn protect_system_paths(mut self, enabled: bool) -> RemoveOptions {
+        self.protect_system_paths = enabled;
+        self
+    }
+
+    /// Protects the directories of `context` instead of those of the environment,
+    /// enabling `protect_system_paths`.
+    pub fn safety_context(mut self, context: SafetyContext) -> RemoveOptions {
+        self.protect_system_paths = true;
+        self.safety = Some(context);
+        self
+    }
+
+    /// Removes directories with the traversal of `remove_fast` on Linux, falling back to
+    /// the path-based one if it fails, which then picks up where it stopped and reports the
+    /// failure with the path it happened on.
+    ///
+    /// Only for the options of `remove` and its variants, which that traversal fully supports.
+    pub(crate) fn descriptor_based(mut self) -> RemoveOptions {
+        self.descriptor_based = true;
+        self
+    }
+
+    /// Enforces `protect_system_paths` for `target`.
+    fn check_protected(&self, target: &Path) -> Result<()> {
+        match (&self.safety, self.protect_system_paths) {
+            (_, false) => Ok(()),
+            (Some(context), true) => context.check(target),
+            (None, true) => SafetyContext::from_env().check(target),
+        }
+    }
+
+    /// Stops the removal with `Error::Cancelled` once `token` is set to `true`.
+    ///
+    /// Cancellation is cooperative: the token is checked before each directory is listed,
+    /// before each entry is removed and before each target of a batch, never in the middle
+    /// of a filesystem call. The threads of a parallel removal all stop at their next entry.
+    /// Whatever was removed before stays removed, so a cancelled removal leaves partial state.
+    /// Best-effort batches stop too, reporting `Error::Cancelled` for the target they stopped at.
+    pub fn cancel_token(mut self, token: Arc<AtomicBool>) -> RemoveOptions {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Stops the removal with `Error::DeadlineExceeded` once `deadline` has passed,
+    /// for a hard cap on the total time spent, unlike `per_op_timeout`.
+    ///
+    /// The deadline is checked wherever the cancel token is, see `cancel_token`:
+    /// a slow filesystem call, or listing a huge directory, can overrun it. Either of them
+    /// stops the removal, and what was removed by then stays removed, so
+    /// `remove_with_stats` tells how far it got.
+    pub fn deadline(mut self, deadline: Instant) -> RemoveOptions {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Enforces `max_bytes` and `max_entries` for a removal that got `stats` so far.
+    pub(crate) fn check_budget(&self, stats: &RemoveStats) -> Result<()> {
+        let budgets = [
+            (self.max_bytes, stats.bytes_freed),
+            (self.max_entries, stats.entries_removed()),
+        ];
+        for (limit, removed) in budgets {
+            match limit {
+                Some(limit) if removed > limit => {
+                    return Err(Error::BudgetExceeded { removed, limit })
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a budget is set, which threads of a parallel removal couldn't share.
+    pub(crate) fn has_budget(&self) -> bool {
+        self.max_bytes.is_some() || self.max_entries.is_some()
+    }
+
+    /// Enforces `cancel_token` and `deadline`, at the points where a removal can stop cleanly.
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.cancel {
+            Some(token) if token.load(Ordering::Relaxed) => return Err(Error::Cancelled),
+            _ => {}
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => Err(Error::DeadlineExceeded),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether to make entries writable when removing them fails for lack of permissions,
+    /// then retry, enabled by default.
+    ///
+    /// Fixing permissions is what lets this crate remove read-only trees: on Windows,
+    /// the read-only attribute of the entry is cleared. On Unix, where unlinking an entry
+    /// is up to the directory holding it, a directory that can't be listed, or whose entries
+    /// can't be unlinked, gets `u+rwx` if the current user owns it; directories around the
+    /// target are never changed, only those the removal empties. Only entries whose removal
+    /// was actually denied get fixed, but that still mutates the tree even if the removal
+    /// fails later on: the remaining fixed entries keep the new modes, unless
+    /// `restore_permissions` is enabled. Disabled, nothing is ever chmod-ed, and removal
+    /// behaves like `std::fs::remove_dir_all`, failing on entries that can't be removed
+    /// with their current permissions.
+    ///
+    /// This is where `remove` differs from `rm -rf`, which doesn't chmod anything: a directory
+    /// of the caller with mode `000`, `222` or `111` and entries inside fails `rm -rf` unless
+    /// it's run as root, but is removed here. A directory that can be listed without its
+    /// entries being stat-ed (`444`) still fails both.
+    pub fn fix_permissions(mut self, enabled: bool) -> RemoveOptions {
+        self.keep_permissions = !enabled;
+        self
+    }
+
+    /// Whether to give the entries whose permissions were fixed, see `fix_permissions`,
+    /// their original permissions back once the removal is over, disabled by default.
+    ///
+    /// This only matters for the entries still there afterwards: those the removal kept on
+    /// purpose, or didn't get to because it failed. Restoring is best effort, failing to
+    /// restore an entry doesn't change the outcome of the removal.
+    pub fn restore_permissions(mut self, enabled: bool) -> RemoveOptions {
+        self.restore_permissions = enabled;
+        self
+    }
+
+    /// Appends a line to the file at `path` for every entry removed, as an audit trail.
+    ///
+    /// Lines are tab-separated: the time of the removal in seconds since the Unix epoch
+    /// (e.g. `1760400000.123456789`), the kind of entry (`file`, `symlink` or `dir`),
+    /// its size in bytes as counted in `RemoveStats::bytes_freed`, 0 for directories,
+    /// and its path, where backslashes, tabs and line breaks are escaped as `\\`, `\t`,
+    /// `\n` and `\r`, and bytes that aren't UTF-8 as `\xNN`. The file is created if needed.
+    ///
+    /// Each entry is written right after it's removed, so a crash midway leaves a trail of
+    /// everything removed until then. The writes aren't synced to disk, a system crash
+    /// can lose the last ones. A target containing the journal is rejected with
+    /// `Error::InvalidTarget` before anything is removed, and failing to write the journal stops
+    /// the removal. A dry run records nothing, and `remove_relative` doesn't support journals.
+    pub fn journal(mut self, path: PathBuf) -> RemoveOptions {
+        self.journal = Some(path);
+        self
+    }
+
+    /// Appends every entry whose permissions get changed to `log`.
+    ///
+    /// Only entries that actually needed fixing are recorded, so after a failed removal
+    /// the log tells exactly which of the remaining entries were modified.
+    /// In a dry run, it lists what would have been changed.
+    /// Nothing is collected unless this is set.
+    pub fn record_permission_changes(mut self, log: Arc<Mutex<Vec<PathBuf>>>) -> RemoveOptions {
+        self.permission_log = Some(log);
+        self
+    }
+
+    /// Adds the size of every file removed to `histogram`, symlinks and directories excluded.
+    ///
+    /// Each file costs one bucket increment, under the lock of `histogram`, which can be
+    /// shared between removals to profile them together. After a failed removal it holds
+    /// the files removed until then; in a dry run, the files that would have been.
+    pub fn size_histogram(mut self, histogram: Arc<Mutex<SizeHistogram>>) -> RemoveOptions {
+        self.size_histogram = Some(histogram);
+        self
+    }
+
+    /// Counts each entry removed in `RemoveStats::bytes_freed` with what `size` returns for
+    /// its path and `symlink_metadata`, instead of `Metadata::len` for files and symlinks
+    /// and nothing for directories.
+    ///
+    /// For the accounting the length misses: the blocks actually allocated
+    /// (`st_blocks * 512` on Unix) for sparse or compressed files, say, or sizes that
+    /// discount shared extents. A file is passed before it's removed, so that `size` can
+    /// still open it, a directory right after. `max_bytes` limits what `size` adds up;
+    /// `size_histogram` and the journal keep the lengths. A panicking `size` fails the
+    /// removal with `Error::CallbackPanicked`.
+    /// The handle-based `remove_fast` and `remove_relative` ignore it.
+    pub fn size_fn<F>(mut self, size: F) -> RemoveOptions
+    where
+        F: Fn(&Path, &std::fs::Metadata) -> u64 + Send + Sync + 'static,
+    {
+        self.size_fn = Some(EntrySize(Arc::new(size)));
+        self
+    }
+
+    /// Validates `path` like `remove` does, then force-removes it with these options.
+    pub fn execute<P: AsRef<Path>>(&self, path: P) -> Result<RemoveStats> {
+        self.execute_reporting_partial(path.as_ref())
+            .map_err(Error::from)
+    }
+
+    /// Same as `execute`, but adds what got removed to `stats` instead of returning it.
+    ///
+    /// Handy to total up removals in a loop. What a failed removal removed
+    /// before the error is added too, so the total is always exact.
+    pub fn execute_into<P: AsRef<Path>>(&self, path: P, stats: &mut RemoveStats) -> Result<()> {
+        match self.execute_reporting_partial(path.as_ref()) {
+            Ok(removed) => {
+                *stats += removed;
+                Ok(())
+            }
+            Err(partial) => {
+                *stats += *partial.stats;
+                Err(partial.error)
+            }
+        }
+    }
+
+    pub(crate) fn execute_reporting_partial(
+        &self,
+        path: &Path,
+    ) -> std::result::Result<RemoveStats, PartialRemoval> {
+        match self.execute_if_exists(path)? {
+            Outcome::Removed(stats) => Ok(stats),
+            Outcome::Missing(_) if self.missing_ok => Ok(RemoveStats::default()),
+            Outcome::Missing(err) if self.raw_not_found => Err(Error::IoError(err).into()),
+            Outcome::Missing(_) => Err(Error::NotFound.into()),
+        }
+    }
+
+    /// Same as `execute_reporting_partial`, but a missing target is not an error.
+    pub(crate) fn execute_if_exists(
+        &self,
+        path: &Path,
+    ) -> std::result::Result<Outcome, PartialRemoval> {
+        if !self.preserve_parent_times {
+            return self.execute_target(path);
+        }
+        let parent = match path.components().as_path().parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        // followed, as `utimensat` follows a parent that is a symlink
+        let times = parent.metadata();
+        let outcome = self.execute_target(path);
+        if let Ok(times) = times {
+            // best-effort, like the removal it follows may have been
+            let _ = restore_times(parent, &times);
+        }
+        outcome
+    }
+
+    fn execute_target(&self, path: &Path) -> std::result::Result<Outcome, PartialRemoval> {
+        if self.trailing_slash_semantics && has_trailing_separator(path) && is_dir_symlink(path) {
+            // emptying the directory the link points to is as bad as removing it
+            self.check_symlink_hops(path)?;
+            self.check_protected(&path.canonicalize().map_err(Error::IoError)?)?;
+            return Remover::new(self).run_contents(path).map(Outcome::Removed);
+        }
+        let path = crate::validate_target(path, self.allow_dot_dot)?;
+        self.check_protected(&path)?;
+        let remover = Remover::new(self);
+        match remover.sys().symlink_metadata(&path) {
+            #[cfg(target_os = "linux")]
+            Ok(metadata) if self.descriptor_based && metadata.is_dir() => {
+                let mut stats = RemoveStats::default();
+                match crate::fast::remove_dir_tree(&path, self, &mut stats) {
+                    Ok(()) => Ok(Outcome::Removed(stats)),
+                    Err(_) => match remover.sys().symlink_metadata(&path) {
+                        Ok(metadata) => remover
+                            .run_with_metadata(&path, &metadata)
+                            .map(|more| Outcome::Removed(stats + more))
+                            .map_err(|mut partial| {
+                                *partial.stats = stats + *partial.stats;
+                                partial
+                            }),
+                        // the failure happened right as the directory got removed
+                        Err(err) if err.kind() == ErrorKind::NotFound => {
+                            Ok(Outcome::Removed(stats))
+                        }
+                        Err(err) => Err(Error::IoError(err).into()),
+                    },
+                }
+            }
+            Ok(metadata) => remover
+                .run_with_metadata(&path, &metadata)
+                .map(Outcome::Removed),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Outcome::Missing(err)),
+            Err(err) if is_symlink_loop(&err) => Err(Error::SymlinkLoop(path.into_owned()).into()),
+            Err(err) => Err(Error::IoError(err).into()),
+        }
+    }
+
+    /// Removes every path in `paths` with these options, see `remove_all`.
+    ///
+    /// With `require_match`, fails with `Error::NoMatch` if none of the paths existed.
+    pub fn execute_all<I, P>(&self, paths: I) -> Result<RemoveStats>
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        crate::batch::execute_all(self, paths)
+    }
+
+    /// Removes every path in `paths` with these options, see `remove_all_best_effort`.
+    pub fn execute_all_best_effort<I, P>(&self, paths: I) -> (RemoveStats, RemoveErrors)
+    where
+        I: IntoIterator<Item = P>,
+        P: AsRef<Path>,
+    {
+        crate::batch::execute_all_best_effort(self, paths)
+    }
+
+    /// Removes the empty directory `path` with these options, see `remove_empty`.
+    pub fn execute_empty<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = crate::validate_target(path.as_ref(), false)?;
+        if let Err(err) = path.symlink_metadata() {
+            return match err.kind() {
+                ErrorKind::NotFound => Err(Error::NotFound),
+                _ => Err(Error::IoError(err)),
+            };
+        }
+        self.check_cancelled()?;
+        Remover::new(self).run_empty(&path)
+    }
+
+    /// Prunes the files under `root` matching `pred` with these options, see `prune`.
+    pub fn execute_prune<P, F>(&self, root: P, mut pred: F) -> Result<RemoveStats>
+    where
+        P: AsRef<Path>,
+        F: FnMut(&Path, &std::fs::Metadata) -> bool,
+    {
+        crate::select::remove_selected(
+            root.as_ref(),
+            self,
+            &mut |path, metadata| Selection::remove_if(!metadata.is_dir() && pred(path, metadata)),
+            true,
+        )
+    }
+}
+
+/// A user callback taking a path, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct PathCallback(Arc<Mutex<PathFn>>);
+
+type PathFn = dyn FnMut(&Path) + Send;
+
+impl PathCallback {
+    /// Calls the callback, the lock being released normally even if it panics.
+    pub(crate) fn call(&self, path: &Path) -> Result<()> {
+        let mut callback = self
+            .0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        crate::error::catch_callback(|| (*callback)(path))
+    }
+}
+
+impl fmt::Debug for PathCallback {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PathCallback(..)")
+    }
+}
+
+/// A user comparator of sibling entries, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct ChildOrder(Arc<OrderFn>);
+
+type OrderFn = dyn Fn(&Path, &std::fs::Metadata, &Path, &std::fs::Metadata) -> std::cmp::Ordering
+    + Send
+    + Sync;
+
+impl ChildOrder {
+    /// Sorts `entries` with the comparator, reporting a panic in it as an error.
+    pub(crate) fn sort<T>(&self, entries: &mut [(PathBuf, std::fs::Metadata, T)]) -> Result<()> {
+        crate::error::catch_callback(|| {
+            entries.sort_by(|(a, a_metadata, _), (b, b_metadata, _)| {
+                (self.0)(a, a_metadata, b, b_metadata)
+            })
+        })
+    }
+}
+
+impl fmt::Debug for ChildOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ChildOrder(..)")
+    }
+}
+
+/// A user measure of the space an entry frees, shared between clones of the options.
+#[derive(Clone)]
+pub(crate) struct EntrySize(Arc<SizeFn>);
+
+type SizeFn = dyn Fn(&Path, &std::fs::Metadata) -> u64 + Send + Sync;
+
+impl EntrySize {
+    /// The size of `path`, reporting a panic in the function as an error.
+    pub(crate) fn call(&self, path: &Path, metadata: &std::fs::Metadata) -> Result<u64> {
+        crate::error::catch_callback(|| (self.0)(path, metadata))
+    }
+}
+
+impl fmt::Debug for EntrySize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("EntrySize(..)")
+    }
+}
+
+/// The result of removing a target that may not exist.
+pub(crate) enum Outcome {
+    Removed(RemoveStats),
+    /// The target did not exist, as reported by stat-ing it.
+    Missing(io::Error),
+}
+
+fn has_trailing_separator(path: &Path) -> bool {
+    path.to_string_lossy().ends_with(std::path::is_separator)
+}
+
+/// Sets the access and modification times of `dir` to those in `times`, to the nanosecond.
+#[cfg(unix)]
+fn restore_times(dir: &Path, times: &std::fs::Metadata) -> io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    let dir = std::ffi::CString::new(dir.as_os_str().as_bytes())?;
+    // SAFETY: an all-zero `timespec` is valid, whatever padding the platform adds
+    let mut spec: [libc::timespec; 2] = unsafe { std::mem::zeroed() };
+    spec[0].tv_sec = times.atime() as libc::time_t;
+    spec[0].tv_nsec = times.atime_nsec() as _;
+    spec[1].tv_sec = times.mtime() as libc::time_t;
+    spec[1].tv_nsec = times.mtime_nsec() as _;
+    // SAFETY: `dir` is nul-terminated and `spec` holds the two times `utimensat` reads
+    if unsafe { libc::utimensat(libc::AT_FDCWD, dir.as_ptr(), spec.as_ptr(), 0) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restore_times(_: &Path, _: &std::fs::Metadata) -> io::Result<()> {
+    Ok(())
+}
+
+fn is_dir_symlink(path: &Path) -> bool {
+    let without_separator = path.components().as_path();
+    let is_symlink = without_separator
+        .symlink_metadata()
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false);
+    is_symlink && path.is_dir()
+}
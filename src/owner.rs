@@ -0,0 +1,119 @@
+//! Who owns an entry, for `RemoveOptions::only_own_files`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Whether the entry at `path`, stat-ed as `metadata`, is owned by the effective user.
+#[cfg(unix)]
+pub(crate) fn is_own(_: &Path, metadata: &fs::Metadata) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    // SAFETY: `geteuid` has no preconditions and never fails
+    Ok(metadata.uid() == unsafe { libc::geteuid() })
+}
+
+/// Whether the owner SID of the entry at `path` is the current user, or the default
+/// owner of what the current process creates (e.g. the Administrators group when elevated).
+#[cfg(windows)]
+pub(crate) fn is_own(path: &Path, _: &fs::Metadata) -> io::Result<bool> {
+    use std::ffi::c_void;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr::null_mut;
+    const SE_FILE_OBJECT: i32 = 1;
+    const OWNER_SECURITY_INFORMATION: u32 = 1;
+    const TOKEN_QUERY: u32 = 8;
+    const TOKEN_USER: i32 = 1;
+    const TOKEN_OWNER: i32 = 4;
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn GetNamedSecurityInfoW(
+            name: *const u16,
+            object_type: i32,
+            info: u32,
+            owner: *mut *mut c_void,
+            group: *mut *mut c_void,
+            dacl: *mut *mut c_void,
+            sacl: *mut *mut c_void,
+            descriptor: *mut *mut c_void,
+        ) -> u32;
+        fn OpenProcessToken(process: *mut c_void, access: u32, token: *mut *mut c_void) -> i32;
+        fn GetTokenInformation(
+            token: *mut c_void,
+            class: i32,
+            info: *mut c_void,
+            length: u32,
+            returned: *mut u32,
+        ) -> i32;
+        fn EqualSid(first: *mut c_void, second: *mut c_void) -> i32;
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentProcess() -> *mut c_void;
+        fn CloseHandle(handle: *mut c_void) -> i32;
+        fn LocalFree(memory: *mut c_void) -> *mut c_void;
+    }
+
+    /// The SID at the start of a `TOKEN_USER` or `TOKEN_OWNER` of the token, as a buffer
+    /// that must outlive the SID pointer.
+    unsafe fn token_sid(token: *mut c_void, class: i32) -> io::Result<Vec<usize>> {
+        let mut length = 0;
+        GetTokenInformation(token, class, null_mut(), 0, &mut length);
+        // `usize` elements keep the buffer aligned for the pointers inside it
+        let mut buffer = vec![0usize; (length as usize).div_ceil(std::mem::size_of::<usize>())];
+        if GetTokenInformation(
+            token,
+            class,
+            buffer.as_mut_ptr().cast(),
+            length,
+            &mut length,
+        ) == 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(buffer)
+    }
+
+    let wide: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut owner = null_mut();
+    let mut descriptor = null_mut();
+    // SAFETY: `wide` is nul-terminated, the out pointers are valid or null, `descriptor`
+    // is freed once `owner`, which points into it, isn't needed anymore,
+    // and the token buffers start with the SID pointer they own
+    unsafe {
+        let status = GetNamedSecurityInfoW(
+            wide.as_ptr(),
+            SE_FILE_OBJECT,
+            OWNER_SECURITY_INFORMATION,
+            &mut owner,
+            null_mut(),
+            null_mut(),
+            null_mut(),
+            &mut descriptor,
+        );
+        if status != 0 {
+            return Err(io::Error::from_raw_os_error(status as i32));
+        }
+        let mut token = null_mut();
+        let result = if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            let result = token_sid(token, TOKEN_USER).and_then(|user| {
+                let default_owner = token_sid(token, TOKEN_OWNER)?;
+                Ok(EqualSid(owner, user[0] as *mut c_void) != 0
+                    || EqualSid(owner, default_owner[0] as *mut c_void) != 0)
+            });
+            CloseHandle(token);
+            result
+        };
+        LocalFree(descriptor);
+        result
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn is_own(_: &Path, _: &fs::Metadata) -> io::Result<bool> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "owners can't be queried on this platform",
+    ))
+}
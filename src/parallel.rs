@@ -0,0 +1,93 @@
+//! Emptying a directory by removing its children concurrently.
+
+use crate::remover::Remover;
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::Instant;
+
+pub(crate) fn remove_contents_parallel(
+    dir: &Path,
+    threads: usize,
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    let dir = crate::validate_target(dir, false)?;
+    match dir.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let started = Instant::now();
+    let children = fs::read_dir(&dir)?
+        .map(|child| child.map(|child| child.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let work = || remove_children(&children, opts, &next, &failed);
+    let threads = threads.clamp(1, children.len().max(1));
+    let parts: Vec<_> = thread::scope(|scope| {
+        let spawned: Vec<_> = (1..threads).map(|_| scope.spawn(work)).collect();
+        let mut parts = vec![work()];
+        for handle in spawned {
+            parts.push(
+                handle
+                    .join()
+                    .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+            );
+        }
+        parts
+    });
+    let mut stats = RemoveStats::default();
+    let mut first_error = None;
+    for (part, error) in parts {
+        stats += part;
+        if let Some((true, error)) = error {
+            first_error = Some(error);
+        }
+    }
+    if let Some(error) = first_error {
+        return Err(error);
+    }
+    stats.max_dir_fanout = stats.max_dir_fanout.max(children.len());
+    stats.elapsed = started.elapsed();
+    Ok(stats)
+}
+
+/// Takes children one at a time until there are none left or a thread failed.
+///
+/// Returns the error this thread stopped at, if any, and whether it was the first one.
+fn remove_children(
+    children: &[PathBuf],
+    opts: &RemoveOptions,
+    next: &AtomicUsize,
+    failed: &AtomicBool,
+) -> (RemoveStats, Option<(bool, Error)>) {
+    let mut stats = RemoveStats::default();
+    while !failed.load(Ordering::Relaxed) {
+        let child = match children.get(next.fetch_add(1, Ordering::Relaxed)) {
+            Some(child) => child,
+            None => break,
+        };
+        if let Err(error) = opts.check_cancelled() {
+            let first = !failed.swap(true, Ordering::Relaxed);
+            return (stats, Some((first, error)));
+        }
+        match Remover::new(opts).run(child) {
+            Ok(removed) => stats += removed,
+            Err(partial) => {
+                let first = !failed.swap(true, Ordering::Relaxed);
+                stats += *partial.stats;
+                return (stats, Some((first, partial.error)));
+            }
+        }
+    }
+    (stats, None)
+}
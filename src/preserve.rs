@@ -0,0 +1,143 @@
+//! Moving a few entries out of a tree before removing it, for `remove_preserving`.
+
+use crate::batch::{confined_entry, identity};
+use crate::sys::RealSys;
+use crate::walk::{TreeWalker, Visit};
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) fn remove_preserving(
+    root: &Path,
+    preserve: &[(&Path, &Path)],
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    let root = crate::validate_target(root, false)?;
+    match root.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let canonical_root = root.canonicalize()?;
+    let mut moves: Vec<(PathBuf, PathBuf)> = Vec::with_capacity(preserve.len());
+    let mut sources: Vec<PathBuf> = Vec::with_capacity(preserve.len());
+    // everything is checked before the first move, so a bad pair leaves the tree untouched
+    for &(src, dest) in preserve {
+        let relative = normalized(confined_entry(src)?);
+        if let Some(other) = sources
+            .iter()
+            .find(|other| relative.starts_with(other) || other.starts_with(&relative))
+        {
+            return Err(Error::InvalidTarget(format!(
+                "Invalid preserved entry {}, it overlaps with {}",
+                src.display(),
+                other.display()
+            )));
+        }
+        let source = root.join(&relative);
+        match source.parent().map(Path::canonicalize) {
+            Some(Ok(parent)) if parent.starts_with(&canonical_root) => {}
+            _ => {
+                return Err(Error::InvalidTarget(format!(
+                    "Invalid preserved entry {}, it doesn't exist inside the root",
+                    src.display()
+                )))
+            }
+        }
+        if source.symlink_metadata().is_err() {
+            return Err(Error::InvalidTarget(format!(
+                "Invalid preserved entry {}, it doesn't exist inside the root",
+                src.display()
+            )));
+        }
+        // `rename` silently replaces some existing entries, so never let it
+        if dest.symlink_metadata().is_ok() {
+            return Err(Error::InvalidTarget(format!(
+                "Invalid destination {}, it already exists",
+                dest.display()
+            )));
+        }
+        if identity(dest).starts_with(&canonical_root) {
+            return Err(Error::InvalidTarget(format!(
+                "Invalid destination {}, it is inside the root being removed",
+                dest.display()
+            )));
+        }
+        sources.push(relative);
+        moves.push((source, dest.to_path_buf()));
+    }
+    for (source, dest) in &moves {
+        match fs::rename(source, dest) {
+            Ok(()) => {}
+            // the source is removed along with the rest of the root
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => copy_tree(source, dest)?,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    opts.execute(&root)
+}
+
+/// `path` without its `.` segments, so that overlaps can be compared.
+fn normalized(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+/// Copies the entry at `source` to the new path `dest`, contents, permissions and
+/// symlinks included, links being copied as links rather than followed.
+fn copy_tree(source: &Path, dest: &Path) -> Result<()> {
+    let dest_of = |path: &Path| match path.strip_prefix(source) {
+        Ok(relative) if relative.as_os_str().is_empty() => dest.to_path_buf(),
+        Ok(relative) => dest.join(relative),
+        Err(_) => unreachable!("the walk stays below its root"),
+    };
+    let mut walker = TreeWalker::new(source);
+    while let Some(visit) = walker.next(&RealSys) {
+        match visit? {
+            Visit::Dir(entry) => fs::create_dir(dest_of(&entry.path))?,
+            Visit::Leaf(entry) if entry.metadata.file_type().is_symlink() => {
+                copy_symlink(&entry.path, &dest_of(&entry.path))?
+            }
+            Visit::Leaf(entry) => {
+                fs::copy(&entry.path, dest_of(&entry.path))?;
+            }
+            // after the contents, as they couldn't be added to a read-only directory
+            Visit::Leave(entry) => {
+                fs::set_permissions(dest_of(&entry.path), entry.metadata.permissions())?
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(link: &Path, dest: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(link)?, dest)
+}
+
+#[cfg(windows)]
+fn copy_symlink(link: &Path, dest: &Path) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file, FileTypeExt};
+    let target = fs::read_link(link)?;
+    if link.symlink_metadata()?.file_type().is_symlink_dir() {
+        symlink_dir(target, dest)
+    } else {
+        symlink_file(target, dest)
+    }
+}
+
+#[cfg(not(any(unix, windows)))]
+fn copy_symlink(_: &Path, _: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "symlinks can't be created on this platform",
+    ))
+}
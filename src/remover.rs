@@ -0,0 +1,1074 @@
+use crate::error::failed;
+use crate::journal::Journal;
+use crate::keep::IgnoreStack;
+use crate::options::ChildOrder;
+use crate::sys::{AtomicCounts, CountingSys, DryRunSys, RealSys, RetryingSys, Sys, TimeoutSys};
+use crate::{
+    Error, Operation, PartialRemoval, RemoveOptions, RemoveStats, RemovedKind, Result,
+    SymlinkPolicy,
+};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+/// The forceful recursive traversal behind all removal functions.
+pub(crate) struct Remover<'a> {
+    opts: &'a RemoveOptions,
+    sys: Box<dyn Sys + 'a>,
+    counts: Option<Arc<AtomicCounts>>,
+    stats: RemoveStats,
+    /// How many names of each hard-linked inode a dry run removed so far, across all threads.
+    links_removed: Arc<Mutex<HashMap<(u64, u64), u64>>>,
+    ignores: IgnoreStack,
+    /// Entries left in place on purpose, which keep their ancestors from being removed.
+    kept: u64,
+    /// The filesystems of the directories being emptied, outermost first.
+    devices: Vec<u64>,
+    /// The resolved parents of the symlinks being followed, outermost first.
+    link_parents: Vec<PathBuf>,
+    /// How many more threads a parallel removal may still start, `None` for a sequential one.
+    idle_threads: Option<Arc<AtomicUsize>>,
+    /// Where removed entries are recorded, shared by the threads of a parallel removal.
+    journal: Option<Arc<Journal>>,
+    /// The original permissions of the entries fixed so far, for `RemoveOptions::restore_permissions`.
+    fixed: Option<Arc<Mutex<Vec<Fixed>>>>,
+    /// The emptied directories left for the second phase of `RemoveOptions::two_phase`.
+    deferred: Option<Arc<Mutex<Vec<Deferred>>>>,
+    started: Instant,
+}
+
+impl<'a> Remover<'a> {
+    pub(crate) fn new(opts: &'a RemoveOptions) -> Remover<'a> {
+        match opts.op_timeout {
+            Some(timeout) => Remover::layered(opts, Box::new(TimeoutSys::new(RealSys, timeout))),
+            None => Remover::with_sys(opts, &RealSys),
+        }
+    }
+
+    pub(crate) fn with_sys(opts: &'a RemoveOptions, sys: &'a dyn Sys) -> Remover<'a> {
+        Remover::layered(opts, Box::new(sys))
+    }
+
+    /// Builds the layers the options call for on top of the `base` filesystem.
+    fn layered(opts: &'a RemoveOptions, base: Box<dyn Sys + 'a>) -> Remover<'a> {
+        // counting goes below the retries and the dry run,
+        // so that every call actually made is counted, and only those
+        let counts = if opts.count_syscalls {
+            Some(Arc::new(AtomicCounts::default()))
+        } else {
+            None
+        };
+        let mut sys = base;
+        if let Some(counts) = &counts {
+            sys = Box::new(CountingSys::new(sys, counts.clone()));
+        }
+        if let Some(policy) = opts.retry {
+            sys = Box::new(RetryingSys::new(sys, policy));
+        }
+        if opts.dry_run {
+            sys = Box::new(DryRunSys::new(sys));
+        }
+        let idle_threads = if opts.parallel && !opts.has_budget() {
+            let threads = thread::available_parallelism().map_or(1, |n| n.get());
+            Some(Arc::new(AtomicUsize::new(threads - 1)))
+        } else {
+            None
+        };
+        // a dry run removes nothing, so it has nothing to record
+        let journal = match &opts.journal {
+            Some(path) if !opts.dry_run => Some(Arc::new(Journal::new(path))),
+            _ => None,
+        };
+        let fixed = if opts.restore_permissions {
+            Some(Arc::default())
+        } else {
+            None
+        };
+        let deferred = if opts.two_phase {
+            Some(Arc::default())
+        } else {
+            None
+        };
+        Remover {
+            opts,
+            sys,
+            counts,
+            stats: RemoveStats::default(),
+            links_removed: Arc::default(),
+            ignores: IgnoreStack::default(),
+            kept: 0,
+            devices: Vec::new(),
+            link_parents: Vec::new(),
+            idle_threads,
+            journal,
+            fixed,
+            deferred,
+            started: Instant::now(),
+        }
+    }
+
+    /// A remover for one part of a parallel removal, sharing the already layered `sys`.
+    fn worker(&self) -> Remover<'_> {
+        Remover {
+            opts: self.opts,
+            sys: Box::new(&*self.sys),
+            counts: None,
+            stats: RemoveStats::default(),
+            links_removed: self.links_removed.clone(),
+            ignores: self.ignores.clone(),
+            kept: 0,
+            devices: self.devices.clone(),
+            link_parents: self.link_parents.clone(),
+            idle_threads: self.idle_threads.clone(),
+            journal: self.journal.clone(),
+            fixed: self.fixed.clone(),
+            deferred: self.deferred.clone(),
+            started: self.started,
+        }
+    }
+
+    pub(crate) fn opts(&self) -> &RemoveOptions {
+        self.opts
+    }
+
+    /// The filesystem as seen by this removal, for callers doing their own walk on top.
+    pub(crate) fn sys(&self) -> &dyn Sys {
+        &*self.sys
+    }
+
+    /// Removes `path`, reporting what got removed even if the removal fails.
+    pub(crate) fn run(mut self, path: &Path) -> std::result::Result<RemoveStats, PartialRemoval> {
+        let result = self
+            .check_journal(path)
+            .and_then(|()| self.recursive_remove(path));
+        self.finish(result)
+    }
+
+    /// Same as `run`, for a target that was already stat-ed by the caller.
+    pub(crate) fn run_with_metadata(
+        mut self,
+        path: &Path,
+        metadata: &fs::Metadata,
+    ) -> std::result::Result<RemoveStats, PartialRemoval> {
+        let result = self
+            .check_journal(path)
+            .and_then(|()| self.remove_entry(path, metadata));
+        self.finish(result)
+    }
+
+    /// Removes everything inside the directory `dir`, but not `dir` itself.
+    pub(crate) fn run_contents(
+        mut self,
+        dir: &Path,
+    ) -> std::result::Result<RemoveStats, PartialRemoval> {
+        let result = self.check_journal(dir).and_then(|()| {
+            self.enter(dir)?;
+            let children = self
+                .sys
+                .read_dir(dir)
+                .map_err(failed(Operation::ReadDir, dir))?;
+            self.remove_children(dir, children)
+        });
+        self.finish(result)
+    }
+
+    /// Removes the remaining `children` of the directory `dir`, as listed by the caller.
+    pub(crate) fn run_entries(
+        mut self,
+        dir: &Path,
+        children: fs::ReadDir,
+    ) -> std::result::Result<RemoveStats, PartialRemoval> {
+        let result = self
+            .check_journal(dir)
+            .and_then(|()| self.enter(dir))
+            .and_then(|()| self.remove_children(dir, children));
+        self.finish(result)
+    }
+
+    /// Removes the directory `path` only if it's empty, never descending into it.
+    pub(crate) fn run_empty(mut self, path: &Path) -> Result<()> {
+        self.remove_empty_dir(path)
+    }
+
+    /// Force-removes `path` as part of a bigger operation, adding to the stats.
+    pub(crate) fn remove_path(&mut self, path: &Path) -> Result<()> {
+        self.check_journal(path)?;
+        self.recursive_remove(path)
+    }
+
+    /// Removes the directory `path` if it's empty, adding to the stats.
+    pub(crate) fn remove_empty_dir(&mut self, path: &Path) -> Result<()> {
+        let metadata = self.sys.symlink_metadata(path).ok();
+        let removed = match &metadata {
+            Some(metadata) => {
+                self.with_permissions_fixed(path, metadata, || self.sys.remove_dir(path))
+            }
+            None => self.sys.remove_dir(path),
+        };
+        match removed {
+            Ok(()) => self.count_removed_dir(path, metadata.as_ref()),
+            Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => Err(Error::NotEmpty),
+            Err(err) => Err(failed(Operation::RemoveDir, path)(err)),
+        }
+    }
+
+    pub(crate) fn finish(
+        mut self,
+        result: Result<()>,
+    ) -> std::result::Result<RemoveStats, PartialRemoval> {
+        let result = match self.remove_deferred_dirs() {
+            Err(err) if result.is_ok() => Err(err),
+            _ => result,
+        };
+        self.restore_permissions();
+        self.stats.elapsed = self.started.elapsed();
+        if let Some(counts) = &self.counts {
+            self.stats.syscalls = counts.snapshot();
+        }
+        match result {
+            Ok(()) => Ok(self.stats),
+            Err(error) => Err(PartialRemoval {
+                stats: Box::new(self.stats),
+                error,
+            }),
+        }
+    }
+
+    fn recursive_remove(&mut self, path: &Path) -> Result<()> {
+        let metadata = self
+            .sys
+            .symlink_metadata(path)
+            .map_err(failed(Operation::Stat, path))?;
+        self.remove_entry(path, &metadata)
+    }
+
+    /// Removes `path` according to `metadata`, the single snapshot of what the entry is.
+    ///
+    /// The entry is never stat-ed again before deciding between unlinking and descending.
+    /// If it gets swapped after the snapshot (e.g. a file replaced by a symlink to a directory),
+    /// the worst outcome is a failing or link-only unlink, not following the new entry.
+    fn remove_entry(&mut self, path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        let action = classify(metadata, self.opts);
+        match action {
+            Action::Refuse(refusal) => return Err(refusal.error(path)),
+            Action::FollowThenUnlink => self.remove_link_target(path)?,
+            Action::Unlink | Action::Descend => {}
+        }
+        // the checks that need more than the entry's metadata
+        if self.opts.refuse_mount_points {
+            self.check_mount_point(path, metadata)?;
+        }
+        if self.opts.only_own_files
+            && !crate::owner::is_own(path, metadata).map_err(failed(Operation::Stat, path))?
+        {
+            self.kept += 1;
+            self.stats.entries_skipped += 1;
+            return Ok(());
+        }
+        if action != Action::Descend {
+            return self.remove_non_dir(path, metadata);
+        }
+        self.enter(path)?;
+        // with two phases, a directory is never removed before all files are
+        if self.deferred.is_some() || self.sys.remove_dir(path).is_err() {
+            self.opts.check_cancelled()?;
+            let children = match self.list_dir(path)? {
+                Listing::Children(children) => children,
+                Listing::Replaced(metadata) => return self.remove_non_dir(path, &metadata),
+                Listing::Gone => return Ok(()),
+            };
+            let kept_before = self.kept;
+            let honoring = self.ignores.enter(path, self.opts)?;
+            self.devices.push(device(metadata));
+            let result = self.remove_children(path, children);
+            self.devices.pop();
+            if honoring {
+                self.ignores.leave();
+            }
+            result?;
+            if self.kept > kept_before {
+                // the directory still holds entries that were kept on purpose
+                return Ok(());
+            }
+            if let Some(deferred) = &self.deferred {
+                deferred
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .push(Deferred {
+                        path: path.to_path_buf(),
+                        metadata: metadata.clone(),
+                        parent_device: self.devices.last().copied(),
+                    });
+                return Ok(());
+            }
+            self.with_permissions_fixed(path, metadata, || self.remove_emptied_dir(path))
+                .map_err(|err| removal_error(Operation::RemoveDir, path, metadata, err))?;
+        }
+        self.count_removed_dir(path, Some(metadata))
+    }
+
+    /// Counts the removed directory `path`, described by `metadata` if it could be read.
+    fn count_removed_dir(&mut self, path: &Path, metadata: Option<&fs::Metadata>) -> Result<()> {
+        self.stats.dirs_removed += 1;
+        self.stats.inodes_freed += 1;
+        if let (Some(size_fn), Some(metadata)) = (&self.opts.size_fn, metadata) {
+            self.stats.bytes_freed += size_fn.call(path, metadata)?;
+        }
+        self.record(path, RemovedKind::Dir, 0)?;
+        self.opts.check_budget(&self.stats)
+    }
+
+    /// The second phase of `RemoveOptions::two_phase`: removes the emptied directories,
+    /// deepest first.
+    fn remove_deferred_dirs(&mut self) -> Result<()> {
+        let mut deferred = match &self.deferred {
+            Some(deferred) => std::mem::take(
+                &mut *deferred
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()),
+            ),
+            None => return Ok(()),
+        };
+        // stable, so that siblings stay in the order they were emptied in
+        deferred.sort_by_key(|dir| std::cmp::Reverse(dir.path.components().count()));
+        for dir in deferred {
+            self.opts.check_cancelled()?;
+            // as when removed right after being emptied, see `fix_permissions`
+            self.devices.extend(dir.parent_device);
+            let removed = self.with_permissions_fixed(&dir.path, &dir.metadata, || {
+                self.remove_emptied_dir(&dir.path)
+            });
+            if dir.parent_device.is_some() {
+                self.devices.pop();
+            }
+            removed.map_err(|err| {
+                removal_error(Operation::RemoveDir, &dir.path, &dir.metadata, err)
+            })?;
+            self.count_removed_dir(&dir.path, Some(&dir.metadata))?;
+        }
+        Ok(())
+    }
+
+    /// Refuses `path` if it's on another filesystem than the directory containing it.
+    fn check_mount_point(&self, path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        if device(metadata) == self.parent_device(path)? {
+            Ok(())
+        } else {
+            Err(Error::MountPoint(path.to_path_buf()))
+        }
+    }
+
+    /// The filesystem of the directory containing `path`.
+    fn parent_device(&self, path: &Path) -> Result<u64> {
+        if let Some(&device) = self.devices.last() {
+            return Ok(device);
+        }
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        match self.sys.symlink_metadata(parent) {
+            Ok(parent) => Ok(device(&parent)),
+            Err(err) => Err(failed(Operation::Stat, parent)(err)),
+        }
+    }
+
+    /// Removes the directory `path` once all of its children were removed.
+    ///
+    /// On Windows, removed children linger in a "delete pending" state until their last
+    /// handle is closed, keeping the directory non-empty for a while: the retry policy
+    /// waits that out. Elsewhere, and for the first attempt on a directory, not being empty
+    /// is a real answer, and retrying it would only delay listing the directory.
+    fn remove_emptied_dir(&self, path: &Path) -> io::Result<()> {
+        let mut result = self.sys.remove_dir(path);
+        if let (true, Some(policy)) = (cfg!(windows), self.opts.retry) {
+            for _ in 0..policy.retries {
+                match &result {
+                    Err(err) if err.kind() == ErrorKind::DirectoryNotEmpty => {
+                        thread::sleep(policy.delay);
+                        result = self.sys.remove_dir(path);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        result
+    }
+
+    /// Removes what the symlink `link` resolves to, unless that contains a directory being emptied.
+    fn remove_link_target(&mut self, link: &Path) -> Result<()> {
+        self.opts.check_symlink_hops(link)?;
+        let target = match fs::canonicalize(link) {
+            Ok(target) => target,
+            // dangling, including through a file used as a directory
+            Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+                return Ok(())
+            }
+            // a cycle of links never resolves, and becomes `Error::SymlinkLoop`
+            Err(err) => return Err(failed(Operation::Stat, link)(err)),
+        };
+        let parent = match link.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let parent = fs::canonicalize(parent).map_err(failed(Operation::Stat, parent))?;
+        let mut emptied = self.link_parents.iter().chain(Some(&parent));
+        if emptied.any(|dir| dir.starts_with(&target)) {
+            return Err(Error::SymlinkLoop(link.to_path_buf()));
+        }
+        let metadata = match self.sys.symlink_metadata(&target) {
+            Ok(metadata) => metadata,
+            // e.g. already removed through another link, or in the simulation of a dry run
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(failed(Operation::Stat, &target)(err)),
+        };
+        // the target counts as being in the directory of the link, not in its own parent
+        let link_device = self.parent_device(link)?;
+        if self.opts.follow_within_filesystem && device(&metadata) != link_device {
+            // only the link itself gets removed
+            return Ok(());
+        }
+        self.link_parents.push(parent);
+        self.devices.push(link_device);
+        let result = self.remove_entry(&target, &metadata);
+        self.devices.pop();
+        self.link_parents.pop();
+        result
+    }
+
+    /// Lists a directory that's about to be emptied, recovering from what may have
+    /// happened to it since it was stat-ed.
+    fn list_dir(&mut self, path: &Path) -> Result<Listing> {
+        match self.sys.read_dir(path) {
+            Ok(children) => Ok(Listing::Children(children)),
+            Err(err) if err.kind() == ErrorKind::NotADirectory => {
+                // the directory got replaced since we looked at it, re-check what's there now
+                match self.sys.symlink_metadata(path) {
+                    Ok(metadata) if !metadata.is_dir() => Ok(Listing::Replaced(metadata)),
+                    Ok(_) => self
+                        .sys
+                        .read_dir(path)
+                        .map(Listing::Children)
+                        .map_err(failed(Operation::ReadDir, path)),
+                    Err(err) if err.kind() == ErrorKind::NotFound => Ok(Listing::Gone),
+                    Err(err) => Err(failed(Operation::Stat, path)(err)),
+                }
+            }
+            // removed by someone else since we looked at it, which is what we're after
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Listing::Gone),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                // permissions may have changed since we fixed them, e.g. a concurrent chmod
+                if let Ok(metadata) = self.sys.symlink_metadata(path) {
+                    let _ = self.make_writable(path, &metadata);
+                }
+                self.sys
+                    .read_dir(path)
+                    .map(Listing::Children)
+                    .map_err(failed(Operation::ReadDir, path))
+            }
+            Err(err) => Err(failed(Operation::ReadDir, path)(err)),
+        }
+    }
+
+    fn remove_children(&mut self, dir: &Path, children: fs::ReadDir) -> Result<()> {
+        if let Some(order) = &self.opts.order {
+            let children = self.ordered(dir, children, order)?;
+            return self.remove_listed(dir, children.into_iter().map(Ok));
+        }
+        if self.opts.sorted {
+            let children = crate::walk::sorted(dir, children)?;
+            return self.remove_listed(dir, children.into_iter().map(Ok));
+        }
+        self.remove_listed(dir, children)
+    }
+
+    /// All of the listing `children` of `dir`, in the order of `RemoveOptions::order_by`.
+    fn ordered(
+        &self,
+        dir: &Path,
+        children: fs::ReadDir,
+        order: &ChildOrder,
+    ) -> Result<Vec<fs::DirEntry>> {
+        let children = if self.opts.sorted {
+            crate::walk::sorted(dir, children)?
+        } else {
+            children
+                .collect::<io::Result<Vec<_>>>()
+                .map_err(failed(Operation::ReadDir, dir))?
+        };
+        let mut entries = Vec::with_capacity(children.len());
+        for child in children {
+            let path = child.path();
+            match self.sys.symlink_metadata(&path) {
+                Ok(metadata) => entries.push((path, metadata, child)),
+                // removed by someone else meanwhile, nothing left to order
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(failed(Operation::Stat, &path)(err)),
+            }
+        }
+        order.sort(&mut entries)?;
+        Ok(entries.into_iter().map(|(_, _, child)| child).collect())
+    }
+
+    fn remove_listed(
+        &mut self,
+        dir: &Path,
+        children: impl Iterator<Item = io::Result<fs::DirEntry>>,
+    ) -> Result<()> {
+        if let Some(idle_threads) = self.idle_threads.clone() {
+            return self.remove_children_parallel(dir, children, &idle_threads);
+        }
+        let mut fanout = 0;
+        for child in children {
+            let child = child.map_err(failed(Operation::ReadDir, dir))?;
+            fanout += 1;
+            self.stats.max_dir_fanout = self.stats.max_dir_fanout.max(fanout);
+            let path = child.path();
+            let is_dir = child.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if self.ignores.is_kept(&path, is_dir, self.opts) {
+                self.kept += 1;
+                continue;
+            }
+            self.remove_child(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Lists all of `dir` first, then splits its children between idle threads
+    /// if there are more than the fanout threshold of them.
+    fn remove_children_parallel(
+        &mut self,
+        dir: &Path,
+        children: impl Iterator<Item = io::Result<fs::DirEntry>>,
+        idle_threads: &AtomicUsize,
+    ) -> Result<()> {
+        let mut paths = Vec::new();
+        let mut fanout = 0;
+        for child in children {
+            let child = child.map_err(failed(Operation::ReadDir, dir))?;
+            fanout += 1;
+            let path = child.path();
+            let is_dir = child.file_type().is_ok_and(|file_type| file_type.is_dir());
+            if self.ignores.is_kept(&path, is_dir, self.opts) {
+                self.kept += 1;
+            } else {
+                paths.push(path);
+            }
+        }
+        self.stats.max_dir_fanout = self.stats.max_dir_fanout.max(fanout);
+        let threshold = self.opts.fanout_threshold();
+        let helpers = if paths.len() > threshold {
+            claim_threads(idle_threads, paths.len() / threshold.max(1))
+        } else {
+            0
+        };
+        if helpers == 0 {
+            return paths.iter().try_for_each(|path| self.remove_child(path));
+        }
+        let this = &*self;
+        let chunk_size = paths.len().div_ceil(helpers + 1);
+        let parts = thread::scope(|scope| {
+            let mut chunks = paths.chunks(chunk_size);
+            let inline = chunks.next().unwrap_or_default();
+            let spawned: Vec<_> = chunks
+                .map(|chunk| scope.spawn(move || this.worker().remove_part(chunk)))
+                .collect();
+            let mut parts = vec![this.worker().remove_part(inline)];
+            for handle in spawned {
+                parts.push(
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+                );
+            }
+            parts
+        });
+        idle_threads.fetch_add(helpers, Ordering::Relaxed);
+        let mut result = Ok(());
+        for (stats, kept, part_result) in parts {
+            self.stats += stats;
+            self.kept += kept;
+            if result.is_ok() {
+                result = part_result;
+            }
+        }
+        result
+    }
+
+    /// Removes `paths` on the current thread, returning the stats and kept count to merge.
+    fn remove_part(mut self, paths: &[std::path::PathBuf]) -> (RemoveStats, u64, Result<()>) {
+        let result = paths.iter().try_for_each(|path| self.remove_child(path));
+        (self.stats, self.kept, result)
+    }
+
+    fn remove_child(&mut self, path: &Path) -> Result<()> {
+        self.opts.check_cancelled()?;
+        let (red_zone, stack_size) = self.opts.stack();
+        let result = stacker::maybe_grow(red_zone, stack_size, ||
+        // don't die with stack overflow for deeply nested directories
+        self.recursive_remove(path));
+        match result {
+            // a followed link may point inside the tree, removing entries before they're reached
+            Err(Error::IoFailed {
+                operation: Operation::Stat,
+                source,
+                ..
+            }) if source.kind() == ErrorKind::NotFound
+                && self.opts.symlinks == SymlinkPolicy::FollowAndRemoveTarget =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn remove_non_dir(&mut self, path: &Path, metadata: &fs::Metadata) -> Result<()> {
+        // measured while the file is still there to be opened
+        let exclusive = self.exclusive_bytes(path, metadata);
+        let links = link_info(path, metadata);
+        let size = match &self.opts.size_fn {
+            Some(size_fn) => size_fn.call(path, metadata)?,
+            None => metadata.len(),
+        };
+        self.with_permissions_fixed(path, metadata, || self.sys.remove_file(path))
+            .map_err(|err| removal_error(Operation::RemoveFile, path, metadata, err))?;
+        if metadata.file_type().is_symlink() {
+            self.stats.symlinks_removed += 1;
+        } else {
+            self.stats.files_removed += 1;
+            if let Some(histogram) = &self.opts.size_histogram {
+                histogram
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .add(metadata.len());
+            }
+        }
+        self.stats.bytes_freed += size;
+        if self.frees_inode(links) {
+            self.stats.inodes_freed += 1;
+            self.stats.exclusive_bytes_freed += exclusive;
+        }
+        self.record(path, RemovedKind::of(metadata), metadata.len())?;
+        self.opts.check_budget(&self.stats)
+    }
+
+    /// How much unlinking the last link of `path` releases, see `RemoveStats::exclusive_bytes_freed`.
+    fn exclusive_bytes(&self, path: &Path, metadata: &fs::Metadata) -> u64 {
+        #[cfg(all(feature = "reflink-stats", target_os = "linux"))]
+        if self.opts.measure_exclusive_bytes && metadata.is_file() {
+            // e.g. a filesystem without extents, where sizes are all there is
+            if let Ok(exclusive) = crate::extents::exclusive_bytes(path) {
+                return exclusive;
+            }
+        }
+        #[cfg(not(all(feature = "reflink-stats", target_os = "linux")))]
+        let _ = path;
+        metadata.len()
+    }
+
+    /// Whether unlinking the entry with the `link_info` `(links, id)` released its last link.
+    fn frees_inode(&mut self, (links, id): (u64, (u64, u64))) -> bool {
+        if links <= 1 {
+            return true;
+        }
+        if !self.opts.dry_run {
+            // each entry is stat-ed right before its unlink, so the count is current,
+            // unless another thread unlinks a link of the same file in between
+            return false;
+        }
+        // a dry run doesn't unlink anything, the count only drops in the simulation
+        let mut links_removed = self
+            .links_removed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let removed = links_removed.entry(id).or_insert(0);
+        *removed += 1;
+        *removed == links
+    }
+
+    /// Refuses to remove `target` if it contains the file of `RemoveOptions::journal`.
+    fn check_journal(&self, target: &Path) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.check_outside(target),
+            None => Ok(()),
+        }
+    }
+
+    /// Appends the entry `path`, just removed, to `RemoveOptions::journal`.
+    fn record(&self, path: &Path, kind: RemovedKind, size: u64) -> Result<()> {
+        match &self.journal {
+            Some(journal) => journal.record(path, kind, size),
+            None => Ok(()),
+        }
+    }
+
+    /// Signals `RemoveOptions::on_enter` that the directory `path` is about to be processed.
+    pub(crate) fn enter(&self, path: &Path) -> Result<()> {
+        match &self.opts.on_enter {
+            Some(on_enter) => on_enter.call(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Calls `remove`, and only if it fails for lack of permissions, makes `path`
+    /// writable and calls it again.
+    ///
+    /// Entries that can be removed as they are never get their permissions changed,
+    /// so a failing removal doesn't leave them any weaker than it found them.
+    fn with_permissions_fixed(
+        &self,
+        path: &Path,
+        metadata: &fs::Metadata,
+        remove: impl Fn() -> io::Result<()>,
+    ) -> io::Result<()> {
+        match remove() {
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => {
+                match self.fix_permissions(path, metadata) {
+                    Ok(true) => remove(),
+                    // not being able to fix permissions is not fatal, report the original error
+                    _ => Err(err),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Fixes what keeps `path` from being removed, returning whether anything changed.
+    ///
+    /// On Windows, that's the read-only attribute of the entry itself. On Unix, unlinking
+    /// is up to the directory holding the entry, which gets fixed instead, but only if it's
+    /// one this removal empties: directories around the target are never touched.
+    fn fix_permissions(&self, path: &Path, metadata: &fs::Metadata) -> io::Result<bool> {
+        if !cfg!(unix) {
+            return self.make_writable(path, metadata);
+        }
+        let parent = match path.parent() {
+            Some(parent) if !self.devices.is_empty() => parent,
+            _ => return Ok(false),
+        };
+        let parent_metadata = self.sys.symlink_metadata(parent)?;
+        self.make_writable(parent, &parent_metadata)
+    }
+
+    /// Makes `path` writable, and listable for a directory on Unix, unless permissions
+    /// are to be kept. Returns whether it changed.
+    fn make_writable(&self, path: &Path, metadata: &fs::Metadata) -> io::Result<bool> {
+        if self.opts.keep_permissions {
+            return Ok(false);
+        }
+        let permissions = match writable(metadata) {
+            Some(permissions) => permissions,
+            None => return Ok(false),
+        };
+        self.sys.set_permissions(path, permissions)?;
+        if let Some(log) = &self.opts.permission_log {
+            log.lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push(path.to_path_buf());
+        }
+        if let Some(fixed) = &self.fixed {
+            fixed
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .push((path.to_path_buf(), metadata.permissions()));
+        }
+        Ok(true)
+    }
+
+    /// Gives the fixed entries that are still there their original permissions back,
+    /// innermost first. Best effort: the outcome of the removal is what gets reported.
+    fn restore_permissions(&self) {
+        let fixed = match &self.fixed {
+            Some(fixed) => fixed,
+            None => return,
+        };
+        let fixed = std::mem::take(
+            &mut *fixed
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner()),
+        );
+        for (path, permissions) in fixed.into_iter().rev() {
+            if self.sys.symlink_metadata(&path).is_ok() {
+                let _ = self.sys.set_permissions(&path, permissions);
+            }
+        }
+    }
+}
+
+/// A directory emptied in the first phase of `RemoveOptions::two_phase`.
+struct Deferred {
+    path: PathBuf,
+    metadata: fs::Metadata,
+    /// The filesystem of the directory being emptied that held it, if any.
+    parent_device: Option<u64>,
+}
+
+/// An entry whose permissions were fixed, with the permissions it had before.
+type Fixed = (PathBuf, fs::Permissions);
+
+/// Takes up to `wanted` of the idle threads, returning how many it got.
+fn claim_threads(idle_threads: &AtomicUsize, wanted: usize) -> usize {
+    let mut claimed = 0;
+    let _ = idle_threads.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |idle| {
+        claimed = idle.min(wanted);
+        Some(idle - claimed)
+    });
+    claimed
+}
+
+/// What `remove_entry` does with an entry, as far as its metadata and the options tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Action {
+    /// Removes the entry with `remove_file`: a file, a symlink, or another non-directory.
+    Unlink,
+    /// Removes what the symlink resolves to, then unlinks the link itself.
+    FollowThenUnlink,
+    /// Empties the directory, then removes it.
+    Descend,
+    /// Fails the removal without touching the entry.
+    Refuse(Refusal),
+}
+
+/// Why `classify` refused an entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    /// A symlink, with `SymlinkPolicy::Error`.
+    Symlink,
+    /// A device node, FIFO or socket, with `RemoveOptions::refuse_device_nodes`.
+    SpecialFile,
+}
+
+impl Refusal {
+    fn error(self, path: &Path) -> Error {
+        match self {
+            Refusal::Symlink => Error::UnexpectedSymlink(path.to_path_buf()),
+            Refusal::SpecialFile => Error::RefusedSpecialFile(path.to_path_buf()),
+        }
+    }
+}
+
+/// The policy deciding what becomes of an entry by its `symlink_metadata` alone.
+///
+/// Only real directories are descended into: a symlink to a directory is a link like any
+/// other, followed only with `SymlinkPolicy::FollowAndRemoveTarget`. What needs more than the metadata,
+/// `RemoveOptions::refuse_mount_points` and `only_own_files`, is checked by the caller
+/// on the entries that aren't refused here.
+pub(crate) fn classify(metadata: &fs::Metadata, opts: &RemoveOptions) -> Action {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        return match opts.symlinks {
+            SymlinkPolicy::RemoveLink => Action::Unlink,
+            SymlinkPolicy::FollowAndRemoveTarget => Action::FollowThenUnlink,
+            SymlinkPolicy::Error => Action::Refuse(Refusal::Symlink),
+        };
+    }
+    if file_type.is_dir() {
+        Action::Descend
+    } else if opts.refuse_device_nodes && is_special_file(metadata) {
+        Action::Refuse(Refusal::SpecialFile)
+    } else {
+        Action::Unlink
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_special_file(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    let file_type = metadata.file_type();
+    file_type.is_block_device()
+        || file_type.is_char_device()
+        || file_type.is_fifo()
+        || file_type.is_socket()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_special_file(_: &fs::Metadata) -> bool {
+    false
+}
+
+/// The hard link count of the entry at `path`, and what identifies the file among its links.
+#[cfg(unix)]
+fn link_info(_: &Path, metadata: &fs::Metadata) -> (u64, (u64, u64)) {
+    (
+        std::os::unix::fs::MetadataExt::nlink(metadata),
+        inode(metadata),
+    )
+}
+
+/// The device and inode numbers of the entry, always `(0, 0)` where they're not in the metadata.
+#[cfg(unix)]
+pub(crate) fn inode(metadata: &fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+/// The filesystem holding the entry, always 0 where it's not in the metadata.
+pub(crate) fn device(metadata: &fs::Metadata) -> u64 {
+    inode(metadata).0
+}
+
+/// Hard link counts need a file handle on Windows, they're not in the metadata:
+/// the entry is opened without following a symlink in its place, for
+/// `GetFileInformationByHandle`. What can't be opened counts as a single link.
+#[cfg(windows)]
+fn link_info(path: &Path, _: &fs::Metadata) -> (u64, (u64, u64)) {
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::AsRawHandle;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x0200_0000;
+    const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x0020_0000;
+    /// `BY_HANDLE_FILE_INFORMATION`, the three times as pairs of `u32`.
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileInformation {
+        attributes: u32,
+        times: [u32; 6],
+        volume_serial_number: u32,
+        size_high: u32,
+        size_low: u32,
+        number_of_links: u32,
+        index_high: u32,
+        index_low: u32,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileInformationByHandle(
+            file: std::os::windows::io::RawHandle,
+            information: *mut FileInformation,
+        ) -> i32;
+    }
+    let file = match fs::OpenOptions::new()
+        .access_mode(0)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT)
+        .open(path)
+    {
+        Ok(file) => file,
+        Err(_) => return (1, (0, 0)),
+    };
+    let mut information = FileInformation::default();
+    // SAFETY: `file` is open, and `information` has the layout the call writes
+    if unsafe { GetFileInformationByHandle(file.as_raw_handle(), &mut information) } == 0 {
+        return (1, (0, 0));
+    }
+    let index = u64::from(information.index_high) << 32 | u64::from(information.index_low);
+    (
+        u64::from(information.number_of_links),
+        (u64::from(information.volume_serial_number), index),
+    )
+}
+
+#[cfg(not(any(unix, windows)))]
+fn link_info(_: &Path, _: &fs::Metadata) -> (u64, (u64, u64)) {
+    (1, (0, 0))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn inode(_: &fs::Metadata) -> (u64, u64) {
+    (0, 0)
+}
+
+/// The permissions `Remover::make_writable` gives the entry, if it has something to fix.
+#[cfg(target_os = "windows")]
+#[allow(clippy::permissions_set_readonly_false)] // on Windows this only clears the attribute
+fn writable(metadata: &fs::Metadata) -> Option<fs::Permissions> {
+    let mut permissions = metadata.permissions();
+    if !permissions.readonly() {
+        return None;
+    }
+    permissions.set_readonly(false);
+    Some(permissions)
+}
+
+/// Directories get `u+rwx`, for their entries to be listed and unlinked. As only the owner
+/// can chmod, and root needs no permissions, others are left alone, as are files,
+/// whose mode never keeps them from being unlinked.
+#[cfg(unix)]
+fn writable(metadata: &fs::Metadata) -> Option<fs::Permissions> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    // SAFETY: `geteuid` has no preconditions and never fails
+    let user = unsafe { libc::geteuid() };
+    let mode = metadata.mode();
+    if !metadata.is_dir() || mode & 0o700 == 0o700 || user == 0 || metadata.uid() != user {
+        return None;
+    }
+    Some(fs::Permissions::from_mode(mode & 0o7777 | 0o700))
+}
+
+#[cfg(not(any(unix, target_os = "windows")))]
+fn writable(_: &fs::Metadata) -> Option<fs::Permissions> {
+    None
+}
+
+/// Whether `Remover::make_writable` has something to fix, for the entry or, on Unix,
+/// for the entries of a directory to be removed.
+pub(crate) fn needs_writable(metadata: &fs::Metadata) -> bool {
+    writable(metadata).is_some()
+}
+
+#[allow(clippy::large_enum_variant)] // `ReadDir` is big on Windows, but this is short-lived
+enum Listing {
+    Children(fs::ReadDir),
+    /// The directory was replaced by a non-directory, described by the new metadata.
+    Replaced(fs::Metadata),
+    /// The directory disappeared.
+    Gone,
+}
+
+/// Turns a failed unlink/rmdir into the most descriptive error we can give.
+fn removal_error(
+    operation: Operation,
+    path: &Path,
+    metadata: &fs::Metadata,
+    err: io::Error,
+) -> Error {
+    if err.kind() == ErrorKind::PermissionDenied && is_system_immutable(metadata) {
+        Error::SystemImmutable(path.to_path_buf())
+    } else {
+        failed(operation, path)(err)
+    }
+}
+
+/// Whether the BSD `schg`/`sappnd` flags are set, which only a low securelevel allows to clear.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+))]
+fn is_system_immutable(metadata: &fs::Metadata) -> bool {
+    #[cfg(target_os = "dragonfly")]
+    use std::os::dragonfly::fs::MetadataExt;
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+    #[cfg(target_os = "ios")]
+    use std::os::ios::fs::MetadataExt;
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+    #[cfg(target_os = "netbsd")]
+    use std::os::netbsd::fs::MetadataExt;
+    #[cfg(target_os = "openbsd")]
+    use std::os::openbsd::fs::MetadataExt;
+
+    const SF_IMMUTABLE: u32 = 0x0002_0000;
+    const SF_APPEND: u32 = 0x0004_0000;
+    metadata.st_flags() & (SF_IMMUTABLE | SF_APPEND) != 0
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "netbsd",
+    target_os = "openbsd",
+    target_os = "dragonfly"
+)))]
+fn is_system_immutable(_: &fs::Metadata) -> bool {
+    false
+}
@@ -0,0 +1,89 @@
+//! Refusing targets whose removal would take a system or user directory along, see
+//! `RemoveOptions::protect_system_paths`.
+
+use crate::{Error, Result};
+use std::path::{Path, PathBuf};
+
+/// The directories a protected removal must never take along.
+///
+/// `SafetyContext::from_env` reads them from the environment, which some setups get wrong,
+/// e.g. a container whose `$HOME` is not the directory worth protecting:
+/// `RemoveOptions::safety_context` then lets the caller say what to protect instead.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SafetyContext {
+    root_markers: Vec<PathBuf>,
+    home: Option<PathBuf>,
+    cwd: Option<PathBuf>,
+}
+
+impl SafetyContext {
+    /// A context protecting nothing but the filesystem roots, which are always protected.
+    pub fn new() -> SafetyContext {
+        SafetyContext::default()
+    }
+
+    /// The home directory from `$HOME` (`%USERPROFILE%` on Windows) and the current directory.
+    pub fn from_env() -> SafetyContext {
+        let home = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+        SafetyContext {
+            root_markers: Vec::new(),
+            home: std::env::var_os(home)
+                .filter(|home| !home.is_empty())
+                .map(PathBuf::from),
+            cwd: std::env::current_dir().ok(),
+        }
+    }
+
+    /// Protects `path` like a filesystem root, e.g. the root of a chroot or a mount point.
+    pub fn root_marker<P: Into<PathBuf>>(mut self, path: P) -> SafetyContext {
+        self.root_markers.push(path.into());
+        self
+    }
+
+    /// Sets the home directory to protect, or none.
+    pub fn home(mut self, home: Option<PathBuf>) -> SafetyContext {
+        self.home = home;
+        self
+    }
+
+    /// Sets the current directory to protect, or none.
+    pub fn cwd(mut self, cwd: Option<PathBuf>) -> SafetyContext {
+        self.cwd = cwd;
+        self
+    }
+
+    /// Refuses `target` if it is a root, or contains the home or the current directory.
+    pub(crate) fn check(&self, target: &Path) -> Result<()> {
+        let target = crate::batch::identity(target);
+        let refuse = |reason: &str| {
+            Err(Error::InvalidTarget(format!(
+                "Invalid target {}, it {}",
+                target.display(),
+                reason
+            )))
+        };
+        if target.parent().is_none() || self.root_markers.iter().any(|root| same(root, &target)) {
+            return refuse("is a filesystem root");
+        }
+        if self.home.iter().any(|home| inside(home, &target)) {
+            return refuse("contains the home directory");
+        }
+        if self.cwd.iter().any(|cwd| inside(cwd, &target)) {
+            return refuse("contains the current directory");
+        }
+        Ok(())
+    }
+}
+
+fn resolved(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn same(path: &Path, target: &Path) -> bool {
+    resolved(path) == target
+}
+
+/// Whether `path` is `target` or inside it.
+fn inside(path: &Path, target: &Path) -> bool {
+    resolved(path).starts_with(target)
+}
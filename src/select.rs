@@ -0,0 +1,191 @@
+use crate::error::catch_callback;
+use crate::remover::Remover;
+use crate::walk::{TreeWalker, Visit};
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use std::collections::HashSet;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+/// Decides what happens to an entry found under the root.
+pub(crate) type Select<'s> = dyn FnMut(&Path, &fs::Metadata) -> Selection + 's;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Selection {
+    /// Force-remove the entry as a whole.
+    Remove,
+    /// Keep the entry, and everything inside it for a directory.
+    Keep,
+    /// Keep a directory and decide for each of its children, keep any other entry.
+    Descend,
+}
+
+impl Selection {
+    pub(crate) fn remove_if(remove: bool) -> Selection {
+        if remove {
+            Selection::Remove
+        } else {
+            Selection::Descend
+        }
+    }
+}
+
+/// Force-removes the entries under `root` picked by `select`, keeping `root` itself.
+///
+/// With `prune_emptied`, directories that had entries but became empty
+/// because of the removal are removed too, bottom-up.
+pub(crate) fn remove_selected(
+    root: &Path,
+    opts: &RemoveOptions,
+    select: &mut Select,
+    prune_emptied: bool,
+) -> Result<RemoveStats> {
+    let root = crate::validate_target(root, false)?;
+    match root.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound && opts.missing_ok => {
+            return crate::batch::check_match(opts, RemoveStats::default())
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let mut remover = Remover::new(opts);
+    let result = walk(&root, &mut remover, select, prune_emptied);
+    let stats = remover.finish(result)?;
+    crate::batch::check_match(opts, stats)
+}
+
+/// Whether a directory being walked turned out to hold entries, and to keep any of them.
+#[derive(Default)]
+struct Listed {
+    had_entries: bool,
+    kept_entries: bool,
+}
+
+fn walk(
+    root: &Path,
+    remover: &mut Remover,
+    select: &mut Select,
+    prune_emptied: bool,
+) -> Result<()> {
+    let mut walker = TreeWalker::new(root).sorted(remover.opts().sorted);
+    // one per directory being walked, innermost last
+    let mut listed: Vec<Listed> = Vec::new();
+    while let Some(visit) = walker.next(remover.sys()) {
+        match visit? {
+            Visit::Dir(entry) if entry.depth == 0 => {
+                remover.opts().check_cancelled()?;
+                remover.enter(&entry.path)?;
+                listed.push(Listed::default());
+            }
+            Visit::Dir(entry) => {
+                listed_dir(&mut listed).had_entries = true;
+                match catch_callback(|| select(&entry.path, &entry.metadata))? {
+                    Selection::Remove => {
+                        walker.skip_contents();
+                        remover.remove_path(&entry.path)?;
+                    }
+                    Selection::Descend => {
+                        remover.opts().check_cancelled()?;
+                        remover.enter(&entry.path)?;
+                        listed.push(Listed::default());
+                    }
+                    Selection::Keep => {
+                        walker.skip_contents();
+                        listed_dir(&mut listed).kept_entries = true;
+                    }
+                }
+            }
+            Visit::Leaf(entry) => {
+                listed_dir(&mut listed).had_entries = true;
+                match catch_callback(|| select(&entry.path, &entry.metadata))? {
+                    Selection::Remove => remover.remove_path(&entry.path)?,
+                    Selection::Descend | Selection::Keep => {
+                        listed_dir(&mut listed).kept_entries = true
+                    }
+                }
+            }
+            Visit::Leave(entry) => {
+                let dir = listed.pop().unwrap_or_default();
+                if entry.depth == 0 {
+                    continue;
+                }
+                if dir.had_entries && !dir.kept_entries && prune_emptied {
+                    remover.remove_empty_dir(&entry.path)?;
+                } else {
+                    listed_dir(&mut listed).kept_entries = true;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The directory whose children are being walked.
+fn listed_dir(listed: &mut [Listed]) -> &mut Listed {
+    listed
+        .last_mut()
+        .expect("only entries inside the root are visited")
+}
+
+/// Force-removes everything under `root` except the relative `keep` paths and their ancestors.
+pub(crate) fn remove_except(
+    root: &Path,
+    keep: &[&Path],
+    opts: &RemoveOptions,
+) -> Result<RemoveStats> {
+    let root = crate::validate_target(root, false)?;
+    let mut kept = HashSet::new();
+    let mut ancestors = HashSet::new();
+    for entry in keep {
+        // lexical confinement is enough: the walk never follows symlinks,
+        // so a kept path can only ever match an entry inside `root`
+        let entry: PathBuf = crate::batch::confined_entry(entry)?
+            .components()
+            .filter(|component| matches!(component, Component::Normal(_)))
+            .collect();
+        ancestors.extend(entry.ancestors().skip(1).map(Path::to_path_buf));
+        kept.insert(entry);
+    }
+    let mut select = |path: &Path, metadata: &fs::Metadata| {
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        if kept.contains(relative) {
+            Selection::Keep
+        } else if metadata.is_dir() && ancestors.contains(relative) {
+            Selection::Descend
+        } else {
+            Selection::Remove
+        }
+    };
+    remove_selected(&root, opts, &mut select, false)
+}
+
+/// Whether the extension of `path` is one of `extensions`, ignoring ASCII case.
+pub(crate) fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => extensions
+            .iter()
+            .any(|candidate| candidate.eq_ignore_ascii_case(extension)),
+        None => false,
+    }
+}
+
+/// `path` relative to `root`, with `/` between its segments on every platform,
+/// as bytes since names may not be valid UTF-8. Empty for `root` itself.
+#[cfg(feature = "regex")]
+pub(crate) fn slashed_relative(root: &Path, path: &Path) -> Vec<u8> {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    let mut slashed = Vec::new();
+    for component in relative.components() {
+        if !slashed.is_empty() {
+            slashed.push(b'/');
+        }
+        slashed.extend_from_slice(component.as_os_str().as_encoded_bytes());
+    }
+    slashed
+}
@@ -0,0 +1,332 @@
+use crate::remover::{inode, Remover};
+use crate::sys::RealSys;
+use crate::walk::TreeWalker;
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// Empties `dir` by moving its children aside and only then deleting them.
+pub(crate) fn swap_clear(dir: &Path) -> Result<()> {
+    let dir = crate::validate_target(dir, false)?;
+    match dir.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let staging = create_staging_dir(&dir)?;
+    let moved = move_children(&dir, &staging);
+    let removed = remove_staged(&staging);
+    moved.and(removed)
+}
+
+fn move_children(dir: &Path, staging: &Path) -> Result<()> {
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        match fs::rename(child.path(), staging.join(child.file_name())) {
+            Ok(()) => {}
+            // e.g. a mount point inside `dir`, which can only be emptied in place
+            Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                remove_staged(&child.path())?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+fn remove_staged(path: &Path) -> Result<()> {
+    Remover::new(&RemoveOptions::default())
+        .run(path)
+        .map(|_| ())
+        .map_err(Error::from)
+}
+
+/// Moves `path` out of the way to a staging name, then removes it on a background thread.
+pub(crate) fn remove_deferred(path: &Path) -> Result<JoinHandle<Result<RemoveStats>>> {
+    deferred(path, rename_no_replace)
+}
+
+pub(crate) fn deferred(
+    path: &Path,
+    mut rename: impl FnMut(&Path, &Path) -> io::Result<()>,
+) -> Result<JoinHandle<Result<RemoveStats>>> {
+    let path = crate::validate_target(path, false)?;
+    let snapshot = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    match stage(&path, fresh_staging_names(&path), |staged| {
+        rename(&path, staged)
+    }) {
+        Ok(staged) => {
+            // what got renamed may not be what was stat-ed, so check before deleting anything
+            let moved = staged.symlink_metadata()?;
+            if inode(&moved) != inode(&snapshot) {
+                // anything that can't be moved back is left for `recover_staging`
+                let _ = rename(&staged, &path);
+                return Err(Error::InvalidTarget(
+                    "Invalid path, target was replaced while being staged".to_string(),
+                ));
+            }
+            Ok(thread::spawn(move || {
+                Remover::new(&RemoveOptions::default())
+                    .run_with_metadata(&staged, &snapshot)
+                    .map_err(Error::from)
+            }))
+        }
+        Err(Error::IoError(err)) if err.kind() == ErrorKind::CrossesDevices => {
+            let result = Remover::new(&RemoveOptions::default())
+                .run(&path)
+                .map_err(Error::from);
+            Ok(thread::spawn(move || result))
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Removes `path` only once every entry of it was moved to a staging directory,
+/// moving everything back if any entry can't be moved.
+pub(crate) fn remove_transactional(path: &Path) -> Result<()> {
+    transactional(path, |from, to| fs::rename(from, to))
+}
+
+pub(crate) fn transactional(
+    path: &Path,
+    mut rename: impl FnMut(&Path, &Path) -> io::Result<()>,
+) -> Result<()> {
+    let path = crate::validate_target(path, false)?;
+    if let Err(err) = path.symlink_metadata() {
+        return match err.kind() {
+            ErrorKind::NotFound => Err(Error::NotFound),
+            _ => Err(err.into()),
+        };
+    }
+    let mut journal = Journal {
+        staging: create_staging_dir(&path)?,
+        moves: Vec::new(),
+        pending: true,
+    };
+    if let Err(err) = stage_tree(&path, &journal.staging, &mut rename, &mut journal.moves) {
+        journal.roll_back(&mut rename);
+        return Err(err);
+    }
+    journal.pending = false;
+    remove_staged(&journal.staging)
+}
+
+/// The moves made by a transactional removal, as `(original, staged)`.
+///
+/// Staging that doesn't get to finish, e.g. because a panic unwinds through it,
+/// is rolled back on drop, so that it doesn't leave an orphaned staging directory.
+struct Journal {
+    staging: PathBuf,
+    moves: Vec<(PathBuf, PathBuf)>,
+    /// Whether the moves still have to be either committed or rolled back.
+    pending: bool,
+}
+
+impl Journal {
+    fn roll_back(&mut self, rename: &mut dyn FnMut(&Path, &Path) -> io::Result<()>) {
+        self.pending = false;
+        // undo the moves latest first, so that directories are back before their contents
+        for (original, staged) in self.moves.iter().rev() {
+            if rename(staged, original).is_err() {
+                // what couldn't be moved back stays in the staging directory, for `recover_staging`
+                return;
+            }
+        }
+        let _ = fs::remove_dir(&self.staging);
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if self.pending {
+            self.roll_back(&mut |from, to| fs::rename(from, to));
+        }
+    }
+}
+
+/// Removes the staging entries left in `dir` by removals that never finished.
+pub(crate) fn recover_staging(dir: &Path) -> Result<RemoveStats> {
+    match dir.symlink_metadata() {
+        Ok(metadata) if metadata.is_dir() => {}
+        Ok(_) => {
+            return Err(Error::InvalidTarget(
+                "Invalid path, target is not a directory".to_string(),
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    }
+    let mut stats = RemoveStats::default();
+    for child in fs::read_dir(dir)? {
+        let child = child?;
+        match staging_pid(&child.file_name()) {
+            Some(pid) if !is_other_live_process(pid) => {
+                stats += Remover::new(&RemoveOptions::default()).run(&child.path())?;
+            }
+            _ => {}
+        }
+    }
+    Ok(stats)
+}
+
+/// The process id in `name` if it has the form of `staging_name`, `.<name>.rm_rf.<pid>.<counter>`.
+fn staging_pid(name: &std::ffi::OsStr) -> Option<u32> {
+    let name = name.as_encoded_bytes();
+    let marker = b".rm_rf.";
+    let start = name
+        .windows(marker.len())
+        .rposition(|window| window == marker)?;
+    if !name.starts_with(b".") || start == 0 {
+        return None;
+    }
+    let suffix = std::str::from_utf8(&name[start + marker.len()..]).ok()?;
+    let (pid, counter) = suffix.split_once('.')?;
+    let is_number = |digits: &str| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(pid) || !is_number(counter) {
+        return None;
+    }
+    pid.parse().ok()
+}
+
+/// Whether `pid` is another process that's still running, and may still use its staging entries.
+#[cfg(unix)]
+fn is_other_live_process(pid: u32) -> bool {
+    use std::convert::TryFrom;
+    let pid = match libc::pid_t::try_from(pid) {
+        // 0 would address the whole process group
+        Ok(pid) if pid > 0 => pid,
+        _ => return false,
+    };
+    if pid as u32 == std::process::id() {
+        return false;
+    }
+    // SAFETY: a null signal only checks that the process exists
+    let signaled = unsafe { libc::kill(pid, 0) } == 0;
+    signaled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Processes can't be checked for elsewhere, so every staging entry is taken as abandoned.
+#[cfg(not(unix))]
+fn is_other_live_process(_: u32) -> bool {
+    false
+}
+
+/// Moves the contents of `path` then `path` itself to `staging`, deepest entries first,
+/// recording each move in `journal` as `(original, staged)`.
+fn stage_tree(
+    path: &Path,
+    staging: &Path,
+    rename: &mut impl FnMut(&Path, &Path) -> io::Result<()>,
+    journal: &mut Vec<(PathBuf, PathBuf)>,
+) -> Result<()> {
+    for entry in TreeWalker::new(path).post_order(&RealSys) {
+        let entry = entry?;
+        let staged = staging.join(journal.len().to_string());
+        rename(&entry.path, &staged)?;
+        journal.push((entry.path, staged));
+    }
+    Ok(())
+}
+
+/// Renames `from` to `to`, failing with `AlreadyExists` rather than replacing an entry at `to`.
+///
+/// Atomic on Linux with `renameat2(RENAME_NOREPLACE)`. Elsewhere, and on filesystems
+/// without support for it, `to` is checked first, leaving a window for a racing entry.
+pub(crate) fn rename_no_replace(from: &Path, to: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "path contains a nul byte"))
+        };
+        let (c_from, c_to) = (c_path(from)?, c_path(to)?);
+        // SAFETY: both paths are valid nul-terminated strings
+        let renamed = unsafe {
+            libc::syscall(
+                libc::SYS_renameat2,
+                libc::AT_FDCWD,
+                c_from.as_ptr(),
+                libc::AT_FDCWD,
+                c_to.as_ptr(),
+                libc::RENAME_NOREPLACE,
+            )
+        };
+        if renamed == 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            // an old kernel, or a filesystem that can't honor the flag
+            Some(libc::ENOSYS) | Some(libc::EINVAL) => {}
+            _ => return Err(err),
+        }
+    }
+    // `rename` silently replaces some existing entries, so never let it
+    if to.symlink_metadata().is_ok() {
+        return Err(io::Error::from(ErrorKind::AlreadyExists));
+    }
+    fs::rename(from, to)
+}
+
+/// How many staging names are tried before giving up with `Error::StagingCollision`.
+pub(crate) const MAX_STAGING_ATTEMPTS: usize = 64;
+
+/// Calls `attempt` with the `names` one after the other, until it doesn't report
+/// the name as taken with `AlreadyExists`. Returns the name that worked.
+pub(crate) fn stage<I>(
+    target: &Path,
+    names: I,
+    mut attempt: impl FnMut(&Path) -> io::Result<()>,
+) -> Result<PathBuf>
+where
+    I: IntoIterator<Item = Result<PathBuf>>,
+{
+    for name in names.into_iter().take(MAX_STAGING_ATTEMPTS) {
+        let name = name?;
+        match attempt(&name) {
+            Ok(()) => return Ok(name),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Err(Error::StagingCollision(target.to_path_buf()))
+}
+
+/// Staging names for `target` that this process never handed out before.
+fn fresh_staging_names(target: &Path) -> impl Iterator<Item = Result<PathBuf>> + '_ {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    std::iter::repeat_with(move || staging_name(target, COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+
+/// The hidden sibling name `.<name>.rm_rf.<pid>.<counter>` for `target`.
+pub(crate) fn staging_name(target: &Path, counter: usize) -> Result<PathBuf> {
+    let name = target.file_name().ok_or_else(|| {
+        Error::InvalidTarget("Invalid path, cannot get last file path component".to_string())
+    })?;
+    let mut staging_name = std::ffi::OsString::from(".");
+    staging_name.push(name);
+    staging_name.push(format!(".rm_rf.{}.{}", std::process::id(), counter));
+    Ok(target.with_file_name(staging_name))
+}
+
+/// Creates an empty directory next to `target`, on the same filesystem as its parent.
+fn create_staging_dir(target: &Path) -> Result<PathBuf> {
+    stage(target, fresh_staging_names(target), |staging| {
+        fs::create_dir(staging)
+    })
+}
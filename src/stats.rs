@@ -0,0 +1,217 @@
+use std::fmt;
+use std::fs::Metadata;
+use std::ops::{Add, AddAssign};
+use std::time::Duration;
+
+/// Counts of what a removal actually deleted.
+///
+/// Plain data, and as such `Send + Sync`. It's `Copy`, and comparable and hashable
+/// field by field, which is guaranteed to stay so. Note that `elapsed` is compared too,
+/// so the stats of two removals are rarely equal as a whole.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct RemoveStats {
+    /// Regular files and other non-directory entries, except symlinks.
+    pub files_removed: u64,
+    pub symlinks_removed: u64,
+    pub dirs_removed: u64,
+    /// Summed sizes of the removed non-directory entries.
+    pub bytes_freed: u64,
+    /// The part of `bytes_freed` from entries whose last link was removed, and with
+    /// `RemoveOptions::measure_exclusive_bytes`, only the data no other file shares.
+    pub exclusive_bytes_freed: u64,
+    /// Wall time the removal took.
+    pub elapsed: Duration,
+    /// The largest number of entries found in a single directory that had to be listed.
+    pub max_dir_fanout: usize,
+    /// Filesystem calls made, only counted with `RemoveOptions::count_syscalls`.
+    pub syscalls: SyscallCounts,
+    /// Distinct files and directories whose storage was released: removing one name of
+    /// a file that has other hard links left doesn't free it. On Windows, telling costs
+    /// each file one more open, for `GetFileInformationByHandle`.
+    /// Only approximate for `remove_parallel` and `remove_contents_parallel`: when two threads
+    /// unlink two links of the same file at once, each may still see the other's, and neither
+    /// counts the file as freed.
+    pub inodes_freed: u64,
+    /// Batch targets skipped because another target of the same batch contains them.
+    pub targets_subsumed: u64,
+    /// Entries owned by other users, left in place with `RemoveOptions::only_own_files`.
+    pub entries_skipped: u64,
+}
+
+/// What kind of entry was removed, as counted in the `RemoveStats` fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RemovedKind {
+    /// A regular file, or any other entry that is neither a directory nor a symlink.
+    File,
+    /// A symlink itself, dangling or not, never what it points to.
+    Symlink,
+    Dir,
+}
+
+impl RemovedKind {
+    pub(crate) fn of(metadata: &Metadata) -> RemovedKind {
+        let file_type = metadata.file_type();
+        if file_type.is_symlink() {
+            RemovedKind::Symlink
+        } else if file_type.is_dir() {
+            RemovedKind::Dir
+        } else {
+            RemovedKind::File
+        }
+    }
+}
+
+/// Numbers of filesystem calls made by a removal, per kind of operation.
+///
+/// These are the calls the traversal makes, each usually being one syscall
+/// (e.g. `symlink_metadata` is one `lstat`, while a `read_dir` stream
+/// may take several `getdents` calls on big directories).
+///
+/// Like `RemoveStats`, it's `Copy`, comparable and hashable.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct SyscallCounts {
+    pub stat: u64,
+    pub read_dir: u64,
+    pub unlink: u64,
+    pub rmdir: u64,
+    pub chmod: u64,
+}
+
+impl SyscallCounts {
+    pub fn total(&self) -> u64 {
+        self.stat + self.read_dir + self.unlink + self.rmdir + self.chmod
+    }
+}
+
+impl AddAssign for SyscallCounts {
+    fn add_assign(&mut self, other: SyscallCounts) {
+        self.stat += other.stat;
+        self.read_dir += other.read_dir;
+        self.unlink += other.unlink;
+        self.rmdir += other.rmdir;
+        self.chmod += other.chmod;
+    }
+}
+
+impl RemoveStats {
+    /// Total number of removed entries of all kinds.
+    pub fn entries_removed(&self) -> u64 {
+        self.files_removed + self.symlinks_removed + self.dirs_removed
+    }
+}
+
+impl Add for RemoveStats {
+    type Output = RemoveStats;
+
+    fn add(mut self, other: RemoveStats) -> RemoveStats {
+        self += other;
+        self
+    }
+}
+
+impl AddAssign for RemoveStats {
+    fn add_assign(&mut self, other: RemoveStats) {
+        self.files_removed += other.files_removed;
+        self.symlinks_removed += other.symlinks_removed;
+        self.dirs_removed += other.dirs_removed;
+        self.bytes_freed += other.bytes_freed;
+        self.exclusive_bytes_freed += other.exclusive_bytes_freed;
+        self.elapsed += other.elapsed;
+        self.max_dir_fanout = self.max_dir_fanout.max(other.max_dir_fanout);
+        self.syscalls += other.syscalls;
+        self.inodes_freed += other.inodes_freed;
+        self.targets_subsumed += other.targets_subsumed;
+        self.entries_skipped += other.entries_skipped;
+    }
+}
+
+/// How many files a removal deleted per size, in buckets doubling in width,
+/// see `RemoveOptions::size_histogram`.
+///
+/// Bucket 0 holds empty files, bucket `i` the sizes from `2^(i-1)` to `2^i - 1`.
+/// Displayed as a text bar chart, one line per bucket from the smallest to the largest
+/// non-empty one, labeled with its half-open range, e.g. `[1 KiB, 2 KiB)`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SizeHistogram {
+    counts: [u64; SizeHistogram::BUCKETS],
+    bytes: u64,
+}
+
+impl SizeHistogram {
+    /// Empty files, then one bucket per bit of a `u64` size.
+    pub const BUCKETS: usize = 65;
+
+    pub fn new() -> SizeHistogram {
+        SizeHistogram {
+            counts: [0; SizeHistogram::BUCKETS],
+            bytes: 0,
+        }
+    }
+
+    /// Counts a file of `size` bytes.
+    pub fn add(&mut self, size: u64) {
+        self.counts[SizeHistogram::bucket(size)] += 1;
+        self.bytes = self.bytes.saturating_add(size);
+    }
+
+    /// The bucket a file of `size` bytes falls into.
+    pub fn bucket(size: u64) -> usize {
+        (u64::BITS - size.leading_zeros()) as usize
+    }
+
+    /// The number of files in each bucket, indexed as described on `SizeHistogram`.
+    pub fn counts(&self) -> &[u64; SizeHistogram::BUCKETS] {
+        &self.counts
+    }
+
+    /// The files counted, in all buckets.
+    pub fn files(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The summed sizes of the files counted.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Default for SizeHistogram {
+    fn default() -> SizeHistogram {
+        SizeHistogram::new()
+    }
+}
+
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const WIDTH: u64 = 40;
+        let used = match (
+            self.counts.iter().position(|&count| count > 0),
+            self.counts.iter().rposition(|&count| count > 0),
+        ) {
+            (Some(first), Some(last)) => first..=last,
+            _ => return write!(f, "no files"),
+        };
+        let max = self.counts.iter().copied().max().unwrap_or(0);
+        for i in used.clone() {
+            if i > *used.start() {
+                writeln!(f)?;
+            }
+            let count = self.counts[i];
+            let range = match i {
+                0 => "0 B".to_string(),
+                // half-open, as the upper bound itself falls into the next bucket
+                _ => format!("[{}, {})", power_of_two(i - 1), power_of_two(i)),
+            };
+            // a bucket that isn't empty always gets at least one mark
+            let bar = (count * WIDTH).div_ceil(max) as usize;
+            write!(f, "{:>17} | {:<40} {}", range, "#".repeat(bar), count)?;
+        }
+        Ok(())
+    }
+}
+
+/// `2^exponent` bytes, in the largest binary unit it's a whole number of.
+fn power_of_two(exponent: usize) -> String {
+    const UNITS: [&str; 7] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    format!("{} {}", 1u64 << (exponent % 10), UNITS[exponent / 10])
+}
@@ -0,0 +1,204 @@
+//! Removal on a background thread, observed as a stream of events, for `remove_stream`.
+
+use crate::remover::Remover;
+use crate::sys::{RealSys, Sys};
+use crate::{Error, RemoveOptions, RemoveStats, Result};
+use futures_core::Stream;
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// What a streamed removal reports as it proceeds, see `remove_stream`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoveEvent {
+    /// A file, symlink or other non-directory entry was removed.
+    FileRemoved(PathBuf),
+    /// A directory was removed, after everything inside it.
+    DirRemoved(PathBuf),
+    /// The removal completed, always the last event of a successful removal.
+    Finished(RemoveStats),
+}
+
+/// How many events a `RemoveStream` holds before its removal waits for them to be read.
+pub(crate) const EVENT_CAPACITY: usize = 1024;
+
+/// The events of a removal running on a background thread, returned by `remove_stream`.
+///
+/// A `futures_core::Stream`, so `futures` and `tokio_stream` adapters apply; `next_event`
+/// reads it without them. At most 1024 events are held: when the consumer falls behind,
+/// the background thread waits for it before removing anything more, so memory stays
+/// bounded whatever the size of the tree. Dropping it cancels the removal.
+#[derive(Debug)]
+pub struct RemoveStream {
+    shared: Arc<Shared>,
+    cancel: Arc<AtomicBool>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Signalled when an event is read, or the stream dropped.
+    space: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    events: VecDeque<Result<RemoveEvent>>,
+    done: bool,
+    /// The stream was dropped: nobody reads the events anymore.
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Shared {
+    fn lock(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Queues `event`, waiting while the queue is full, or marks the end with `None`.
+    fn push(&self, event: Option<Result<RemoveEvent>>) {
+        let mut state = self.lock();
+        match event {
+            Some(event) => {
+                while state.events.len() >= EVENT_CAPACITY && !state.closed {
+                    state = self
+                        .space
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                if state.closed {
+                    return;
+                }
+                state.events.push_back(event)
+            }
+            None => state.done = true,
+        }
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl RemoveStream {
+    pub(crate) fn spawn(path: &Path) -> RemoveStream {
+        let shared = Arc::new(Shared::default());
+        let cancel = Arc::new(AtomicBool::new(false));
+        let path = path.to_path_buf();
+        let opts = RemoveOptions::default().cancel_token(cancel.clone());
+        let events = shared.clone();
+        thread::spawn(move || {
+            let sys = EventSys {
+                inner: RealSys,
+                shared: &events,
+            };
+            match remove(&path, &opts, &sys) {
+                Ok(stats) => events.push(Some(Ok(RemoveEvent::Finished(stats)))),
+                Err(err) => events.push(Some(Err(err))),
+            }
+            events.push(None);
+        });
+        RemoveStream { shared, cancel }
+    }
+
+    /// The next event, or `None` once the removal is over.
+    pub fn next_event(&mut self) -> NextEvent<'_> {
+        NextEvent { stream: self }
+    }
+}
+
+impl Stream for RemoveStream {
+    type Item = Result<RemoveEvent>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut state = self.shared.lock();
+        match state.events.pop_front() {
+            Some(event) => {
+                self.shared.space.notify_one();
+                Poll::Ready(Some(event))
+            }
+            None if state.done => Poll::Ready(None),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RemoveStream {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+        let mut state = self.shared.lock();
+        state.closed = true;
+        state.events.clear();
+        self.shared.space.notify_all();
+    }
+}
+
+/// The future returned by `RemoveStream::next_event`.
+#[derive(Debug)]
+pub struct NextEvent<'s> {
+    stream: &'s mut RemoveStream,
+}
+
+impl Future for NextEvent<'_> {
+    type Output = Option<Result<RemoveEvent>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+fn remove(path: &Path, opts: &RemoveOptions, sys: &dyn Sys) -> Result<RemoveStats> {
+    let path = crate::validate_target(path, false)?;
+    let metadata = match path.symlink_metadata() {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(Remover::with_sys(opts, sys).run_with_metadata(&path, &metadata)?)
+}
+
+/// Reports every successful removal to the stream.
+struct EventSys<'e, S> {
+    inner: S,
+    shared: &'e Shared,
+}
+
+impl<S: Sys> Sys for EventSys<'_, S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_file(path)?;
+        self.shared
+            .push(Some(Ok(RemoveEvent::FileRemoved(path.to_path_buf()))));
+        Ok(())
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.inner.remove_dir(path)?;
+        self.shared
+            .push(Some(Ok(RemoveEvent::DirRemoved(path.to_path_buf()))));
+        Ok(())
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.inner.set_permissions(path, permissions)
+    }
+}
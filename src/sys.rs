@@ -0,0 +1,417 @@
+#[cfg(test)]
+use crate::Operation;
+use crate::{RetryPolicy, SyscallCounts};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Indirection over the filesystem calls made during removal,
+/// so that tests can inject faults and races.
+pub(crate) trait Sys: Sync {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata>;
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()>;
+}
+
+impl<S: Sys + ?Sized> Sys for &S {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        (**self).symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        (**self).read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        (**self).set_permissions(path, permissions)
+    }
+}
+
+impl<S: Sys + ?Sized> Sys for Box<S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        (**self).symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        (**self).read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        (**self).remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        (**self).set_permissions(path, permissions)
+    }
+}
+
+pub(crate) struct RealSys;
+
+impl Sys for RealSys {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        fs::symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        fs::read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        fs::set_permissions(path, permissions)
+    }
+}
+
+/// Makes each call to `inner` on a helper thread, giving up on it after `timeout`,
+/// for `RemoveOptions::per_op_timeout`.
+///
+/// A call that times out keeps its helper thread blocked until the OS returns,
+/// but the removal moves on: that thread is abandoned, and later calls get another one.
+pub(crate) struct TimeoutSys<S> {
+    inner: Arc<S>,
+    timeout: Duration,
+    /// Helper threads waiting for a call, each fed through its channel.
+    idle: Mutex<Vec<mpsc::Sender<Call>>>,
+}
+
+type Call = Box<dyn FnOnce() + Send>;
+
+impl<S: Sys + Send + 'static> TimeoutSys<S> {
+    pub(crate) fn new(inner: S, timeout: Duration) -> TimeoutSys<S> {
+        TimeoutSys {
+            inner: Arc::new(inner),
+            timeout,
+            idle: Mutex::default(),
+        }
+    }
+
+    fn call<T, F>(&self, path: &Path, op: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&S, &Path) -> io::Result<T> + Send + 'static,
+    {
+        let helper = self.idle.lock().unwrap().pop();
+        let helper = helper.unwrap_or_else(spawn_helper);
+        let (inner, path) = (self.inner.clone(), path.to_path_buf());
+        let (done, result) = mpsc::channel();
+        let call: Call = Box::new(move || {
+            let _ = done.send(op(&inner, &path));
+        });
+        if helper.send(call).is_err() {
+            return Err(io::Error::other("filesystem helper thread died"));
+        }
+        match result.recv_timeout(self.timeout) {
+            Ok(result) => {
+                self.idle.lock().unwrap().push(helper);
+                result
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "filesystem call timed out",
+            )),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                Err(io::Error::other("filesystem call panicked"))
+            }
+        }
+    }
+}
+
+/// Starts a thread making the calls sent to it, until the sender is dropped.
+fn spawn_helper() -> mpsc::Sender<Call> {
+    let (sender, calls) = mpsc::channel::<Call>();
+    thread::spawn(move || {
+        for call in calls {
+            call();
+        }
+    });
+    sender
+}
+
+impl<S: Sys + Send + 'static> Sys for TimeoutSys<S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.call(path, |inner, path| inner.symlink_metadata(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.call(path, |inner, path| inner.read_dir(path))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.call(path, |inner, path| inner.remove_file(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.call(path, |inner, path| inner.remove_dir(path))
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.call(path, move |inner, path| {
+            inner.set_permissions(path, permissions)
+        })
+    }
+}
+
+/// Counts the calls that reach `inner`, for `RemoveOptions::count_syscalls`.
+pub(crate) struct CountingSys<S> {
+    inner: S,
+    counts: std::sync::Arc<AtomicCounts>,
+}
+
+#[derive(Default)]
+pub(crate) struct AtomicCounts {
+    stat: AtomicU64,
+    read_dir: AtomicU64,
+    unlink: AtomicU64,
+    rmdir: AtomicU64,
+    chmod: AtomicU64,
+}
+
+impl AtomicCounts {
+    pub(crate) fn snapshot(&self) -> SyscallCounts {
+        SyscallCounts {
+            stat: self.stat.load(Ordering::Relaxed),
+            read_dir: self.read_dir.load(Ordering::Relaxed),
+            unlink: self.unlink.load(Ordering::Relaxed),
+            rmdir: self.rmdir.load(Ordering::Relaxed),
+            chmod: self.chmod.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<S: Sys> CountingSys<S> {
+    pub(crate) fn new(inner: S, counts: std::sync::Arc<AtomicCounts>) -> CountingSys<S> {
+        CountingSys { inner, counts }
+    }
+}
+
+impl<S: Sys> Sys for CountingSys<S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.counts.stat.fetch_add(1, Ordering::Relaxed);
+        self.inner.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.counts.read_dir.fetch_add(1, Ordering::Relaxed);
+        self.inner.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.counts.unlink.fetch_add(1, Ordering::Relaxed);
+        self.inner.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.counts.rmdir.fetch_add(1, Ordering::Relaxed);
+        self.inner.remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.counts.chmod.fetch_add(1, Ordering::Relaxed);
+        self.inner.set_permissions(path, permissions)
+    }
+}
+
+/// Retries the calls to `inner` failing transiently, for `RemoveOptions::retry`.
+pub(crate) struct RetryingSys<S> {
+    inner: S,
+    policy: RetryPolicy,
+}
+
+impl<S: Sys> RetryingSys<S> {
+    pub(crate) fn new(inner: S, policy: RetryPolicy) -> RetryingSys<S> {
+        RetryingSys { inner, policy }
+    }
+
+    fn retry<T>(&self, mut call: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        let mut retries = 0;
+        loop {
+            match call() {
+                Err(err) if is_transient(&err) && retries < self.policy.retries => {
+                    retries += 1;
+                    std::thread::sleep(self.policy.delay);
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Errors that may go away by themselves, e.g. on a busy or flaky network filesystem.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    ) || is_sharing_violation(err)
+}
+
+/// `ERROR_SHARING_VIOLATION`, while another process (often an antivirus or an indexer)
+/// briefly has the entry open.
+#[cfg(windows)]
+fn is_sharing_violation(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(32)
+}
+
+#[cfg(not(windows))]
+fn is_sharing_violation(_: &io::Error) -> bool {
+    false
+}
+
+impl<S: Sys> Sys for RetryingSys<S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.retry(|| self.inner.symlink_metadata(path))
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.retry(|| self.inner.read_dir(path))
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.retry(|| self.inner.remove_file(path))
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.retry(|| self.inner.remove_dir(path))
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.retry(|| self.inner.set_permissions(path, permissions.clone()))
+    }
+}
+
+/// Simulates removals on top of `inner`: reads go through, mutations only get recorded.
+pub(crate) struct DryRunSys<S> {
+    inner: S,
+    /// Entries removed in the simulation whose parent directory is still there.
+    removed: std::sync::Mutex<HashSet<PathBuf>>,
+}
+
+impl<S: Sys> DryRunSys<S> {
+    pub(crate) fn new(inner: S) -> DryRunSys<S> {
+        DryRunSys {
+            inner,
+            removed: std::sync::Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl<S: Sys> Sys for DryRunSys<S> {
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.inner.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.inner.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.removed.lock().unwrap().insert(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Succeeds if all of the directory's entries were removed in the simulation.
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut children = Vec::new();
+        for child in self.inner.read_dir(path)? {
+            children.push(child?.path());
+        }
+        let mut removed = self.removed.lock().unwrap();
+        if !children.iter().all(|child| removed.contains(child)) {
+            return Err(io::Error::from(io::ErrorKind::DirectoryNotEmpty));
+        }
+        for child in &children {
+            removed.remove(child);
+        }
+        removed.insert(path.to_path_buf());
+        Ok(())
+    }
+
+    fn set_permissions(&self, _: &Path, _: fs::Permissions) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A real filesystem with a hook called before every operation.
+/// The hook can mutate the filesystem (to simulate races) or return an error to inject.
+#[cfg(test)]
+pub(crate) struct MockSys<F> {
+    hook: std::sync::Mutex<F>,
+}
+
+#[cfg(test)]
+impl<F> MockSys<F>
+where
+    F: FnMut(Operation, &Path) -> Option<io::Error> + Send,
+{
+    pub(crate) fn new(hook: F) -> MockSys<F> {
+        MockSys {
+            hook: std::sync::Mutex::new(hook),
+        }
+    }
+
+    fn intercept(&self, op: Operation, path: &Path) -> io::Result<()> {
+        match (self.hook.lock().unwrap())(op, path) {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl<F> Sys for MockSys<F>
+where
+    F: FnMut(Operation, &Path) -> Option<io::Error> + Send,
+{
+    fn symlink_metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        self.intercept(Operation::Stat, path)?;
+        RealSys.symlink_metadata(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<fs::ReadDir> {
+        self.intercept(Operation::ReadDir, path)?;
+        RealSys.read_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.intercept(Operation::RemoveFile, path)?;
+        RealSys.remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.intercept(Operation::RemoveDir, path)?;
+        RealSys.remove_dir(path)
+    }
+
+    fn set_permissions(&self, path: &Path, permissions: fs::Permissions) -> io::Result<()> {
+        self.intercept(Operation::SetPermissions, path)?;
+        RealSys.set_permissions(path, permissions)
+    }
+}
@@ -0,0 +1,31 @@
+//! `camino` interop, for code bases that standardize on `Utf8Path`.
+//!
+//! `Utf8Path` implements `AsRef<Path>`, so the regular functions accept it already;
+//! these variants are for generic code bound by `AsRef<Utf8Path>` instead.
+
+use crate::Result;
+use camino::Utf8Path;
+
+/// Same as `rm_rf::remove`.
+pub fn remove<P: AsRef<Utf8Path>>(path: P) -> Result<()> {
+    crate::remove(path.as_ref().as_std_path())
+}
+
+/// Same as `rm_rf::ensure_removed`.
+pub fn ensure_removed<P: AsRef<Utf8Path>>(path: P) -> Result<()> {
+    crate::ensure_removed(path.as_ref().as_std_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use camino::Utf8PathBuf;
+
+    #[test]
+    fn utf8_path_test() {
+        let root = Utf8PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("target/utf8_testdir");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        super::remove(&root).unwrap();
+        assert!(!root.exists());
+        super::ensure_removed(&root).unwrap();
+    }
+}
@@ -0,0 +1,156 @@
+//! The traversal shared by the operations that walk a tree without removing it as a whole.
+//!
+//! The selective removals, staging, `analyze` and the observed removals use it. The removal
+//! of whole trees keeps traversals of its own, the recursion of `Remover` and the descriptor
+//! based one of `fast`, which visit entries in the same `read_dir` order and post-order,
+//! each stat-ed once, but differ in the following:
+//!
+//! - `Remover` first tries to remove each directory before listing it, which succeeds
+//!   right away when it's empty; a non-empty one gets a second attempt once emptied.
+//! - Both stop at the first failure, where `TreeWalker` reports it and goes on.
+//! - `Remover` fixes permissions and lists again when a directory can't be listed, and
+//!   takes a directory removed or replaced since it was stat-ed in stride.
+//! - `Remover` applies `RemoveOptions::order_by`, which `TreeWalker` doesn't support,
+//!   and with `RemoveOptions::parallel`, removes the children of a wide directory on
+//!   several threads, in no defined order between them.
+//! - `fast` never sorts, and only removes a directory it can't open without listing it.
+
+use crate::error::failed;
+use crate::sys::Sys;
+use crate::{Operation, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// An entry found by a `TreeWalker`, with the single `symlink_metadata` snapshot taken of it.
+pub(crate) struct WalkEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) metadata: fs::Metadata,
+    /// 0 for the root, 1 for its children, and so on.
+    pub(crate) depth: usize,
+}
+
+/// What a `TreeWalker` reached.
+pub(crate) enum Visit {
+    /// A directory, whose contents come next unless `TreeWalker::skip_contents` is called.
+    Dir(WalkEntry),
+    /// Any other entry, symlinks included: links are never followed.
+    Leaf(WalkEntry),
+    /// A directory after all of its contents, in the order `Visit::Dir` entered them.
+    Leave(WalkEntry),
+}
+
+/// A depth-first walk from a root, listing each directory once, in `read_dir` order unless sorted.
+///
+/// Kept iterative, so deep trees don't need a bigger stack. The filesystem is passed to
+/// each call rather than held, so callers can remove entries with it in between.
+pub(crate) struct TreeWalker {
+    root: Option<PathBuf>,
+    /// A directory just returned as `Visit::Dir`, listed on the next call.
+    entered: Option<WalkEntry>,
+    stack: Vec<(WalkEntry, Children)>,
+    sorted: bool,
+}
+
+/// The children of a directory still to be visited, as listed or sorted.
+type Children = Box<dyn Iterator<Item = io::Result<fs::DirEntry>>>;
+
+impl TreeWalker {
+    pub(crate) fn new(root: &Path) -> TreeWalker {
+        TreeWalker {
+            root: Some(root.to_path_buf()),
+            entered: None,
+            stack: Vec::new(),
+            sorted: false,
+        }
+    }
+
+    /// Visits the children of each directory sorted by name, see `RemoveOptions::sorted`.
+    pub(crate) fn sorted(mut self, enabled: bool) -> TreeWalker {
+        self.sorted = enabled;
+        self
+    }
+
+    /// Doesn't list the directory just returned as `Visit::Dir`, nor returns a `Visit::Leave` for it.
+    pub(crate) fn skip_contents(&mut self) {
+        self.entered = None;
+    }
+
+    /// The next visit, or `None` once the walk is over. A failure doesn't end the walk,
+    /// but the entry it concerns is skipped, the contents of a directory included.
+    pub(crate) fn next(&mut self, sys: &dyn Sys) -> Option<Result<Visit>> {
+        if let Some(root) = self.root.take() {
+            return Some(
+                sys.symlink_metadata(&root)
+                    .map_err(failed(Operation::Stat, &root))
+                    .map(|metadata| self.visit(root, metadata, 0)),
+            );
+        }
+        if let Some(dir) = self.entered.take() {
+            let children = match sys.read_dir(&dir.path) {
+                Ok(children) if self.sorted => match sorted(&dir.path, children) {
+                    Ok(children) => Box::new(children.into_iter().map(Ok)) as Children,
+                    Err(err) => return Some(Err(err)),
+                },
+                Ok(children) => Box::new(children),
+                Err(err) => return Some(Err(failed(Operation::ReadDir, &dir.path)(err))),
+            };
+            self.stack.push((dir, children));
+        }
+        let (dir, children) = self.stack.last_mut()?;
+        match children.next() {
+            Some(Ok(child)) => {
+                let path = child.path();
+                let depth = dir.depth + 1;
+                Some(match sys.symlink_metadata(&path) {
+                    Ok(metadata) => Ok(self.visit(path, metadata, depth)),
+                    Err(err) => Err(failed(Operation::Stat, &path)(err)),
+                })
+            }
+            Some(Err(err)) => Some(Err(failed(Operation::ReadDir, &dir.path)(err))),
+            None => self.stack.pop().map(|(dir, _)| Ok(Visit::Leave(dir))),
+        }
+    }
+
+    /// Every entry after everything inside it, the root last, as removing a tree one
+    /// entry at a time needs them.
+    pub(crate) fn post_order<'s>(
+        mut self,
+        sys: &'s dyn Sys,
+    ) -> impl Iterator<Item = Result<WalkEntry>> + 's {
+        std::iter::from_fn(move || loop {
+            match self.next(sys)? {
+                Ok(Visit::Dir(_)) => {}
+                Ok(Visit::Leaf(entry)) | Ok(Visit::Leave(entry)) => return Some(Ok(entry)),
+                Err(err) => return Some(Err(err)),
+            }
+        })
+    }
+
+    fn visit(&mut self, path: PathBuf, metadata: fs::Metadata, depth: usize) -> Visit {
+        let entry = WalkEntry {
+            path,
+            metadata,
+            depth,
+        };
+        if entry.metadata.is_dir() {
+            self.entered = Some(WalkEntry {
+                path: entry.path.clone(),
+                metadata: entry.metadata.clone(),
+                depth,
+            });
+            Visit::Dir(entry)
+        } else {
+            Visit::Leaf(entry)
+        }
+    }
+}
+
+/// All of the listing `children` of `dir`, sorted by the bytes of their names.
+pub(crate) fn sorted(dir: &Path, children: fs::ReadDir) -> Result<Vec<fs::DirEntry>> {
+    let mut children = children
+        .collect::<io::Result<Vec<_>>>()
+        .map_err(failed(Operation::ReadDir, dir))?;
+    children.sort_by_cached_key(|child| child.file_name());
+    Ok(children)
+}